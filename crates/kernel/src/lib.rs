@@ -1,130 +1,518 @@
+use lib::framing::{FrameDecoder, MAX_BATCH_PAGES};
+use lib::hash::Blake2b;
 use lib::dac::{reveal_loop, V0SliceContentPage, MAX_PAGE_SIZE};
 use lib::message::Message;
-use lib::message::{Content, UserMessage};
-use lib::public_key::PublicKey;
+use lib::message::{verify_signatures, UserMessage};
 use lib::public_key_hash::PublicKeyHash;
+use lib::receipt::Receipt;
+use lib::transition;
 
-use lib::constants::SEQUENCER_PK;
 // src/lib.rs
-use storage::{read_account, store_account};
-use tezos_smart_rollup::core_unsafe::PREIMAGE_HASH_SIZE;
-use tezos_smart_rollup::michelson::ticket;
+use std::cmp::Ordering;
+use storage::{
+    delete_batch_cursor, read_batch_cursor, read_last_batch_level, read_level_stats,
+    store_batch_cursor, store_last_batch, store_level_info, store_level_stats, store_receipt,
+    BatchCursor, DurableStorage, LevelStats,
+};
 use tezos_smart_rollup::storage::path::{OwnedPath};
 use tezos_smart_rollup::{kernel_entry, prelude::*};
 
+mod migration;
+mod snapshot;
 mod stages;
 mod storage;
+mod ticks;
 mod upgrade;
 
 use lib::error::*;
-use stages::{place_pixel, read_input, verify_nonce, verify_signature};
+use stages::{
+    handle_transfer, read_input, verify_batch_header, verify_sequencer_signature,
+    verify_signature, Input,
+};
+use ticks::{
+    TickCounter, TICKS_PER_INBOX_MESSAGE, TICKS_PER_PAGE, TICKS_PER_SIGNATURE,
+    TICKS_PER_SKIPPED_PAGE, TICKS_PER_USER_MESSAGE,
+};
 
 
 /// A step is processing only one message from the inbox
 ///
 /// It will execute several sub steps:
-/// - verify the signature of the message
-/// - verify the nonce of the message
-/// - handle the message
-fn step<R: Runtime>(host: &mut R, message: UserMessage, _level: u32) -> Result<()> {
+/// - verify the signature of the message, unless it was already verified with its batch
+/// - apply the inner of the message to the durable storage at the given level,
+///   see `transition::apply`
+fn step<R: Runtime>(
+    host: &mut R,
+    message: UserMessage,
+    level: u32,
+    signature_verified: bool,
+) -> Result<()> {
     let public_key = message.public_key();
     let public_key_hash = PublicKeyHash::from(public_key);
     host.write_debug("Message is deserialized\n");
 
-    let inner = verify_signature(message)?;
+    let inner = if signature_verified {
+        message.inner
+    } else {
+        verify_signature(message)?
+    };
     host.write_debug("Signature is correct\n");
 
-    // Verify the nonce
-    let account = read_account(host, public_key_hash)?;
-    let content = verify_nonce(inner, account.nonce())?;
-    let account = account.increment_nonce();
-    let _ = store_account(host, &account)?;
+    transition::apply(&mut DurableStorage(host), &public_key_hash, level, &inner)
+}
+
+/// Decode a user message from the content of a DAC page
+fn parse_user_message(content: &[u8]) -> Result<UserMessage> {
+    UserMessage::from_bytes(content)
+}
+
+/// Process messages of a DAC batch, as many as the tick budget affords
+///
+/// The signatures of all the messages are verified at once, see `verify_signatures`.
+/// If one of them is invalid, each message falls back to the verification of its own signature,
+/// so that only the invalid messages are rejected, at the cost of `TICKS_PER_SIGNATURE` each.
+///
+/// Each message produces a receipt keyed by the hash of its bytes, see `UserMessage::hash`,
+/// recording the level, the index of the batch, and the index of the message in the batch,
+/// starting at `first_index`, and is counted in the statistics of the level.
+/// The messages are applied at the level the batch was built for rather than the level of
+/// inclusion, so that the cooldowns are judged as in the preview of the sequencer.
+/// A message that cannot be deserialized is skipped without aborting the rest of the batch.
+///
+/// Returns the number of processed messages
+fn handle_user_messages<R: Runtime>(
+    host: &mut R,
+    level: u32,
+    batch: u64,
+    first_index: u32,
+    contents: Vec<Vec<u8>>,
+    ticks: &mut TickCounter,
+    stats: &mut LevelStats,
+) -> Result<usize> {
+    let affordable = ticks.affordable(TICKS_PER_USER_MESSAGE) as usize;
+    let mut messages: Vec<(Blake2b, Result<UserMessage>)> = contents
+        .iter()
+        .take(affordable)
+        .map(|content| (Blake2b::from(content), parse_user_message(content)))
+        .collect();
 
-    // Interpret the message
-    match content {
-        Content::PlacePixel(post_tweet) => place_pixel(host, &account, post_tweet)?,
+    let user_messages: Vec<&UserMessage> = messages
+        .iter()
+        .filter_map(|(_, message)| message.as_ref().ok())
+        .collect();
+    let signatures_verified = match verify_signatures(&user_messages) {
+        Ok(()) => true,
+        Err(_) => {
+            debug_msg!(host, "Batch verification failed, verifying signatures one by one\n");
+            let affordable = ticks.affordable(TICKS_PER_USER_MESSAGE + TICKS_PER_SIGNATURE);
+            messages.truncate(affordable as usize);
+            ticks.consume(messages.len() as u64 * TICKS_PER_SIGNATURE);
+            false
+        }
     };
+    ticks.consume(messages.len() as u64 * TICKS_PER_USER_MESSAGE);
+
+    // The batch being processed is the last one accepted
+    let batch_level = read_last_batch_level(host)?;
+    let processed = messages.len();
+    for (index, (hash, message)) in messages.into_iter().enumerate() {
+        let result =
+            message.and_then(|message| step(host, message, batch_level, signatures_verified));
+        match &result {
+            Ok(()) => stats.accepted += 1,
+            Err(err) => {
+                debug_msg!(host, "Message {} rejected: {}\n", hash.to_string(), err.to_string());
+                stats.rejected += 1;
+            }
+        }
+        let receipt = Receipt::new(hash, level, batch, first_index + index as u32, &result);
+        store_receipt(host, &receipt)?;
+    }
+    Ok(processed)
+}
+
+/// Outcome of a work which may be spread over several kernel runs
+#[derive(Debug, PartialEq)]
+enum Progress {
+    /// The work is done
+    Complete,
+    /// The tick budget is exhausted, the progress is stored to resume after a reboot
+    Interrupted,
+}
+
+/// Error stopping the traversal of the pages of an interrupted batch
+const INTERRUPTED: &str = "Batch interrupted";
+
+/// Error stopping the traversal of the pages of a batch at `MAX_BATCH_PAGES`
+const TOO_LARGE: &str = "Batch too large";
+
+/// Processes the messages of a batch page by page, starting at a cursor
+///
+/// The pages of the batch carry length-prefixed messages,
+/// a message can span several pages, so the pages are fed to the frame decoder.
+/// The pages before the cursor were processed before a reboot and are skipped.
+/// The traversal stops at `MAX_BATCH_PAGES`, so that skipping them fits in a kernel run.
+///
+/// When the tick budget is exhausted, the cursor is moved to the next message
+/// and the traversal is stopped.
+struct BatchProcessor<'a> {
+    cursor: BatchCursor,
+    ticks: &'a mut TickCounter,
+    stats: &'a mut LevelStats,
+    frames: FrameDecoder,
+    /// Index of the next content page
+    page: u32,
+    /// Number of bytes pushed to the frame decoder
+    pushed: usize,
+    /// Position in the stream of the frame decoder of the start of each pushed page
+    page_starts: Vec<(u32, usize)>,
+    interrupted: bool,
+}
+
+impl<'a> BatchProcessor<'a> {
+    fn new(cursor: BatchCursor, ticks: &'a mut TickCounter, stats: &'a mut LevelStats) -> Self {
+        BatchProcessor {
+            cursor,
+            ticks,
+            stats,
+            frames: FrameDecoder::new(),
+            page: 0,
+            pushed: 0,
+            page_starts: Vec::new(),
+            interrupted: false,
+        }
+    }
+
+    /// Page and offset in the page of a position in the stream of the frame decoder
+    fn locate(&self, position: usize) -> (u32, u32) {
+        let (page, start) = self
+            .page_starts
+            .iter()
+            .rev()
+            .find(|(_, start)| *start <= position)
+            .copied()
+            .unwrap_or((self.cursor.page, 0));
+        let base = match page == self.cursor.page {
+            true => self.cursor.offset as usize,
+            false => 0,
+        };
+        (page, (base + position - start) as u32)
+    }
+
+    fn handle_page<Host: Runtime>(
+        &mut self,
+        host: &mut Host,
+        page: V0SliceContentPage,
+    ) -> std::result::Result<(), &'static str> {
+        let page_index = self.page;
+        if page_index as usize >= MAX_BATCH_PAGES {
+            return Err(TOO_LARGE);
+        }
+        self.page += 1;
+        let content = match page_index.cmp(&self.cursor.page) {
+            Ordering::Less => {
+                self.ticks.consume(TICKS_PER_SKIPPED_PAGE);
+                return Ok(());
+            }
+            Ordering::Equal => page
+                .as_ref()
+                .get(self.cursor.offset as usize..)
+                .unwrap_or_default(),
+            Ordering::Greater => page.as_ref(),
+        };
+        self.ticks.consume(TICKS_PER_PAGE);
+        self.page_starts.push((page_index, self.pushed));
+        self.pushed += content.len();
+        self.frames.push(content);
+
+        let mut contents = Vec::new();
+        let mut positions = Vec::new();
+        loop {
+            let position = self.frames.position();
+            match self.frames.next_frame() {
+                Some(content) => {
+                    contents.push(content);
+                    positions.push(position);
+                }
+                None => break,
+            }
+        }
+
+        let processed = handle_user_messages(
+            host,
+            self.cursor.level,
+            self.cursor.batch,
+            self.cursor.index,
+            contents,
+            self.ticks,
+            self.stats,
+        )
+        .map_err(|_| "Failed to process the messages")?;
+        self.cursor.index += processed as u32;
+
+        match positions.get(processed) {
+            Some(&position) => {
+                let (page, offset) = self.locate(position);
+                self.cursor.page = page;
+                self.cursor.offset = offset;
+                self.interrupted = true;
+                Err(INTERRUPTED)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Process the messages of a batch, starting at the cursor
+///
+/// If the tick budget is exhausted before the end of the batch,
+/// the cursor is stored to resume the batch after a reboot.
+fn process_batch<R: Runtime>(
+    host: &mut R,
+    cursor: BatchCursor,
+    ticks: &mut TickCounter,
+    stats: &mut LevelStats,
+) -> Result<Progress> {
+    // Support 3 levels of hashes pages, and then bottom layer of content.
+    const MAX_DAC_LEVELS: usize = 4;
+
+    let mut buffer = [0; MAX_PAGE_SIZE * MAX_DAC_LEVELS];
+    let root = cursor.root;
+    let mut processor = BatchProcessor::new(cursor, ticks, stats);
+
+    let result = reveal_loop(
+        host,
+        0,
+        &root,
+        buffer.as_mut_slice(),
+        MAX_DAC_LEVELS,
+        &mut |host: &mut R, page| processor.handle_page(host, page),
+    );
+    if processor.interrupted {
+        debug_msg!(host, "Batch interrupted at message {}\n", processor.cursor.index);
+        store_batch_cursor(host, &processor.cursor)?;
+        return Ok(Progress::Interrupted);
+    }
+    delete_batch_cursor(host)?;
+    if !processor.frames.is_empty() {
+        debug_msg!(host, "Batch ends with a truncated message\n");
+        reject_truncated_message(host, &processor.cursor, processor.frames.pending())?;
+        processor.stats.rejected += 1;
+    }
+    match result {
+        Err(TOO_LARGE) => {
+            debug_msg!(
+                host,
+                "Batch has more than {} pages, the rest is ignored\n",
+                MAX_BATCH_PAGES
+            );
+            Ok(Progress::Complete)
+        }
+        Err(err) => Err(Error::GenericError(String::from(err))),
+        Ok(()) => Ok(Progress::Complete),
+    }
+}
 
-    Ok(())
+/// Rejects the truncated message ending a batch, with a receipt keyed by the hash of its bytes
+///
+/// Its bytes include the length prefix: unlike the other messages, the frame is incomplete.
+fn reject_truncated_message<R: Runtime>(
+    host: &mut R,
+    cursor: &BatchCursor,
+    pending: &[u8],
+) -> Result<()> {
+    let result = Err(Error::InvalidUserMessage);
+    let receipt = Receipt::new(
+        Blake2b::from(pending),
+        cursor.level,
+        cursor.batch,
+        cursor.index,
+        &result,
+    );
+    store_receipt(host, &receipt)
 }
 
-fn handle_txs<Host: Runtime>(
+/// Process a batch of user messages signed by a sequencer
+///
+/// The batch is rejected if it is not signed by one of the sequencers,
+/// or if it does not follow the last accepted batch
+fn handle_batch<R: Runtime>(
+    host: &mut R,
+    message: Message,
     level: u32,
-) -> impl FnMut(&mut Host, V0SliceContentPage) -> std::result::Result<(), &'static str> {
-    move |host, page| {
-        let content: &[u8] = page.as_ref();
-        let content = std::str::from_utf8(content).unwrap();
-        let message: UserMessage = serde_json_wasm::from_str(content).unwrap();
-        // FIXME:
-        step(host, message, level).unwrap();
-        Ok(())
+    ticks: &mut TickCounter,
+    stats: &mut LevelStats,
+) -> Result<Progress> {
+    let Message { signature, header } = message;
+
+    debug_msg!(host, "verifying sequencer signature: {:?}\n", signature);
+    verify_sequencer_signature(host, &signature, &header)?;
+    debug_msg!(host, "sequencer signature is valid\n");
+    verify_batch_header(host, &header, level)?;
+    store_last_batch(host, &header)?;
+
+    let cursor = BatchCursor::start(header.merkle_root, header.index, level);
+    process_batch(host, cursor, ticks, stats)
+}
+
+/// Request another kernel run, with a new tick budget, to continue the processing of the inbox
+fn reboot<R: Runtime>(host: &mut R) -> Result<()> {
+    debug_msg!(host, "Tick budget exhausted, rebooting\n");
+    host.mark_for_reboot().map_err(Error::from)
+}
+
+/// Statistics of the given level
+///
+/// The statistics of the previous level are stored,
+/// and the ones of the given level are read as it may have been started before a reboot
+fn level_stats<'a, R: Runtime>(
+    host: &mut R,
+    stats: &'a mut Option<LevelStats>,
+    level: u32,
+) -> Result<&'a mut LevelStats> {
+    if stats.as_ref().map(|stats| stats.level) != Some(level) {
+        if let Some(previous) = stats.take() {
+            store_level_stats(host, &previous)?;
+        }
+        *stats = Some(read_level_stats(host, level)?);
     }
+    Ok(stats.get_or_insert_with(|| LevelStats::new(level)))
+}
+
+/// Resume the batch interrupted by the previous kernel run, if any
+fn resume_batch<R: Runtime>(
+    host: &mut R,
+    ticks: &mut TickCounter,
+    stats: &mut Option<LevelStats>,
+) -> Result<Progress> {
+    match read_batch_cursor(host)? {
+        None => Ok(Progress::Complete),
+        Some(cursor) => {
+            debug_msg!(host, "Resuming batch at message {}\n", cursor.index);
+            let stats = level_stats(host, stats, cursor.level)?;
+            match process_batch(host, cursor, ticks, stats) {
+                Err(Error::Runtime(err)) => Err(Error::Runtime(err)),
+                Err(err) => {
+                    debug_msg!(host, "Batch rejected: {}\n", err.to_string());
+                    Ok(Progress::Complete)
+                }
+                progress => progress,
+            }
+        }
+    }
+}
+
+/// Continue the work interrupted by the previous kernel run
+///
+/// The migration of the canvas comes first, as no pixel can be placed before its end,
+/// then the interrupted batch and the snapshot of the frozen canvas.
+fn resume<R: Runtime>(
+    host: &mut R,
+    ticks: &mut TickCounter,
+    stats: &mut Option<LevelStats>,
+) -> Result<Progress> {
+    if let Progress::Interrupted = migration::continue_migration(host, ticks)? {
+        return Ok(Progress::Interrupted);
+    }
+    if let Progress::Interrupted = resume_batch(host, ticks, stats)? {
+        return Ok(Progress::Interrupted);
+    }
+    snapshot::continue_snapshot(host, ticks)
 }
 
 /// Process all the inbox
 ///
-/// Read a message, process the error of the read message
-/// If the message is correctly deserialized it continue the execution
-/// Then all the errors, will be stored in a receipt
-/// Continue until the inbox is emptied
+/// Read the messages one by one until the inbox is emptied.
+/// The batches are processed and the errors of their messages are stored in receipts,
+/// the other messages are handled according to their kind.
+/// The statistics of each level are updated along the way.
 ///
-/// This function stop its execution when a RuntimeError happens
+/// Once the canvas is frozen by governance, its snapshot is computed before reading more messages.
 ///
-/// TODO: it can count ticks and reboot the kernel between two inbox message
-fn execute<R: Runtime>(host: &mut R) -> Result<()> {
-    let message = read_input::<ticket::BytesTicket>(host);
-    match message {
-        Err(ReadInputError::EndOfInbox) => Ok(()),
-        Err(ReadInputError::TimeToReboot) => Ok(()),
-        Err(ReadInputError::Runtime(err)) => Err(Error::Runtime(err)),
-        Err(err) => {
-            debug_msg!(host, "Error while reading input: {:?}\n", err);
-            execute(host)
-        }
-        Ok((None, level))  => {
-            execute(host)
-        },
-        Ok((Some(message), level)) => {
-            let Message {
-                signature,
-                unprefixed_merkle_root,
-            } = message;
-
-            let pk = PublicKey::from_b58(&SEQUENCER_PK).unwrap();
-            debug_msg!(host, "verifying sequencer signature: {:?}\n", signature);
-            signature.verify(&pk, &unprefixed_merkle_root)?;
-            debug_msg!(host, "sequencer signature is valid\n");
-
-            // this is done because I don't know how to use Serde properly
-            let mut root_hash = [0; PREIMAGE_HASH_SIZE];
-            root_hash[1..].copy_from_slice(&unprefixed_merkle_root);
-
-            // Support 3 levels of hashes pages, and then bottom layer of content.
-            const MAX_DAC_LEVELS: usize = 4;
-
-            let mut buffer = [0; MAX_PAGE_SIZE * MAX_DAC_LEVELS];
-            let mut handle_txs = handle_txs(level);
-
-            match reveal_loop(
-                host,
-                0,
-                &root_hash,
-                buffer.as_mut_slice(),
-                MAX_DAC_LEVELS,
-                &mut handle_txs,
-            ) {
-                Err(err) => Err(Error::GenericError(String::from(err))),
-                Ok(()) => Ok(()),
+/// The ticks of the run are estimated, when the budget is exhausted
+/// the kernel is marked for reboot and the next run continues with the rest of the inbox
+///
+/// This function stop its execution when a RuntimeError happens
+fn execute<R: Runtime>(
+    host: &mut R,
+    ticks: &mut TickCounter,
+    stats: &mut Option<LevelStats>,
+) -> Result<()> {
+    loop {
+        if !ticks.can_afford(TICKS_PER_INBOX_MESSAGE) {
+            return reboot(host);
+        }
+        ticks.consume(TICKS_PER_INBOX_MESSAGE);
+        let (level, input) = match read_input(host) {
+            Ok(input) => input,
+            Err(ReadInputError::Runtime(err)) => return Err(Error::Runtime(err)),
+            Err(_) => return Ok(()),
+        };
+        let stats = level_stats(host, stats, level)?;
+        stats.inbox_messages += 1;
+
+        match input {
+            Input::StartOfLevel => debug_msg!(host, "Start of level {}\n", level),
+            Input::InfoPerLevel(info) => {
+                debug_msg!(host, "Info: {}\n", info);
+                store_level_info(host, &info)?;
+            }
+            Input::EndOfLevel => debug_msg!(host, "End of level {}\n", level),
+            Input::Transfer(transfer) => match handle_transfer(host, transfer) {
+                Err(Error::Runtime(err)) => return Err(Error::Runtime(err)),
+                Err(err) => {
+                    debug_msg!(host, "Governance message rejected: {}\n", err.to_string())
+                }
+                Ok(()) => {
+                    if let Progress::Interrupted = snapshot::continue_snapshot(host, ticks)? {
+                        return reboot(host);
+                    }
+                }
+            },
+            Input::Batch(message) => match handle_batch(host, message, level, ticks, stats) {
+                Err(Error::Runtime(err)) => return Err(Error::Runtime(err)),
+                Err(err) => debug_msg!(host, "Batch rejected: {}\n", err.to_string()),
+                Ok(Progress::Interrupted) => return reboot(host),
+                Ok(Progress::Complete) => {}
+            },
+            Input::Foreign => {
+                debug_msg!(host, "External message not intended for this rollup\n")
             }
+            Input::Invalid(err) => debug_msg!(host, "Error while reading input: {}\n", err),
         }
     }
 }
 
+/// Initialize the configuration of the durable storage, on the first run of the kernel
+fn genesis<R: Runtime>(host: &mut R) -> Result<()> {
+    storage::init_canvas_config(host)?;
+    storage::init_sequencers(host)
+}
+
 pub fn entry<R: Runtime>(host: &mut R) {
+    run(host, TickCounter::new())
+}
+
+/// Run the kernel with the given tick budget
+fn run<R: Runtime>(host: &mut R, mut ticks: TickCounter) {
     debug_msg!(host, "Executing kernel: {}\n", env!("GIT_HASH"));
     let greeting_path: OwnedPath = "/greeting".as_bytes().to_vec().try_into().unwrap();
     let _ = Runtime::store_write(host, &greeting_path, "hello world".as_bytes(), 0);
-    match execute(host) {
+    if let Err(err) = genesis(host) {
+        debug_msg!(host, "{}\n", &err.to_string());
+        return;
+    }
+    let mut stats = None;
+    let result = match resume(host, &mut ticks, &mut stats) {
+        Ok(Progress::Complete) => execute(host, &mut ticks, &mut stats),
+        Ok(Progress::Interrupted) => reboot(host),
+        Err(err) => Err(err),
+    };
+    let result = match &stats {
+        Some(stats) => result.and(store_level_stats(host, stats)),
+        None => result,
+    };
+    match result {
         Ok(_) => {}
         Err(err) => debug_msg!(host, "{}\n", &err.to_string()),
     }
@@ -132,106 +520,1537 @@ pub fn entry<R: Runtime>(host: &mut R) {
 
 kernel_entry!(entry);
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::dac::encoding::{prepare_preimages, PreimageHash};
+    use lib::framing::{pack, MAX_BATCH_SIZE};
+    use crate::storage::{read_account, store_account, STATS_LEVELS};
+    use lib::message::{BatchHeader, Content, Inner, PlacePixel, SigningScheme, BATCH_VERSION};
+    use tezos_smart_rollup::core_unsafe::PREIMAGE_HASH_SIZE;
+    use lib::nonce::Nonce;
+    use lib::public_key::PublicKey;
+    use tezos_crypto_rs::hash::HashTrait;
+    use lib::account::Account;
+    use lib::canvas::CanvasConfig;
+    use lib::constants::L1_GOVERNANCE_CONTRACT_ADDRESS;
+    use lib::cooldown::CooldownConfig;
+    use lib::event::EventWindow;
+    use lib::governance::GovernanceMessage;
+    use lib::place::{PlaceState, RollupConfig};
+    use lib::transition::Storage;
+    use std::path::PathBuf;
+    use lib::canvas::{BLANK_PIXEL, CHUNK_SIZE, CHUNK_WIDTH};
+    use lib::pixel::PIXEL_RECORD_SIZE;
+    use lib::snapshot::{hash_canvas, SnapshotDestination};
+    use tezos_smart_rollup::storage::path::RefPath;
+    use tezos_smart_rollup::michelson::{ticket, MichelsonBytes};
+    use tezos_smart_rollup::types::Contract;
+    use tezos_smart_rollup_mock::{MockHost, TransferMetadata};
 
+    const SEQUENCER_SK: &str =
+        "edskRrdh2fnaZv2sDYB9Lv6dmNPSeMAMBtRK9E4Ap85ea8pQfaDvxisnhHsCGihvpLBDnbBdwjBPL1nWtJuzWhfXR3LErGut7d";
 
-#[test]
-fn test() {
-   const USER_MESSAGES: &[&str] = &[
-    r#"{
-          "pkey": {
-            "Ed25519": "edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB"
-          },
-          "signature": {
-            "Ed25519": "edsigtpxbt1mWVGykfTE2D87DybgTY7PmvB4Nhg7N3Xuof6DsvGNwNVsXkWa65SLMsvQfav9FwxcEfnZPCvQiWgUnNFjxvCFwDs"
-          },
-          "inner": {
-            "nonce": 777,
-            "content": {
-              "PlacePixel": {
-                "x": 1,
-                "y": 2,
-                "color": [
-                  1,
-                  2,
-                  3
-                ]
-              }
-            }
-          }
-        }"#,
-    // r#"{
-    //       "pkey": {
-    //         "Ed25519": "edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB"
-    //       },
-    //       "signature": {
-    //         "Ed25519": "edsigtZtyBeW9ZVcchuV3ModHtfGgH8zpdMCdm9gTnpBJoXWr1w44uuysVMX8WJyf3dEKpgKz53EpeirUDFK7seQ4iRHF25FSSw"
-    //       },
-    //       "inner": {
-    //         "nonce": 777,
-    //         "content": {
-    //           "PlacePixel": {
-    //             "x": 1,
-    //             "y": 2,
-    //             "rgba": [
-    //               1,
-    //               2,
-    //               3,
-    //               4
-    //             ]
-    //           }
-    //         }
-    //       }
-    //     }"#,
-]; 
-    // let preimage_dir = Path::new("/home/d4hines/repos/tezos-place/rollup/wasm_2_0_0");
-    // if !preimage_dir.is_dir() {
-    //     fs::create_dir_all(preimage_dir).unwrap();
-    // }
-
-    // let save_preimages = |hash: PreimageHash, preimage: Vec<u8>| {
-    //     let name = hex::encode(hash.as_ref());
-    //     let path = preimage_dir.join(name);
-
-    //     if let Err(e) = fs::write(&path, preimage) {
-    //         eprintln!("Failed to write preimage to {:?} due to {}.", path, e);
-    //     }
-    // };
-
-    let content: Vec<Vec<u8>> = USER_MESSAGES
-        .iter()
-        .map(|x| {
-            let bytes: &[u8] = x.as_ref();
-            bytes.to_vec()
+    /// Secret key of edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB
+    fn user_secret_key() -> ed25519_compact::SecretKey {
+        let seed = ed25519_compact::Seed::new([
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31,
+        ]);
+        ed25519_compact::KeyPair::from_seed(seed).sk
+    }
+
+    fn user_public_key_hash() -> PublicKeyHash {
+        let pkey =
+            PublicKey::from_b58("edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB").unwrap();
+        PublicKeyHash::from(pkey)
+    }
+
+    /// Signs a PlacePixel message with the user key
+    fn user_message(nonce: u64, x: u32, y: u32, color: [u8; 3]) -> Vec<u8> {
+        let content = Content::PlacePixel(PlacePixel { x, y, color });
+        let inner = Inner::new(Nonce(nonce), content);
+        let message = UserMessage::new(user_secret_key(), inner);
+        message.to_bytes()
+    }
+
+    fn sequencer_secret_key() -> ed25519_compact::SecretKey {
+        let sk = tezos_crypto_rs::hash::SecretKeyEd25519::from_base58_check(SEQUENCER_SK).unwrap();
+        let sk = sk.as_ref().as_slice();
+        ed25519_compact::SecretKey::from_slice(sk).unwrap()
+    }
+
+    /// Reveals the messages as a DAC batch and adds the signed root hash to the inbox
+    fn add_batch(host: &mut MockHost, messages: Vec<Vec<u8>>) {
+        add_batch_signed_by(host, sequencer_secret_key(), messages)
+    }
+
+    fn add_batch_signed_by(
+        host: &mut MockHost,
+        sk: ed25519_compact::SecretKey,
+        messages: Vec<Vec<u8>>,
+    ) {
+        let merkle_root = prepare_batch(host, messages);
+        let header = next_header(host, merkle_root);
+        let message = batch_message(host, sk, header);
+        host.add_external(message);
+    }
+
+    /// Signs the header for the rollup of the host
+    fn batch_message(
+        host: &mut MockHost,
+        sk: ed25519_compact::SecretKey,
+        header: BatchHeader,
+    ) -> Message {
+        let rollup_address = host.reveal_metadata().unwrap().raw_rollup_address;
+        Message::new(sk, &rollup_address, header)
+    }
+
+    /// Reveals the messages as a DAC batch and returns its root hash
+    fn prepare_batch(host: &mut MockHost, messages: Vec<Vec<u8>>) -> [u8; PREIMAGE_HASH_SIZE] {
+        let root_hash: PreimageHash = prepare_preimages(pack(&messages), |_hash, page| {
+            host.set_preimage(page);
         })
-        .collect();
-    let mut host = tezos_smart_rollup_mock::MockHost::default();
-    let root_hash: PreimageHash = prepare_preimages(content, |_hash, page| {
-        host.set_preimage(page);
-    })
-    .unwrap();
-    let mut unprefixed_merkel_root: [u8; 32] = [0; 32];
-    unprefixed_merkel_root.copy_from_slice(&root_hash.as_ref()[1..]);
-
-    let sk = tezos_crypto_rs::hash::SecretKeyEd25519::from_base58_check(
-            "edskRrdh2fnaZv2sDYB9Lv6dmNPSeMAMBtRK9E4Ap85ea8pQfaDvxisnhHsCGihvpLBDnbBdwjBPL1nWtJuzWhfXR3LErGut7d",
+        .unwrap();
+        *root_hash.as_ref()
+    }
+
+    /// Header following the last batch accepted by the kernel
+    fn next_header(host: &mut MockHost, merkle_root: [u8; PREIMAGE_HASH_SIZE]) -> BatchHeader {
+        match storage::read_last_batch(host).unwrap() {
+            Some((index, previous_root)) => BatchHeader {
+                version: BATCH_VERSION,
+                index: index + 1,
+                level: host.level(),
+                previous_root,
+                merkle_root,
+            },
+            None => BatchHeader::first(merkle_root, host.level()),
+        }
+    }
+
+    /// Adds a ticket from the governance contract carrying the message to the inbox
+    fn add_governance_message(host: &mut MockHost, message: GovernanceMessage) {
+        add_governance_message_from(host, L1_GOVERNANCE_CONTRACT_ADDRESS, message)
+    }
+
+    /// Adds a ticket from the given contract carrying the message to the inbox
+    fn add_governance_message_from(
+        host: &mut MockHost,
+        contract: &str,
+        message: GovernanceMessage,
+    ) {
+        let ticket = ticket::BytesTicket::new(
+            Contract::from_b58check(contract).unwrap(),
+            MichelsonBytes(message.to_bytes()),
+            1,
+        )
+        .unwrap();
+        let sender = tezos_crypto_rs::hash::ContractKt1Hash::from_base58_check(contract).unwrap();
+        let source = tezos_smart_rollup::types::PublicKeyHash::from_b58check(
+            "tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv",
         )
-    .unwrap();
+        .unwrap();
+        host.add_transfer(ticket, &TransferMetadata::new(sender, source));
+    }
 
-     let sk = sk.as_ref().as_slice();
-    let sk = ed25519_compact::SecretKey::from_slice(sk).unwrap();
+    fn read_pixel(host: &mut MockHost, x: u32, y: u32) -> Vec<u8> {
+        storage::read_pixel_color(host, x, y).unwrap().to_vec()
+    }
 
-    let message = Message::new(sk, unprefixed_merkel_root);
+    fn read_receipt(host: &mut MockHost, message: &[u8]) -> (Option<bool>, Option<u32>, u64) {
+        let hash = Blake2b::from(message);
+        let success = storage::read_receipt_success(host, &hash).unwrap();
 
-    MockHost::add_external(&mut host, message);
+        let path: Vec<u8> = format!("/receipts/{}/error", hash.to_string()).into();
+        let path = OwnedPath::try_from(path).unwrap();
+        let error = storage::read_u32(host, &path).unwrap();
 
-    let _level = MockHost::run_level(&mut host, entry);
-    let greeting = "hello world".as_bytes();
-    let greeting_path: OwnedPath = "/greeting".as_bytes().to_vec().try_into().unwrap();
-    let greeting_read = MockHost::store_read(&mut host, &greeting_path, 0, greeting.len()).unwrap();
-    assert!(greeting == greeting_read);
+        let path: Vec<u8> = format!("/receipts/{}/index", hash.to_string()).into();
+        let path = OwnedPath::try_from(path).unwrap();
+        let index = storage::read_u64(host, &path).unwrap().unwrap();
+
+        (success, error, index)
+    }
+
+    fn read_nonce(host: &mut MockHost) -> u64 {
+        let account = read_account(host, user_public_key_hash()).unwrap();
+        account.nonce().0
+    }
+
+    #[test]
+    fn test() {
+        // PlacePixel { x: 1, y: 2, color: [1, 2, 3] } with the nonce 777
+        const USER_MESSAGES: &[&str] = &["010003a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b800dda3a8e94f08375ed548563a1885d374de721add789239c15327df30102fc4ae4a302712c665e437690486f5c8b635c15ca8b6ac603ea91f72c575574b5c59060000000000000309000000000100000002010203"];
+
+        let content: Vec<Vec<u8>> = USER_MESSAGES
+            .iter()
+            .map(|x| hex::decode(x).unwrap())
+            .collect();
+        let mut host = MockHost::default();
+
+        // The message was signed with the nonce 777
+        let account = Account {
+            public_key_hash: user_public_key_hash(),
+            nonce: Nonce(776),
+        };
+        store_account(&mut host, &account).unwrap();
+
+        add_batch(&mut host, content);
+
+        let _level = host.run_level(entry);
+        let greeting = "hello world".as_bytes();
+        let greeting_path: OwnedPath = "/greeting".as_bytes().to_vec().try_into().unwrap();
+        let greeting_read = host.store_read(&greeting_path, 0, greeting.len()).unwrap();
+        assert!(greeting == greeting_read);
+
+        assert_eq!(read_pixel(&mut host, 1, 2), vec![1, 2, 3]);
+        assert_eq!(read_nonce(&mut host), 777);
+    }
+
+    #[test]
+    fn test_replay_in_same_batch_is_rejected() {
+        let mut host = MockHost::default();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        let second = user_message(2, 4, 5, [0, 255, 0]);
+
+        add_batch(&mut host, vec![first.clone(), second, first]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+        assert_eq!(read_nonce(&mut host), 2);
+    }
+
+    #[test]
+    fn test_replay_across_levels_is_rejected() {
+        let mut host = MockHost::default();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        let second = user_message(2, 4, 5, [0, 255, 0]);
+
+        add_batch(&mut host, vec![first.clone(), second.clone()]);
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+
+        add_batch(&mut host, vec![first, second]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+        assert_eq!(read_nonce(&mut host), 2);
+    }
+
+    #[test]
+    fn test_nonce_from_the_future_is_rejected() {
+        let mut host = MockHost::default();
+        let message = user_message(3, 4, 5, [255, 0, 0]);
+
+        add_batch(&mut host, vec![message]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), BLANK_PIXEL.to_vec());
+        assert_eq!(read_nonce(&mut host), 0);
+    }
+
+    #[test]
+    fn test_receipts_are_stored() {
+        let mut host = MockHost::default();
+        let valid = user_message(1, 4, 5, [255, 0, 0]);
+        let invalid_nonce = user_message(5, 4, 5, [0, 255, 0]);
+
+        add_batch(&mut host, vec![valid.clone(), invalid_nonce.clone()]);
+        let level = host.run_level(entry);
+
+        assert_eq!(read_receipt(&mut host, &valid), (Some(true), None, 0));
+        assert_eq!(
+            read_receipt(&mut host, &invalid_nonce),
+            (Some(false), Some(Error::InvalidNonce.code()), 1)
+        );
+
+        let message = UserMessage::from_bytes(&valid).unwrap();
+        let path: Vec<u8> = format!("/receipts/{}/level", message.hash().to_string()).into();
+        let path = OwnedPath::try_from(path).unwrap();
+        assert_eq!(storage::read_u64(&mut host, &path).unwrap(), Some(level.into()));
+        let (batch, _) = storage::read_last_batch(&mut host).unwrap().unwrap();
+        let path: Vec<u8> = format!("/receipts/{}/batch", message.hash().to_string()).into();
+        let path = OwnedPath::try_from(path).unwrap();
+        assert_eq!(storage::read_u64(&mut host, &path).unwrap(), Some(batch));
+    }
+
+    #[test]
+    fn test_replay_does_not_overwrite_receipt() {
+        let mut host = MockHost::default();
+        let message = user_message(1, 4, 5, [255, 0, 0]);
+
+        add_batch(&mut host, vec![message.clone(), message.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(read_receipt(&mut host, &message), (Some(true), None, 0));
+    }
+
+    #[test]
+    fn test_receipts_are_keyed_by_the_signed_message() {
+        let mut host = MockHost::default();
+        let raw = user_message(1, 4, 5, [255, 0, 0]);
+        let content = Content::PlacePixel(PlacePixel {
+            x: 4,
+            y: 5,
+            color: [255, 0, 0],
+        });
+        let inner = Inner::new(Nonce(1), content);
+        let micheline =
+            UserMessage::new_with_scheme(user_secret_key(), inner, SigningScheme::Micheline)
+                .to_bytes();
+
+        add_batch(&mut host, vec![raw.clone(), micheline.clone()]);
+        host.run_level(entry);
+
+        // The same inner signed twice has two receipts
+        assert_eq!(read_receipt(&mut host, &raw), (Some(true), None, 0));
+        assert_eq!(
+            read_receipt(&mut host, &micheline),
+            (Some(false), Some(Error::InvalidNonce.code()), 1)
+        );
+    }
+
+    #[test]
+    fn test_malformed_messages_do_not_abort_the_batch() {
+        let mut host = MockHost::default();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        let truncated = first[..first.len() - 1].to_vec();
+        let json = b"{\"pkey\": 42}".to_vec();
+        let mut invalid_signature = user_message(2, 6, 7, [0, 0, 255]);
+        let last = invalid_signature.len() - 1;
+        invalid_signature[last] = 254;
+        let second = user_message(2, 8, 9, [0, 255, 0]);
+
+        add_batch(
+            &mut host,
+            vec![
+                first.clone(),
+                truncated.clone(),
+                json.clone(),
+                invalid_signature.clone(),
+                second.clone(),
+            ],
+        );
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(read_pixel(&mut host, 8, 9), vec![0, 255, 0]);
+        assert_eq!(read_pixel(&mut host, 6, 7), BLANK_PIXEL.to_vec());
+        assert_eq!(read_nonce(&mut host), 2);
+
+        assert_eq!(
+            read_receipt(&mut host, &truncated),
+            (Some(false), Some(Error::InvalidUserMessage.code()), 1)
+        );
+        assert_eq!(
+            read_receipt(&mut host, &json),
+            (Some(false), Some(Error::InvalidUserMessage.code()), 2)
+        );
+        assert_eq!(
+            read_receipt(&mut host, &invalid_signature),
+            (Some(false), Some(Error::InvalidSignature.code()), 3)
+        );
+        assert_eq!(read_receipt(&mut host, &second), (Some(true), None, 4));
+    }
+
+    #[test]
+    fn test_truncated_message_ending_a_batch_is_rejected() {
+        let mut host = MockHost::default();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        let second = user_message(2, 6, 7, [0, 255, 0]);
+
+        // The length prefix of the second message announces more bytes than the batch has
+        let mut pages = pack(&[first.clone()]);
+        let mut truncated = (second.len() as u32).to_be_bytes().to_vec();
+        truncated.extend_from_slice(&second[..10]);
+        pages.last_mut().unwrap().extend_from_slice(&truncated);
+        let root_hash: PreimageHash = prepare_preimages(pages, |_hash, page| {
+            host.set_preimage(page);
+        })
+        .unwrap();
+        let header = next_header(&mut host, *root_hash.as_ref());
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        let level = host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(
+            read_receipt(&mut host, &truncated),
+            (Some(false), Some(Error::InvalidUserMessage.code()), 1)
+        );
+        let stats = read_level_stats(&mut host, level).unwrap();
+        assert_eq!((stats.accepted, stats.rejected), (1, 1));
+    }
+
+    #[test]
+    fn test_level_stats_are_stored() {
+        let mut host = MockHost::default();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        let invalid_nonce = user_message(5, 4, 5, [0, 255, 0]);
+        let second = user_message(2, 4, 5, [0, 0, 255]);
+
+        add_batch(&mut host, vec![first, invalid_nonce, second]);
+        add_governance_message(&mut host, GovernanceMessage::EnableAllowlist(false));
+        let level = host.run_level(entry);
+
+        let stats = read_level_stats(&mut host, level).unwrap();
+        assert_eq!(
+            stats,
+            LevelStats {
+                level,
+                inbox_messages: 5,
+                accepted: 2,
+                rejected: 1,
+            }
+        );
+
+        let next_level = host.run_level(entry);
+        let stats = read_level_stats(&mut host, next_level).unwrap();
+        assert_eq!((stats.inbox_messages, stats.accepted, stats.rejected), (3, 0, 0));
+    }
+
+    #[test]
+    fn test_level_stats_are_bounded() {
+        let mut host = MockHost::default();
+        let level = host.run_level(entry);
+        let stats = read_level_stats(&mut host, level).unwrap();
+        assert_eq!(stats.inbox_messages, 3);
+
+        // The statistics of a level replace the ones of the level STATS_LEVELS before
+        let later = level + STATS_LEVELS;
+        let mut stats = LevelStats::new(later);
+        stats.accepted = 4;
+        store_level_stats(&mut host, &stats).unwrap();
+        let empty = LevelStats::new(level);
+        assert_eq!(read_level_stats(&mut host, level).unwrap(), empty);
+        assert_eq!(read_level_stats(&mut host, later).unwrap(), stats);
+        let slots = host.store_count_subkeys(&RefPath::assert_from(b"/stats"));
+        assert_eq!(slots.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_level_info_is_stored() {
+        let mut host = MockHost::default();
+        let info = host.info_per_level().clone();
+        host.run_level(entry);
+
+        let timestamp = storage::read_level_timestamp(&mut host).unwrap();
+        assert_eq!(timestamp, Some(info.predecessor_timestamp.i64()));
+    }
+
+    #[test]
+    fn test_canvas_is_frozen_after_the_event() {
+        let mut host = MockHost::default();
+        let timestamp = host.info_per_level().predecessor_timestamp.i64();
+        let window = EventWindow {
+            start: None,
+            end: Some(timestamp + 1),
+        };
+        add_governance_message(&mut host, GovernanceMessage::SetEventWindow(window));
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        add_batch(&mut host, vec![first.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(storage::read_event_window(&mut host).unwrap(), window);
+        assert_eq!(read_receipt(&mut host, &first), (Some(true), None, 0));
 
-    let first_pixel_path: RefPath = RefPath::assert_from(b"/image/1/2");
-    let first_pixel: Vec<u8> = MockHost::store_read(&mut host, &first_pixel_path, 0, 4).unwrap();
-    assert!(first_pixel == vec![1, 2, 3]);
-    ()
+        let second = user_message(2, 4, 5, [0, 255, 0]);
+        add_batch(&mut host, vec![second.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(
+            read_receipt(&mut host, &second),
+            (Some(false), Some(Error::CanvasFrozen.code()), 0)
+        );
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+    }
+
+    #[test]
+    fn test_freeze_sends_the_canvas_hash() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 4,
+            height: 3,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+        let destination =
+            SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint").unwrap();
+        add_governance_message(
+            &mut host,
+            GovernanceMessage::SetSnapshotDestination(destination.clone()),
+        );
+        add_batch(&mut host, vec![user_message(1, 2, 1, [255, 0, 0])]);
+        add_governance_message(&mut host, GovernanceMessage::Freeze);
+        let level = host.run_level(entry);
+
+        let mut rgb = BLANK_PIXEL.repeat(4 * 3);
+        rgb[(4 + 2) * 3..(4 + 3) * 3].copy_from_slice(&[255, 0, 0]);
+        let hash = hash_canvas(&rgb, 4);
+        assert!(storage::is_frozen(&mut host).unwrap());
+        assert_eq!(
+            storage::read_snapshot_hash(&mut host).unwrap(),
+            Some(hash.to_vec())
+        );
+        assert_eq!(
+            host.outbox_at(level),
+            vec![destination.outbox_message(&hash).unwrap()]
+        );
+
+        let late = user_message(2, 0, 0, [0, 255, 0]);
+        add_batch(&mut host, vec![late.clone()]);
+        add_governance_message(&mut host, GovernanceMessage::Freeze);
+        let level = host.run_level(entry);
+        assert_eq!(
+            read_receipt(&mut host, &late),
+            (Some(false), Some(Error::CanvasFrozen.code()), 0)
+        );
+        assert!(host.outbox_at(level).is_empty());
+    }
+
+    #[test]
+    fn test_hash_is_sent_to_a_destination_set_after_the_freeze() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 4,
+            height: 3,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+        add_governance_message(&mut host, GovernanceMessage::Freeze);
+        let level = host.run_level(entry);
+        assert!(host.outbox_at(level).is_empty());
+
+        let destination =
+            SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint").unwrap();
+        add_governance_message(
+            &mut host,
+            GovernanceMessage::SetSnapshotDestination(destination.clone()),
+        );
+        let level = host.run_level(entry);
+        let hash = hash_canvas(&BLANK_PIXEL.repeat(4 * 3), 4);
+        assert_eq!(
+            host.outbox_at(level),
+            vec![destination.outbox_message(&hash).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_spreads_over_reboots() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 1024,
+            height: 8,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+        add_batch(&mut host, vec![user_message(1, 1023, 7, [0, 0, 255])]);
+        add_governance_message(&mut host, GovernanceMessage::Freeze);
+        host.run_level(entry_with_small_budget);
+
+        let mut rgb = BLANK_PIXEL.repeat(1024 * 8);
+        let last = rgb.len() - 3;
+        rgb[last..].copy_from_slice(&[0, 0, 255]);
+        assert_eq!(storage::read_snapshot_progress(&mut host).unwrap(), None);
+        assert_eq!(
+            storage::read_snapshot_hash(&mut host).unwrap(),
+            Some(hash_canvas(&rgb, 1024).to_vec())
+        );
+    }
+
+    #[test]
+    fn test_canvas_is_migrated_to_chunks() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 1024,
+            height: 4,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+        let legacy_pixels = [(0, 0, [1, 2, 3]), (300, 1, [4, 5, 6]), (1023, 3, [7, 8, 9])];
+        for (x, y, color) in legacy_pixels {
+            let path = OwnedPath::try_from(format!("/image/{}/{}", x, y)).unwrap();
+            host.store_write(&path, &color, 0).unwrap();
+        }
+        add_batch(&mut host, vec![user_message(1, 0, 0, [255, 0, 0])]);
+        host.run_level(entry_with_small_budget);
+
+        let path = RefPath::assert_from(b"/image");
+        assert!(!storage::exists(&mut host, &path).unwrap());
+        assert_eq!(read_pixel(&mut host, 0, 0), vec![255, 0, 0]);
+        assert_eq!(read_pixel(&mut host, 300, 1), vec![4, 5, 6]);
+        assert_eq!(read_pixel(&mut host, 1023, 3), vec![7, 8, 9]);
+        assert_eq!(read_pixel(&mut host, 301, 1), BLANK_PIXEL.to_vec());
+
+        let row = storage::read_canvas_row(&mut host, 1, 1024).unwrap();
+        assert_eq!(row.len(), 1024 * 3);
+        assert_eq!(row[300 * 3..301 * 3], [4, 5, 6]);
+        let path = RefPath::assert_from(b"/canvas/1/1");
+        assert_eq!(host.store_value_size(&path).unwrap(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_keys_are_counted_after_migration() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 1024,
+            height: 4,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+        for y in 0..4 {
+            for x in 0..1024 {
+                let path = OwnedPath::try_from(format!("/image/{}/{}", x, y)).unwrap();
+                host.store_write(&path, &[x as u8, y as u8, 0], 0).unwrap();
+            }
+        }
+        let messages = vec![
+            user_message(1, 0, 0, [255, 0, 0]),
+            user_message(2, 300, 0, [0, 255, 0]),
+            user_message(3, 1023, 3, [0, 0, 255]),
+        ];
+        add_batch(&mut host, messages);
+        host.run_level(entry);
+
+        let path = RefPath::assert_from(b"/image");
+        assert!(!storage::exists(&mut host, &path).unwrap());
+        assert_eq!(read_pixel(&mut host, 5, 2), vec![5, 2, 0]);
+        assert_eq!(read_pixel(&mut host, 1023, 3), vec![0, 0, 255]);
+
+        // One key per chunk of the canvas, and one per chunk holding an attributed pixel
+        let path = RefPath::assert_from(b"/canvas");
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 4);
+        for y in 0..4 {
+            let path = OwnedPath::try_from(format!("/canvas/{}", y)).unwrap();
+            assert_eq!(host.store_count_subkeys(&path).unwrap(), 4);
+        }
+        let path = RefPath::assert_from(b"/pixels");
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 2);
+        let path = RefPath::assert_from(b"/pixels/0");
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 2);
+        let path = RefPath::assert_from(b"/pixels/3");
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 1);
+        assert_eq!(storage::read_pixel_version(&mut host, 300, 0).unwrap(), 1);
+        assert_eq!(storage::read_pixel_version(&mut host, 301, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_pixels_cannot_be_placed_before_the_event() {
+        let mut host = MockHost::default();
+        let timestamp = host.info_per_level().predecessor_timestamp.i64();
+        let window = EventWindow {
+            start: Some(timestamp + 1),
+            end: None,
+        };
+        storage::store_event_window(&mut host, &window).unwrap();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        add_batch(&mut host, vec![first.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(
+            read_receipt(&mut host, &first),
+            (Some(false), Some(Error::EventNotStarted.code()), 0)
+        );
+
+        let second = user_message(2, 4, 5, [0, 255, 0]);
+        add_batch(&mut host, vec![second.clone()]);
+        host.run_level(entry);
+        assert_eq!(read_receipt(&mut host, &second), (Some(true), None, 0));
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+    }
+
+    #[test]
+    fn test_cooldown_is_enforced() {
+        let mut host = MockHost::default();
+        let config = CooldownConfig {
+            max_pixels: 2,
+            levels: 2,
+        };
+        storage::store_cooldown_config(&mut host, &config).unwrap();
+
+        let rejected = user_message(3, 2, 0, [0, 0, 255]);
+        add_batch(
+            &mut host,
+            vec![
+                user_message(1, 0, 0, [255, 0, 0]),
+                user_message(2, 1, 0, [255, 0, 0]),
+                rejected.clone(),
+            ],
+        );
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 1, 0), vec![255, 0, 0]);
+        assert_eq!(
+            read_receipt(&mut host, &rejected),
+            (
+                Some(false),
+                Some(Error::CooldownNotElapsed.code()),
+                2
+            )
+        );
+
+        add_batch(&mut host, vec![user_message(4, 3, 0, [255, 0, 0])]);
+        host.run_level(entry);
+        add_batch(&mut host, vec![user_message(5, 4, 0, [255, 0, 0])]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 2, 0), BLANK_PIXEL.to_vec());
+        assert_eq!(read_pixel(&mut host, 3, 0), BLANK_PIXEL.to_vec());
+        assert_eq!(read_pixel(&mut host, 4, 0), vec![255, 0, 0]);
+        assert_eq!(read_nonce(&mut host), 5);
+    }
+
+    #[test]
+    fn test_cooldown_is_set_by_the_governance() {
+        let mut host = MockHost::default();
+        let config = CooldownConfig {
+            max_pixels: 1,
+            levels: 10,
+        };
+        add_governance_message(&mut host, GovernanceMessage::SetCooldown(config));
+        let rejected = user_message(2, 1, 0, [255, 0, 0]);
+        add_batch(
+            &mut host,
+            vec![user_message(1, 0, 0, [255, 0, 0]), rejected.clone()],
+        );
+        host.run_level(entry);
+
+        assert_eq!(storage::read_cooldown_config(&mut host).unwrap(), config);
+        assert_eq!(
+            read_receipt(&mut host, &rejected),
+            (
+                Some(false),
+                Some(Error::CooldownNotElapsed.code()),
+                1
+            )
+        );
+        assert_eq!(read_pixel(&mut host, 1, 0), BLANK_PIXEL.to_vec());
+    }
+
+    #[test]
+    fn test_canvas_config_is_stored_at_genesis() {
+        let mut host = MockHost::default();
+        host.run_level(entry);
+
+        let config = storage::read_canvas_config(&mut host).unwrap();
+        assert_eq!(config, CanvasConfig::default());
+        let path = RefPath::assert_from(b"/config/canvas/width");
+        assert_eq!(storage::read_u32(&mut host, &path).unwrap(), Some(1024));
+    }
+
+    #[test]
+    fn test_out_of_bounds_pixel_is_rejected() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            width: 10,
+            height: 10,
+            palette: None,
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+
+        let out_of_bounds = user_message(1, 10, 3, [255, 0, 0]);
+        add_batch(&mut host, vec![out_of_bounds.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 10, 3), BLANK_PIXEL.to_vec());
+        assert_eq!(
+            read_receipt(&mut host, &out_of_bounds),
+            (Some(false), Some(Error::PixelOutOfBounds.code()), 0)
+        );
+    }
+
+    #[test]
+    fn test_color_outside_of_palette_is_rejected() {
+        let mut host = MockHost::default();
+        let config = CanvasConfig {
+            palette: Some(vec![[0, 0, 0], [255, 255, 255]]),
+            ..CanvasConfig::default()
+        };
+        storage::store_canvas_config(&mut host, &config).unwrap();
+
+        let invalid_color = user_message(1, 4, 5, [255, 0, 0]);
+        let valid_color = user_message(2, 4, 5, [255, 255, 255]);
+        add_batch(&mut host, vec![invalid_color.clone(), valid_color]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 255, 255]);
+        assert_eq!(
+            read_receipt(&mut host, &invalid_color),
+            (Some(false), Some(Error::InvalidColor.code()), 0)
+        );
+    }
+
+    #[test]
+    fn test_pixel_attribution_is_stored() {
+        let mut host = MockHost::default();
+        add_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        let first_level = host.run_level(entry);
+        add_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        let second_level = host.run_level(entry);
+
+        let record = storage::read_pixel_record(&mut host, 4, 5).unwrap().unwrap();
+        assert_eq!(record.owner, user_public_key_hash());
+        assert_eq!(record.level, second_level);
+        assert_eq!(record.version, 2);
+        assert_eq!(record.color, [0, 255, 0]);
+
+        let history = storage::read_pixel_history(&mut host, 4, 5).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].level, first_level);
+        assert_eq!(history[1].color, [255, 0, 0]);
+
+        let pixels = storage::read_account_pixels(&mut host, &user_public_key_hash()).unwrap();
+        assert_eq!(pixels, 2);
+        assert!(storage::read_pixel_record(&mut host, 5, 4).unwrap().is_none());
+
+        // The histories of the pixels of a chunk share one value, the total is kept with the cooldown
+        let path = RefPath::assert_from(b"/pixels/5");
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 1);
+        let path = RefPath::assert_from(b"/pixels/5/0");
+        assert_eq!(host.store_value_size(&path).unwrap(), CHUNK_WIDTH as usize * 8 * PIXEL_RECORD_SIZE);
+        let path = OwnedPath::try_from(format!("/accounts/{}", user_public_key_hash().to_string())).unwrap();
+        assert_eq!(host.store_count_subkeys(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_pixel_history_is_bounded() {
+        let mut host = MockHost::default();
+        let config = CooldownConfig {
+            max_pixels: 100,
+            levels: 1,
+        };
+        storage::store_cooldown_config(&mut host, &config).unwrap();
+
+        let messages = (1..=10)
+            .map(|nonce| user_message(nonce, 4, 5, [nonce as u8, 0, 0]))
+            .collect();
+        add_batch(&mut host, messages);
+        host.run_level(entry);
+
+        let history = storage::read_pixel_history(&mut host, 4, 5).unwrap();
+        let versions: Vec<u64> = history.iter().map(|record| record.version).collect();
+        assert_eq!(versions, vec![10, 9, 8, 7, 6, 5, 4, 3]);
+        assert_eq!(history[0].color, [10, 0, 0]);
+    }
+
+    #[test]
+    fn test_allowlist_is_enforced() {
+        let mut host = MockHost::default();
+        add_governance_message(&mut host, GovernanceMessage::EnableAllowlist(true));
+        let not_allowed = user_message(1, 4, 5, [255, 0, 0]);
+        add_batch(&mut host, vec![not_allowed.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), BLANK_PIXEL.to_vec());
+        assert_eq!(
+            read_receipt(&mut host, &not_allowed),
+            (Some(false), Some(Error::NotAllowed.code()), 0)
+        );
+        assert_eq!(read_nonce(&mut host), 0);
+
+        add_governance_message(&mut host, GovernanceMessage::AllowlistAdd(user_public_key_hash()));
+        add_batch(&mut host, vec![user_message(1, 4, 5, [0, 255, 0])]);
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+
+        add_governance_message(
+            &mut host,
+            GovernanceMessage::AllowlistRemove(user_public_key_hash()),
+        );
+        add_batch(&mut host, vec![user_message(2, 4, 5, [0, 0, 255])]);
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+    }
+
+    #[test]
+    fn test_governance_from_another_contract_is_ignored() {
+        let mut host = MockHost::default();
+        add_governance_message_from(
+            &mut host,
+            "KT1NgXQ6Mwu3XKFDcKdYFS6dkkY3iNKdBKEc",
+            GovernanceMessage::EnableAllowlist(true),
+        );
+        add_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+    }
+
+    #[test]
+    fn test_sequencer_key_rotation() {
+        let mut host = MockHost::default();
+        let seed = ed25519_compact::Seed::new([42; 32]);
+        let new_sequencer = ed25519_compact::KeyPair::from_seed(seed);
+        let new_sequencer_pk =
+            tezos_crypto_rs::hash::PublicKeyEd25519::try_from_bytes(new_sequencer.pk.as_ref())
+                .unwrap();
+        let new_sequencer_pk = PublicKey::from_b58(&new_sequencer_pk.to_base58_check()).unwrap();
+
+        add_batch_signed_by(
+            &mut host,
+            new_sequencer.sk.clone(),
+            vec![user_message(1, 4, 5, [255, 0, 0])],
+        );
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), BLANK_PIXEL.to_vec());
+
+        add_governance_message(
+            &mut host,
+            GovernanceMessage::AddSequencer(new_sequencer_pk.clone()),
+        );
+        add_batch_signed_by(
+            &mut host,
+            new_sequencer.sk.clone(),
+            vec![user_message(1, 4, 5, [255, 0, 0])],
+        );
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(storage::read_sequencers(&mut host).unwrap().len(), 2);
+
+        let old_sequencer_pk = PublicKey::from_b58(lib::constants::SEQUENCER_PK).unwrap();
+        add_governance_message(&mut host, GovernanceMessage::RemoveSequencer(old_sequencer_pk));
+        add_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(
+            storage::read_sequencers(&mut host).unwrap(),
+            vec![new_sequencer_pk.clone()]
+        );
+
+        add_governance_message(&mut host, GovernanceMessage::RemoveSequencer(new_sequencer_pk));
+        host.run_level(entry);
+        assert_eq!(storage::read_sequencers(&mut host).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_duplicated_batch_is_rejected() {
+        let mut host = MockHost::default();
+        let root = prepare_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        let header = next_header(&mut host, root);
+        let message = batch_message(&mut host, sequencer_secret_key(), header.clone());
+        host.add_external(message);
+        host.run_level(entry);
+
+        // A batch signed by the sequencer can be injected again by anyone
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        add_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+        assert_eq!(storage::read_last_batch(&mut host).unwrap().unwrap().0, 1);
+    }
+
+    #[test]
+    fn test_missing_batch_is_rejected() {
+        let mut host = MockHost::default();
+        add_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        host.run_level(entry);
+
+        let first = BatchHeader::first([0; 33], 0);
+        let skipped = first.next([1; 33], 0);
+        let root = prepare_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        let mut header = next_header(&mut host, root);
+        header.index += 1;
+        header.previous_root = skipped.merkle_root;
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(storage::read_last_batch(&mut host).unwrap().unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_batch_with_wrong_previous_root_is_rejected() {
+        let mut host = MockHost::default();
+        add_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        host.run_level(entry);
+
+        let root = prepare_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        let mut header = next_header(&mut host, root);
+        header.previous_root = [7; 33];
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+    }
+
+    #[test]
+    fn test_batch_level_is_checked() {
+        let mut host = MockHost::default();
+        host.run_level(entry);
+        host.run_level(entry);
+
+        // A batch cannot be built for a level after its inclusion
+        let root = prepare_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        let mut header = next_header(&mut host, root);
+        header.level += 1;
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        host.run_level(entry);
+        assert!(storage::read_last_batch(&mut host).unwrap().is_none());
+
+        add_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        let level = host.run_level(entry);
+        assert_eq!(storage::read_last_batch_level(&mut host).unwrap(), level);
+
+        // Nor for a level before the one of the last batch
+        let root = prepare_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        let mut header = next_header(&mut host, root);
+        header.level = level - 1;
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        host.run_level(entry);
+        assert_eq!(storage::read_last_batch(&mut host).unwrap().unwrap().0, 0);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+
+        // A batch built for an earlier level is accepted
+        let root = prepare_batch(&mut host, vec![user_message(2, 4, 5, [0, 255, 0])]);
+        let mut header = next_header(&mut host, root);
+        header.level = level;
+        let message = batch_message(&mut host, sequencer_secret_key(), header);
+        host.add_external(message);
+        let inclusion_level = host.run_level(entry);
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+
+        // Its messages are applied at the level it was built for
+        let record = storage::read_pixel_record(&mut host, 4, 5).unwrap().unwrap();
+        assert_eq!(record.level, level);
+        let hash = Blake2b::from(&user_message(2, 4, 5, [0, 255, 0]));
+        let path: Vec<u8> = format!("/receipts/{}/level", hash.to_string()).into();
+        let path = OwnedPath::try_from(path).unwrap();
+        assert_eq!(storage::read_u64(&mut host, &path).unwrap(), Some(inclusion_level.into()));
+    }
+
+    #[test]
+    fn test_batch_signed_for_another_rollup_is_rejected() {
+        let mut host = MockHost::default();
+        let root = prepare_batch(&mut host, vec![user_message(1, 4, 5, [255, 0, 0])]);
+        let header = next_header(&mut host, root);
+        host.add_external(Message::new(sequencer_secret_key(), &[0; 20], header));
+        host.run_level(entry);
+
+        assert_eq!(read_nonce(&mut host), 0);
+        assert!(storage::read_last_batch(&mut host).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_messages_span_several_pages() {
+        let mut host = MockHost::default();
+        let config = CooldownConfig {
+            max_pixels: 100,
+            levels: 1,
+        };
+        storage::store_cooldown_config(&mut host, &config).unwrap();
+
+        let messages: Vec<Vec<u8>> = (0..90)
+            .map(|i| user_message(i + 1, i as u32, 0, [255, 0, 0]))
+            .collect();
+        assert!(pack(&messages).len() > 1);
+        add_batch(&mut host, messages.clone());
+        host.run_level(entry);
+
+        assert_eq!(read_nonce(&mut host), 90);
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(read_pixel(&mut host, i as u32, 0), vec![255, 0, 0]);
+            assert_eq!(read_receipt(&mut host, message), (Some(true), None, i as u64));
+        }
+    }
+
+    #[test]
+    fn test_secp256k1_and_p256_users_can_place_pixels() {
+        // PlacePixel { x: 4, y: 5, color: [255, 0, 0] } with the nonce 1, signed by
+        // sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV and
+        // p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS
+        let vectors = [
+            (
+                "tz2JMcJCm8XXZGqZDYEKqEQ81r29xfY8FgfX",
+                "01010284bf7562262bbd6940085748f3be6afa52ae317155181ece31b66351ccffa4b00179197f8c98e525a4958cffddd5277cae6e0f8f34452ef07795d3f8bf48af037b2e78d4c474f2becfe518b28a7a1b52496dd3fb7ef7dbb210f32dfda23ed8ff040000000000000001000000000400000005ff0000",
+            ),
+            (
+                "tz3eMN7uTh8FG734or1EzSKwXKJQDdevUKLH",
+                "010202515c3d6eb9e396b904d3feca7f54fdcd0cc1e997bf375dca515ad0a6c3b4035f027cfb3b8304d9822c7bd6fe424545fcaa9e4496a6d47b6035b4afe07278a521c05007539efb7883633a12e5b7823aec551cbccb62834c2a0d427f6cd032114d4d0000000000000001000000000400000005ff0000",
+            ),
+        ];
+        for (pkh, message) in vectors {
+            let mut host = MockHost::default();
+            let message = hex::decode(message).unwrap();
+            add_batch(&mut host, vec![message.clone()]);
+            host.run_level(entry);
+
+            assert_eq!(read_receipt(&mut host, &message), (Some(true), None, 0));
+            assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+            let pkh = PublicKeyHash::from_b58(pkh).unwrap();
+            let account = read_account(&mut host, pkh.clone()).unwrap();
+            assert_eq!(account.nonce().0, 1);
+            let owner = storage::read_pixel_record(&mut host, 4, 5).unwrap().unwrap().owner;
+            assert_eq!(owner, pkh);
+        }
+    }
+
+    #[test]
+    fn test_installer_config_is_kept_at_genesis() {
+        let mut host = MockHost::default();
+        let seed = ed25519_compact::Seed::new([42; 32]);
+        let sequencer = ed25519_compact::KeyPair::from_seed(seed);
+        // As revealed by the setup of `upgrade-client get-setup-config`
+        let path = RefPath::assert_from(b"/config/sequencers");
+        host.store_write(&path, sequencer.pk.as_ref(), 0).unwrap();
+        let path = RefPath::assert_from(b"/config/event/end");
+        host.store_write(&path, &1_700_000_000_i64.to_be_bytes(), 0)
+            .unwrap();
+
+        add_batch_signed_by(
+            &mut host,
+            sequencer.sk.clone(),
+            vec![user_message(1, 4, 5, [255, 0, 0])],
+        );
+        host.run_level(entry);
+
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!(storage::read_sequencers(&mut host).unwrap().len(), 1);
+        assert_eq!(
+            storage::read_event_window(&mut host).unwrap(),
+            EventWindow {
+                start: None,
+                end: Some(1_700_000_000)
+            }
+        );
+    }
+
+    #[test]
+    fn test_sequencer_keys_have_to_be_ed25519() {
+        let mut host = MockHost::default();
+        let key =
+            PublicKey::from_b58("sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV").unwrap();
+        add_governance_message(&mut host, GovernanceMessage::AddSequencer(key));
+        host.run_level(entry);
+
+        assert_eq!(storage::read_sequencers(&mut host).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_wallet_signed_messages_are_accepted() {
+        let mut host = MockHost::default();
+        let content = Content::PlacePixel(PlacePixel {
+            x: 4,
+            y: 5,
+            color: [255, 0, 0],
+        });
+        let inner = Inner::new(Nonce(1), content);
+        let message =
+            UserMessage::new_with_scheme(user_secret_key(), inner, SigningScheme::Micheline)
+                .to_bytes();
+        let second = user_message(2, 4, 5, [0, 255, 0]);
+
+        add_batch(&mut host, vec![message.clone(), second.clone()]);
+        host.run_level(entry);
+
+        assert_eq!(read_receipt(&mut host, &message), (Some(true), None, 0));
+        assert_eq!(read_receipt(&mut host, &second), (Some(true), None, 1));
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![0, 255, 0]);
+        assert_eq!(read_nonce(&mut host), 2);
+    }
+
+    /// Kernel run with a budget of 14 user messages, after the batch header and its first page
+    fn entry_with_small_budget(host: &mut MockHost) {
+        run(host, TickCounter::with_budget(1_000_000_000))
+    }
+
+    /// Stores a cooldown configuration allowing the user to place all the pixels of a test
+    fn disable_cooldown(host: &mut MockHost) {
+        let config = CooldownConfig {
+            max_pixels: 1000,
+            levels: 1,
+        };
+        storage::store_cooldown_config(host, &config).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_batch_is_resumed() {
+        let mut host = MockHost::default();
+        genesis(&mut host).unwrap();
+        disable_cooldown(&mut host);
+        let messages: Vec<Vec<u8>> = (0..90)
+            .map(|i| user_message(i + 1, i as u32, 0, [255, 0, 0]))
+            .collect();
+        let frame_size = (4 + messages[0].len()) as u32;
+        let root = prepare_batch(&mut host, messages.clone());
+
+        let mut ticks = TickCounter::with_budget(TICKS_PER_PAGE + 10 * TICKS_PER_USER_MESSAGE);
+        let progress = process_batch(
+            &mut host,
+            BatchCursor::start(root, 0, 1),
+            &mut ticks,
+            &mut LevelStats::new(1),
+        )
+        .unwrap();
+        assert_eq!(progress, Progress::Interrupted);
+        let cursor = read_batch_cursor(&mut host).unwrap().unwrap();
+        assert_eq!(
+            cursor,
+            BatchCursor {
+                root,
+                batch: 0,
+                level: 1,
+                index: 10,
+                page: 0,
+                offset: 10 * frame_size,
+            }
+        );
+        assert_eq!(read_nonce(&mut host), 10);
+
+        let mut cursors = vec![cursor.clone()];
+        let mut cursor = cursor;
+        loop {
+            let mut ticks = TickCounter::with_budget(1_000_000_000);
+            match process_batch(&mut host, cursor, &mut ticks, &mut LevelStats::new(1)).unwrap() {
+                Progress::Complete => break,
+                Progress::Interrupted => {
+                    cursor = read_batch_cursor(&mut host).unwrap().unwrap();
+                    cursors.push(cursor.clone());
+                }
+            }
+        }
+        assert!(read_batch_cursor(&mut host).unwrap().is_none());
+        assert!(cursors.windows(2).all(|pair| pair[0].index < pair[1].index));
+        for cursor in cursors.iter() {
+            let position = cursor.page * V0SliceContentPage::MAX_CONTENT_SIZE as u32 + cursor.offset;
+            assert_eq!(position, cursor.index * frame_size);
+        }
+        assert!(cursors.iter().any(|cursor| cursor.page > 0));
+
+        assert_eq!(read_nonce(&mut host), 90);
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(read_pixel(&mut host, i as u32, 0), vec![255, 0, 0]);
+            assert_eq!(read_receipt(&mut host, message), (Some(true), None, i as u64));
+        }
+    }
+
+    #[test]
+    fn test_pages_beyond_the_limit_are_ignored() {
+        let mut host = MockHost::default();
+        genesis(&mut host).unwrap();
+        let first = user_message(1, 4, 5, [255, 0, 0]);
+        // The second message ends after the last page a batch can have
+        let root = prepare_batch(&mut host, vec![first, vec![0; MAX_BATCH_SIZE]]);
+
+        let mut ticks = TickCounter::with_budget(u64::MAX);
+        let mut stats = LevelStats::new(1);
+        let progress =
+            process_batch(&mut host, BatchCursor::start(root, 0, 1), &mut ticks, &mut stats)
+                .unwrap();
+
+        assert_eq!(progress, Progress::Complete);
+        assert!(read_batch_cursor(&mut host).unwrap().is_none());
+        assert_eq!(read_pixel(&mut host, 4, 5), vec![255, 0, 0]);
+        assert_eq!((stats.accepted, stats.rejected), (1, 1));
+    }
+
+    #[test]
+    fn test_kernel_reboots_when_the_budget_is_exhausted() {
+        let mut host = MockHost::default();
+        disable_cooldown(&mut host);
+        let first: Vec<Vec<u8>> = (0..50)
+            .map(|i| user_message(i + 1, i as u32, 0, [255, 0, 0]))
+            .collect();
+        let second: Vec<Vec<u8>> = (50..60)
+            .map(|i| user_message(i + 1, i as u32, 0, [0, 255, 0]))
+            .collect();
+        // Both batches are in the inbox of the same level
+        let header = BatchHeader::first(prepare_batch(&mut host, first.clone()), host.level());
+        let next_header = header.next(prepare_batch(&mut host, second.clone()), host.level());
+        for header in [header, next_header] {
+            let message = batch_message(&mut host, sequencer_secret_key(), header);
+            host.add_external(message);
+        }
+
+        let level = host.run_level(entry_with_small_budget);
+
+        assert!(read_batch_cursor(&mut host).unwrap().is_none());
+        let stats = read_level_stats(&mut host, level).unwrap();
+        assert_eq!((stats.inbox_messages, stats.accepted, stats.rejected), (5, 60, 0));
+        assert_eq!(read_nonce(&mut host), 60);
+        for (i, message) in first.iter().chain(second.iter()).enumerate() {
+            let index = i as u64 % 50;
+            assert_eq!(read_receipt(&mut host, message), (Some(true), None, index));
+        }
+        assert_eq!(read_pixel(&mut host, 59, 0), vec![0, 255, 0]);
+    }
+
+    /// Messages of several curves, with an invalid signature in the middle
+    fn mixed_messages() -> Vec<Vec<u8>> {
+        // PlacePixel { x: 4, y: 5, color: [255, 0, 0] } with the nonce 1,
+        // signed by sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV
+        let secp256k1 = hex::decode("01010284bf7562262bbd6940085748f3be6afa52ae317155181ece31b66351ccffa4b00179197f8c98e525a4958cffddd5277cae6e0f8f34452ef07795d3f8bf48af037b2e78d4c474f2becfe518b28a7a1b52496dd3fb7ef7dbb210f32dfda23ed8ff040000000000000001000000000400000005ff0000").unwrap();
+        let mut invalid_signature = user_message(2, 6, 7, [0, 0, 255]);
+        let last = invalid_signature.len() - 1;
+        invalid_signature[last] = 254;
+        vec![
+            user_message(1, 1, 1, [255, 0, 0]),
+            secp256k1,
+            invalid_signature,
+            user_message(2, 2, 2, [0, 255, 0]),
+        ]
+    }
+
+    #[test]
+    fn test_batch_verification_falls_back_to_each_message() {
+        let messages = mixed_messages();
+        let mut host = MockHost::default();
+        genesis(&mut host).unwrap();
+        let mut ticks = TickCounter::new();
+        let processed = handle_user_messages(
+            &mut host,
+            1,
+            1,
+            0,
+            messages.clone(),
+            &mut ticks,
+            &mut LevelStats::new(1),
+        )
+        .unwrap();
+
+        assert_eq!(processed, 4);
+        assert_eq!(read_receipt(&mut host, &messages[0]), (Some(true), None, 0));
+        assert_eq!(read_receipt(&mut host, &messages[1]), (Some(true), None, 1));
+        assert_eq!(
+            read_receipt(&mut host, &messages[2]),
+            (Some(false), Some(Error::InvalidSignature.code()), 2)
+        );
+        assert_eq!(read_receipt(&mut host, &messages[3]), (Some(true), None, 3));
+        assert_eq!(read_pixel(&mut host, 2, 2), vec![0, 255, 0]);
+        assert_eq!(read_nonce(&mut host), 2);
+    }
+
+    /// Compares the ticks of the batch verification with the verification of each message
+    ///
+    /// With the same budget, fewer messages are processed when one signature is invalid,
+    /// as each signature is then verified on its own.
+    #[test]
+    fn bench_signature_verification() {
+        const MESSAGES: u64 = 100;
+        const BUDGET: u64 = 50 * (TICKS_PER_USER_MESSAGE + TICKS_PER_SIGNATURE);
+        let mut messages: Vec<Vec<u8>> = (0..MESSAGES)
+            .map(|i| user_message(i + 1, i as u32 % 10, i as u32 / 10, [255, 0, 0]))
+            .collect();
+        let config = CooldownConfig {
+            max_pixels: MESSAGES as u32,
+            levels: 1,
+        };
+        let run = |messages: &Vec<Vec<u8>>| {
+            let mut host = MockHost::default();
+            genesis(&mut host).unwrap();
+            storage::store_cooldown_config(&mut host, &config).unwrap();
+            let mut ticks = TickCounter::with_budget(BUDGET);
+            let mut stats = LevelStats::new(1);
+            let processed =
+                handle_user_messages(&mut host, 1, 1, 0, messages.clone(), &mut ticks, &mut stats)
+                    .unwrap();
+            (processed, stats.accepted)
+        };
+
+        // All the signatures are verified at once
+        let (processed, accepted) = run(&messages);
+        assert_eq!(processed, 83);
+        assert_eq!(accepted, 83);
+
+        // The fallback verifies each signature on its own
+        let last = messages[10].len() - 1;
+        messages[10][last] ^= 1;
+        let (processed, accepted) = run(&messages);
+        assert_eq!(processed, 50);
+        assert_eq!(accepted, 10);
+    }
+
+    /// Pseudo-random generator of the differential tests (xorshift64*), seeded for reproducibility
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn color(&mut self) -> [u8; 3] {
+            let bytes = self.next().to_be_bytes();
+            [bytes[0], bytes[1], bytes[2]]
+        }
+    }
+
+    /// Write the configuration read by the sequencer to the durable storage of the kernel
+    fn store_rollup_config(host: &mut MockHost, accounts: &[PublicKeyHash], config: &RollupConfig) {
+        storage::store_allowlist_enabled(host, config.allowlist.is_some()).unwrap();
+        for account in accounts {
+            let allowed = config
+                .allowlist
+                .as_ref()
+                .map_or(false, |allowlist| allowlist.contains(&account.to_string()));
+            storage::store_allowed(host, account, allowed).unwrap();
+        }
+        storage::store_event_window(host, &config.event_window).unwrap();
+        storage::store_canvas_config(host, &config.canvas).unwrap();
+        storage::store_cooldown_config(host, &config.cooldown).unwrap();
+    }
+
+    /// Signs a PlacePixel message with the key of the given seed
+    fn signed_message(seed: u8, nonce: u64, pixel: PlacePixel) -> UserMessage {
+        let sk = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([seed; 32])).sk;
+        let inner = Inner::new(Nonce(nonce), Content::PlacePixel(pixel));
+        UserMessage::new(sk, inner)
+    }
+
+    #[test]
+    fn test_kernel_and_sequencer_transitions_agree() {
+        let seeds = [1, 2, 3, 4];
+        let accounts: Vec<PublicKeyHash> = seeds
+            .iter()
+            .map(|seed| {
+                let pixel = PlacePixel {
+                    x: 0,
+                    y: 0,
+                    color: BLANK_PIXEL,
+                };
+                let message = signed_message(*seed, 0, pixel);
+                PublicKeyHash::from(message.public_key())
+            })
+            .collect();
+
+        for seed in 1..=5 {
+            let mut rng = Rng(seed);
+            let mut host = MockHost::default();
+            genesis(&mut host).unwrap();
+            host.run_level(entry);
+            let mut place = PlaceState::new(PathBuf::from("/nonexistent/place.png"));
+
+            // The configuration set by the governance, the sequencer reads it from the rollup node
+            let palette: Vec<[u8; 3]> = (0..4).map(|_| rng.color()).collect();
+            let mut config = RollupConfig {
+                allowlist: match rng.below(2) {
+                    0 => None,
+                    _ => Some(accounts[..3].iter().map(|pkh| pkh.to_string()).collect()),
+                },
+                frozen: false,
+                event_window: EventWindow {
+                    start: Some(0),
+                    end: Some(i64::MAX),
+                },
+                level_timestamp: None,
+                canvas: CanvasConfig {
+                    width: 12,
+                    height: 12,
+                    palette: match rng.below(2) {
+                        0 => None,
+                        _ => Some(palette.clone()),
+                    },
+                },
+                cooldown: CooldownConfig {
+                    max_pixels: 1 + rng.below(3) as u32,
+                    levels: 1 + rng.below(4) as u32,
+                },
+            };
+            store_rollup_config(&mut host, &accounts, &config);
+
+            // Batches waiting for their inclusion, with the level at which they are included
+            let mut in_flight: Vec<(u32, Message)> = vec![];
+            let mut last_header: Option<BatchHeader> = None;
+            let mut batched: Vec<Vec<u8>> = vec![];
+
+            for _ in 0..100 {
+                // The sequencer follows the last level processed by the rollup node
+                place.set_l1_head(host.level() - 1);
+                config.level_timestamp = storage::read_level_timestamp(&mut host).unwrap();
+                place.set_rollup_config(config.clone());
+
+                // Only the messages accepted by the preview are batched
+                for _ in 0..rng.below(3) {
+                    let mut messages = vec![];
+                    for _ in 0..1 + rng.below(5) {
+                        let account = rng.below(seeds.len() as u64) as usize;
+                        let nonce = place.nonce(&accounts[account].to_string()).0;
+                        // Mostly the next nonce, sometimes a replay or a gap
+                        let nonce = match rng.below(10) {
+                            0 => rng.below(nonce + 3),
+                            _ => nonce + 1,
+                        };
+                        // Mostly in a small area to overwrite pixels, sometimes outside
+                        let (x, y) = match rng.below(20) {
+                            0 => (1024 + rng.below(10) as u32, rng.below(16) as u32),
+                            _ => (rng.below(16) as u32, rng.below(16) as u32),
+                        };
+                        // Mostly a color of the palette
+                        let color = match rng.below(5) {
+                            0 => rng.color(),
+                            _ => palette[rng.below(4) as usize],
+                        };
+                        let pixel = PlacePixel { x, y, color };
+                        let message = signed_message(seeds[account], nonce, pixel);
+                        if place.set_pixel(&message).is_ok() {
+                            messages.push(message.to_bytes());
+                        }
+                    }
+                    if messages.is_empty() {
+                        continue;
+                    }
+                    batched.extend(messages.iter().cloned());
+                    let merkle_root = prepare_batch(&mut host, messages);
+                    let header = match &last_header {
+                        Some(last_header) => last_header.next(merkle_root, place.level()),
+                        None => BatchHeader::first(merkle_root, place.level()),
+                    };
+                    last_header = Some(header.clone());
+                    let message = batch_message(&mut host, sequencer_secret_key(), header);
+                    // Included up to 3 levels after the level it is built for
+                    in_flight.push((host.level() + rng.below(4) as u32, message));
+                }
+
+                // The batches are included in order
+                let included = in_flight
+                    .iter()
+                    .take_while(|(level, _)| *level <= host.level())
+                    .count();
+                for (_, message) in in_flight.drain(..included) {
+                    host.add_external(message);
+                }
+                host.run_level(entry);
+            }
+            for (_, message) in in_flight.drain(..) {
+                host.add_external(message);
+            }
+            host.run_level(entry);
+
+            assert!(!batched.is_empty());
+            for message in &batched {
+                assert_eq!(read_receipt(&mut host, message).0, Some(true));
+            }
+            let mut durable = DurableStorage(&mut host);
+            for x in 0..16 {
+                for y in 0..16 {
+                    assert_eq!(
+                        durable.read_pixel(x, y).unwrap(),
+                        place.read_pixel(x, y).unwrap()
+                    );
+                }
+            }
+            for account in &accounts {
+                assert_eq!(
+                    durable.read_nonce(account).unwrap(),
+                    place.read_nonce(account).unwrap()
+                );
+                assert_eq!(
+                    durable.read_cooldown(account).unwrap(),
+                    place.read_cooldown(account).unwrap()
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,41 @@
+use lib::error::*;
+use tezos_smart_rollup::prelude::*;
+
+use crate::storage::{
+    end_canvas_migration, read_canvas_config, read_canvas_migration, read_legacy_pixel,
+    store_canvas_migration, store_pixel_color,
+};
+use crate::ticks::{TickCounter, TICKS_PER_PIXEL_MIGRATION};
+use crate::Progress;
+
+/// Move the pixels stored under /image/{x}/{y} by a previous kernel to the chunks of the canvas
+///
+/// The canvas is migrated row by row, the next row is stored when the tick budget is exhausted.
+/// No message is processed before the end of the migration,
+/// so a pixel placed in a chunk is never overwritten by its previous color.
+pub fn continue_migration<R: Runtime>(host: &mut R, ticks: &mut TickCounter) -> Result<Progress> {
+    let mut row = match read_canvas_migration(host)? {
+        Some(row) => row,
+        None => return Ok(Progress::Complete),
+    };
+    let canvas = read_canvas_config(host)?;
+    let row_cost = canvas.width as u64 * TICKS_PER_PIXEL_MIGRATION;
+    while row < canvas.height {
+        if !ticks.can_afford(row_cost) {
+            debug_msg!(host, "Canvas migration interrupted at row {}\n", row);
+            store_canvas_migration(host, row)?;
+            return Ok(Progress::Interrupted);
+        }
+        ticks.consume(row_cost);
+        for x in 0..canvas.width {
+            if let Some(color) = read_legacy_pixel(host, x, row)? {
+                store_pixel_color(host, x, row, &color)?;
+            }
+        }
+        row += 1;
+    }
+
+    debug_msg!(host, "Canvas migrated to chunks\n");
+    end_canvas_migration(host)?;
+    Ok(Progress::Complete)
+}
@@ -0,0 +1,85 @@
+use lib::error::*;
+use lib::snapshot::{hash_row, SnapshotDestination, INITIAL_SNAPSHOT_HASH, SNAPSHOT_HASH_SIZE};
+use tezos_smart_rollup::prelude::*;
+
+use crate::storage::{
+    freeze, is_frozen, read_canvas_config, read_canvas_row, read_snapshot_destination,
+    read_snapshot_hash, read_snapshot_progress, store_snapshot_destination, store_snapshot_hash,
+    store_snapshot_progress, SnapshotProgress,
+};
+use crate::ticks::{TickCounter, TICKS_PER_PIXEL_READ};
+use crate::Progress;
+
+/// Freeze the canvas: no pixel can be placed anymore and the snapshot of the canvas starts
+///
+/// Freezing an already frozen canvas does nothing.
+pub fn freeze_canvas<R: Runtime>(host: &mut R) -> Result<()> {
+    if is_frozen(host)? {
+        debug_msg!(host, "The canvas is already frozen\n");
+        return Ok(());
+    }
+    let progress = SnapshotProgress {
+        row: 0,
+        hash: INITIAL_SNAPSHOT_HASH,
+    };
+    freeze(host, &progress)
+}
+
+/// Hash the frozen canvas row by row, and send the hash to the L1 contract once it is complete
+///
+/// The canvas is too large to be read in one kernel run,
+/// the progress is stored when the tick budget is exhausted.
+pub fn continue_snapshot<R: Runtime>(host: &mut R, ticks: &mut TickCounter) -> Result<Progress> {
+    let mut progress = match read_snapshot_progress(host)? {
+        Some(progress) => progress,
+        None => return Ok(Progress::Complete),
+    };
+    let canvas = read_canvas_config(host)?;
+    let row_cost = canvas.width as u64 * TICKS_PER_PIXEL_READ;
+    while progress.row < canvas.height {
+        if !ticks.can_afford(row_cost) {
+            debug_msg!(host, "Snapshot interrupted at row {}\n", progress.row);
+            store_snapshot_progress(host, &progress)?;
+            return Ok(Progress::Interrupted);
+        }
+        ticks.consume(row_cost);
+        let row = read_canvas_row(host, progress.row, canvas.width)?;
+        progress.hash = hash_row(&progress.hash, &row);
+        progress.row += 1;
+    }
+
+    store_snapshot_hash(host, &progress.hash)?;
+    debug_msg!(host, "Snapshot of the canvas: {}\n", hex::encode(progress.hash));
+    match read_snapshot_destination(host)? {
+        Some(destination) => send_snapshot_hash(host, &destination, &progress.hash)?,
+        None => debug_msg!(host, "No snapshot destination, the hash is only stored\n"),
+    }
+    Ok(Progress::Complete)
+}
+
+/// Set the contract receiving the hash of the final canvas
+///
+/// If the snapshot is already complete, its hash is sent to the new destination right away.
+pub fn set_snapshot_destination<R: Runtime>(
+    host: &mut R,
+    destination: &SnapshotDestination,
+) -> Result<()> {
+    store_snapshot_destination(host, destination)?;
+    if let Some(hash) = read_snapshot_hash(host)? {
+        let hash: [u8; SNAPSHOT_HASH_SIZE] =
+            hash.try_into().map_err(|_| Error::StateDeserializarion)?;
+        send_snapshot_hash(host, destination, &hash)?;
+    }
+    Ok(())
+}
+
+/// Send the hash of the canvas to the L1 contract, through the outbox
+fn send_snapshot_hash<R: Runtime>(
+    host: &mut R,
+    destination: &SnapshotDestination,
+    hash: &[u8; SNAPSHOT_HASH_SIZE],
+) -> Result<()> {
+    let message = destination.outbox_message(hash)?;
+    host.write_output(&message)?;
+    Ok(())
+}
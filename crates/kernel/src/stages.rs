@@ -1,150 +1,201 @@
-use crate::{storage::store_pixel, upgrade};
+use crate::storage::{
+    read_last_batch, read_last_batch_level, read_sequencers, store_allowed,
+    store_allowlist_enabled, store_cooldown_config, store_event_window, store_sequencers,
+};
+use crate::{snapshot, upgrade};
 
 use lib::constants::{L1_GOVERNANCE_CONTRACT_ADDRESS, MAGIC_BYTE};
 
 use lib::{
-    account::Account,
-    message::{Content, Inner, Message, PlacePixel},
-    nonce::Nonce,
+    governance::GovernanceMessage,
+    message::{BatchHeader, Inner, Message, BATCH_VERSION},
+    signature::Signature,
 };
 use tezos_crypto_rs::hash::ContractKt1Hash;
 
 use tezos_smart_rollup::{
     core_unsafe::PREIMAGE_HASH_SIZE,
-    inbox::{InboxMessage, InternalInboxMessage},
-    michelson::{ticket, Michelson, MichelsonBytes},
+    inbox::{InboxMessage, InfoPerLevel, InternalInboxMessage, Transfer},
+    michelson::{ticket, MichelsonBytes},
     prelude::*,
 };
 
 use lib::error::*;
 use lib::message::UserMessage;
 
-fn vec_to_array_ref<'a, const N: usize>(vec: &'a Vec<u8>) -> Option<&'a [u8; N]> {
-    if vec.len() == N {
-        Some((&vec[..]).try_into().ok()?)
-    } else {
-        None
+/// An inbox message, as seen by the kernel
+pub enum Input {
+    StartOfLevel,
+    InfoPerLevel(InfoPerLevel),
+    EndOfLevel,
+    Transfer(Transfer<ticket::BytesTicket>),
+    /// A batch of user messages signed by a sequencer
+    Batch(Message),
+    /// An external message not intended for this rollup
+    Foreign,
+    /// A message which cannot be decoded
+    Invalid(String),
+}
+
+/// Read the next inbox message and its level
+pub fn read_input(host: &mut impl Runtime) -> std::result::Result<(u32, Input), ReadInputError> {
+    let message = host
+        .read_input()
+        .map_err(ReadInputError::Runtime)?
+        .ok_or(ReadInputError::EndOfInbox)?;
+    debug_msg!(host, "read input: {:?}\n", message);
+    let input = match InboxMessage::<ticket::BytesTicket>::parse(message.as_ref()) {
+        Ok((_, InboxMessage::Internal(msg))) => match msg {
+            InternalInboxMessage::StartOfLevel => Input::StartOfLevel,
+            InternalInboxMessage::InfoPerLevel(info) => Input::InfoPerLevel(info),
+            InternalInboxMessage::EndOfLevel => Input::EndOfLevel,
+            InternalInboxMessage::Transfer(transfer) => Input::Transfer(transfer),
+        },
+        Ok((_, InboxMessage::External([MAGIC_BYTE, bytes @ ..]))) => match Message::parse(bytes) {
+            Ok(batch) => Input::Batch(batch),
+            Err(err) => Input::Invalid(err.to_string()),
+        },
+        Ok((_, InboxMessage::External(_))) => Input::Foreign,
+        Err(err) => Input::Invalid(format!("Unknown error: {:?}", err)),
+    };
+    Ok((message.level, input))
+}
+
+/// Apply a transfer: only the tickets of the governance contract are handled
+pub fn handle_transfer<R: Runtime>(
+    host: &mut R,
+    transfer: Transfer<ticket::BytesTicket>,
+) -> Result<()> {
+    let governance_contract =
+        ContractKt1Hash::from_base58_check(L1_GOVERNANCE_CONTRACT_ADDRESS).unwrap();
+    if transfer.sender != governance_contract {
+        debug_msg!(host, "Transfer not sent by the governance contract\n");
+        return Ok(());
     }
+    let MichelsonBytes(data) = transfer.payload.contents();
+    handle_governance(host, data)
 }
 
-pub fn read_input<Expr: Michelson>(
-    host: &mut impl Runtime,
-) -> std::result::Result<(Option<Message>, u32), ReadInputError> {
-    let input = host.read_input().map_err(ReadInputError::Runtime)?;
-    match input {
-        None => Err(ReadInputError::EndOfInbox),
-        Some(message) => {
-            debug_msg!(host, "read input: {:?}\n", message);
-            match InboxMessage::<ticket::BytesTicket>::parse(message.as_ref()) {
-                Ok(parsed_msg) => match parsed_msg {
-                    (remaining, InboxMessage::Internal(msg)) => {
-                        assert!(remaining.is_empty());
-                        match msg {
-                            InternalInboxMessage::StartOfLevel => {
-                                host.write_debug(format!("Start of Level\n").as_str());
-                                Ok((None, message.level))
-                            }
-                            InternalInboxMessage::InfoPerLevel(info) => {
-                                host.write_debug(format!("Info: {:?}\n", info).as_str());
-                                Ok((None, message.level))
-                            }
-                            InternalInboxMessage::EndOfLevel => {
-                                host.write_debug(format!("End of Level\n").as_str());
-                                Ok((None, message.level))
-                            }
-                            InternalInboxMessage::Transfer(transfer) => {
-                                host.write_debug(format!("Transfer: {:?}\n", transfer).as_str());
-                                let upgrade_contract: ContractKt1Hash =
-                                    tezos_crypto_rs::hash::ContractKt1Hash::from_base58_check(
-                                        L1_GOVERNANCE_CONTRACT_ADDRESS,
-                                    )
-                                    .unwrap();
-                                if transfer.sender == upgrade_contract {
-                                    let ticket: ticket::BytesTicket = transfer.payload;
-                                    let MichelsonBytes(data) = ticket.contents();
-                                    let data: &Vec<u8> = data;
-                                    let root_hash: &[u8; PREIMAGE_HASH_SIZE] =
-                                        vec_to_array_ref(data).unwrap();
-                                    let root_hash_hex = hex::encode(root_hash);
-                                    host.write_debug(
-                                        format!("Received upgrade hash: {}\n", root_hash_hex)
-                                            .as_str(),
-                                    );
-                                    upgrade::install_kernel(host, root_hash).unwrap();
-                                    host.write_debug(format!("Upgrade complete\n").as_str());
-                                    host.mark_for_reboot().unwrap();
-                                    Ok((None, message.level))  
-                                } else {
-                                    Ok((None, message.level)) 
-                                }
-                            }
-                        }
-                    }
-                    (remaining, InboxMessage::External(data)) => {
-                        assert!(remaining.is_empty());
-                        match data {
-                            [MAGIC_BYTE, ..] => {
-                                let bytes = data.iter().skip(1).copied().collect();
-                                let str = String::from_utf8(bytes)
-                                    .map_err(ReadInputError::FromUtf8Error)?;
-                                let msg: Message = serde_json_wasm::from_str(&str)
-                                    .map_err(ReadInputError::SerdeJson)?;
-                                Ok((Some(msg), message.level))
-                            }
-                            _ => {
-                                debug_msg!(host, "External message not intended for this rollup\n");
-                                Err(ReadInputError::NotATzwitterMessage)
-                            }
-                        }
-                    }
-                },
-                Err(err) => Err(ReadInputError::GenericError(format!(
-                    "Unknown error: {:?}",
-                    err
-                ))),
+/// Apply a message sent by the governance contract
+fn handle_governance<R: Runtime>(host: &mut R, data: &[u8]) -> Result<()> {
+    match GovernanceMessage::parse(data)? {
+        GovernanceMessage::UpgradeKernel(root_hash) => {
+            let root_hash_hex = hex::encode(root_hash);
+            host.write_debug(format!("Received upgrade hash: {}\n", root_hash_hex).as_str());
+            upgrade::install_kernel(host, &root_hash).map_err(|err| Error::GenericError(err.to_string()))?;
+            host.write_debug(format!("Upgrade complete\n").as_str());
+            host.mark_for_reboot()?;
+        }
+        GovernanceMessage::AllowlistAdd(public_key_hash) => {
+            debug_msg!(host, "Allowing {}\n", public_key_hash.to_string());
+            store_allowed(host, &public_key_hash, true)?;
+        }
+        GovernanceMessage::AllowlistRemove(public_key_hash) => {
+            debug_msg!(host, "Disallowing {}\n", public_key_hash.to_string());
+            store_allowed(host, &public_key_hash, false)?;
+        }
+        GovernanceMessage::EnableAllowlist(enabled) => {
+            debug_msg!(host, "Allowlist enabled: {}\n", enabled);
+            store_allowlist_enabled(host, enabled)?;
+        }
+        GovernanceMessage::AddSequencer(public_key) => {
+            debug_msg!(host, "Adding sequencer {}\n", public_key.to_b58());
+            let mut sequencers = read_sequencers(host)?;
+            if !sequencers.contains(&public_key) {
+                sequencers.push(public_key);
             }
+            store_sequencers(host, &sequencers)?;
+        }
+        GovernanceMessage::RemoveSequencer(public_key) => {
+            debug_msg!(host, "Removing sequencer {}\n", public_key.to_b58());
+            let mut sequencers = read_sequencers(host)?;
+            sequencers.retain(|sequencer| sequencer != &public_key);
+            store_sequencers(host, &sequencers)?;
+        }
+        GovernanceMessage::SetEventWindow(window) => {
+            debug_msg!(host, "Event window: {:?}\n", window);
+            store_event_window(host, &window)?;
+        }
+        GovernanceMessage::Freeze => {
+            debug_msg!(host, "Freezing the canvas\n");
+            snapshot::freeze_canvas(host)?;
+        }
+        GovernanceMessage::SetSnapshotDestination(destination) => {
+            debug_msg!(host, "Snapshot destination: {}\n", destination.to_b58());
+            snapshot::set_snapshot_destination(host, &destination)?;
+        }
+        GovernanceMessage::SetCooldown(config) => {
+            debug_msg!(host, "Cooldown: {:?}\n", config);
+            store_cooldown_config(host, &config)?;
         }
     }
+    Ok(())
 }
 
-/// Verify the signature of a message
-///
-/// Returns the inner message
-pub fn verify_signature(message: UserMessage) -> Result<Inner> {
-    let signature = message.signature();
-    let pkey = message.public_key();
-    let inner = message.inner();
-    let hash = inner.hash();
-
-    signature.verify(pkey, hash.as_ref())?;
-    let UserMessage { inner, .. } = message;
-    Ok(inner)
+/// Verify that a batch header has been signed by one of the sequencers
+pub fn verify_sequencer_signature<R: Runtime>(
+    host: &mut R,
+    signature: &Signature,
+    header: &BatchHeader,
+) -> Result<()> {
+    let sequencers = read_sequencers(host)?;
+    let rollup_address = host.reveal_metadata()?.raw_rollup_address;
+    let data = Message::signed_bytes(&rollup_address, header);
+    let is_signed = sequencers
+        .iter()
+        .any(|sequencer| signature.verify(sequencer, &data).is_ok());
+    match is_signed {
+        true => Ok(()),
+        false => Err(Error::InvalidSignature),
+    }
 }
 
-/// Verify the nonce of the inner message
+/// Verify that the batch follows the last accepted batch
 ///
-/// If the nonce is correct the content of the inner is returned
-pub fn verify_nonce(inner: Inner, _nonce: &Nonce) -> Result<Content> {
-    Ok(inner.content)
-    // FIXME: implement this later
-    // let next_nonce = nonce.next();
-    // let inner_nonce = inner.nonce();
-    // if &next_nonce == inner_nonce {
-    //     let Inner { content, .. } = inner;
-    //     Ok(content)
-    // } else {
-    //     Err(Error::InvalidNonce)
-    // }
-}
-
-/// Create a new tweet from the PostTweet request
-/// Save the tweet to the durable state
-/// And add a tweet entry to the user account
-pub fn place_pixel<R: Runtime>(
+/// Its index has to be the next one and it has to refer to the root of the last batch.
+/// Its level cannot be lower than the one of the last batch,
+/// nor higher than the level at which it is included.
+pub fn verify_batch_header<R: Runtime>(
     host: &mut R,
-    _account: &Account,
-    place_pixel: PlacePixel,
+    header: &BatchHeader,
+    level: u32,
 ) -> Result<()> {
-    store_pixel(host, &place_pixel)?;
-    Ok(())
+    if header.version != BATCH_VERSION {
+        return Err(Error::InvalidBatchHeader);
+    }
+    let last_level = read_last_batch_level(host)?;
+    if header.level < last_level || header.level > level {
+        debug_msg!(
+            host,
+            "Batch built for level {}, after a batch for level {}, included at level {}\n",
+            header.level,
+            last_level,
+            level
+        );
+        return Err(Error::InvalidBatchHeader);
+    }
+    let (expected_index, expected_previous_root) = match read_last_batch(host)? {
+        Some((index, root)) => (index + 1, root),
+        None => (0, [0; PREIMAGE_HASH_SIZE]),
+    };
+    if header.index == expected_index && header.previous_root == expected_previous_root {
+        Ok(())
+    } else {
+        debug_msg!(
+            host,
+            "Expected batch {}, received batch {}\n",
+            expected_index,
+            header.index
+        );
+        Err(Error::InvalidBatchHeader)
+    }
+}
+
+/// Verify the signature of a message, raw or signed by a wallet depending on its version
+///
+/// Returns the inner message
+pub fn verify_signature(message: UserMessage) -> Result<Inner> {
+    message.verify_signature()?;
+    let UserMessage { inner, .. } = message;
+    Ok(inner)
 }
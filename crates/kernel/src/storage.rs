@@ -1,21 +1,64 @@
+use tezos_smart_rollup::core_unsafe::{MAX_FILE_CHUNK_SIZE, PREIMAGE_HASH_SIZE};
+use tezos_smart_rollup::inbox::InfoPerLevel;
 use tezos_smart_rollup::{prelude::*, storage::path::*};
 
+use lib::canvas::{blank_chunk, chunk_location, chunks_per_row, CanvasConfig, BLANK_PIXEL, CHUNK_SIZE, CHUNK_WIDTH};
+use lib::cooldown::{Cooldown, CooldownConfig, COOLDOWN_SIZE};
+use lib::event::EventWindow;
 use lib::public_key_hash::PublicKeyHash;
+use lib::hash::Blake2b;
 use lib::receipt::Receipt;
-use lib::message::PlacePixel;
+use lib::constants::{PIXEL_HISTORY_SIZE, SEQUENCER_PK};
+use lib::message::{BatchHeader, PlacePixel};
+use lib::pixel::{PixelRecord, PIXEL_RECORD_SIZE};
+use lib::snapshot::{SnapshotDestination, SNAPSHOT_HASH_SIZE};
+use lib::public_key::{PublicKey, ED25519_PUBLIC_KEY_SIZE};
+use lib::transition::Storage;
 use lib::{account::Account, error::*, nonce::Nonce};
 
 const ACCOUNTS: RefPath = RefPath::assert_from(b"/accounts");
+const ALLOWLIST: RefPath = RefPath::assert_from(b"/allowlist");
+const ALLOWLIST_ENABLED: RefPath = RefPath::assert_from(b"/config/allowlist/enabled");
 const RECEIPTS: RefPath = RefPath::assert_from(b"/receipts");
+const CANVAS_WIDTH: RefPath = RefPath::assert_from(b"/config/canvas/width");
+const CANVAS_HEIGHT: RefPath = RefPath::assert_from(b"/config/canvas/height");
+const CANVAS_PALETTE: RefPath = RefPath::assert_from(b"/config/canvas/palette");
+const SEQUENCERS: RefPath = RefPath::assert_from(b"/config/sequencers");
+const LAST_BATCH_INDEX: RefPath = RefPath::assert_from(b"/sequencer/last_batch/index");
+const LAST_BATCH_ROOT: RefPath = RefPath::assert_from(b"/sequencer/last_batch/root");
+const LAST_BATCH_LEVEL: RefPath = RefPath::assert_from(b"/sequencer/last_batch/level");
+const BATCH_CURSOR: RefPath = RefPath::assert_from(b"/sequencer/cursor");
+const COOLDOWN_MAX_PIXELS: RefPath = RefPath::assert_from(b"/config/cooldown/max_pixels");
+const COOLDOWN_LEVELS: RefPath = RefPath::assert_from(b"/config/cooldown/levels");
+const EVENT_START: RefPath = RefPath::assert_from(b"/config/event/start");
+const EVENT_END: RefPath = RefPath::assert_from(b"/config/event/end");
+const LEVEL_TIMESTAMP: RefPath = RefPath::assert_from(b"/level/timestamp");
+const SNAPSHOT_DESTINATION: RefPath = RefPath::assert_from(b"/config/snapshot/destination");
+const FROZEN: RefPath = RefPath::assert_from(b"/snapshot/frozen");
+const SNAPSHOT_ROW: RefPath = RefPath::assert_from(b"/snapshot/row");
+const SNAPSHOT_PARTIAL_HASH: RefPath = RefPath::assert_from(b"/snapshot/partial_hash");
+const SNAPSHOT_HASH: RefPath = RefPath::assert_from(b"/snapshot/hash");
+const LEGACY_IMAGE: RefPath = RefPath::assert_from(b"/image");
+const CANVAS_MIGRATION_ROW: RefPath = RefPath::assert_from(b"/migration/canvas/row");
 
-/// Compute the paths for the different fields of a tweet
-///
-/// The field_path should start with slash
-fn pixel_path(x: u32, y: u32) -> Result<OwnedPath> {
+/// Compute the path /canvas/{y}/{chunk} of a chunk of a row of the canvas
+fn chunk_path(y: u32, chunk: u32) -> Result<OwnedPath> {
+    let path: Vec<u8> = format!("/canvas/{}/{}", y, chunk).into();
+    OwnedPath::try_from(path).map_err(Error::from)
+}
+
+/// Compute the path /image/{x}/{y} of a pixel in the layout used before the chunks
+fn legacy_pixel_path(x: u32, y: u32) -> Result<OwnedPath> {
     let path: Vec<u8> = format!("/image/{}/{}", x, y).into();
     OwnedPath::try_from(path).map_err(Error::from)
 }
 
+/// Compute the path /pixels/{y}/{chunk} of the histories of the pixels of a chunk
+fn attribution_path(y: u32, chunk: u32) -> Result<OwnedPath> {
+    let path: Vec<u8> = format!("/pixels/{}/{}", y, chunk).into();
+    OwnedPath::try_from(path).map_err(Error::from)
+}
+
 /// Compute the paths for the different fields of an account
 ///
 /// The field_path should start with slash
@@ -29,15 +72,26 @@ fn account_field_path(public_key_hash: &PublicKeyHash, field_path: &str) -> Resu
     concat(&public_key_hash, &field_path).map_err(Error::from)
 }
 
+/// Compute the path /allowlist/{tz1...}
+fn allowlist_path(public_key_hash: &PublicKeyHash) -> Result<OwnedPath> {
+    let public_key_hash: Vec<u8> = format!("/{}", public_key_hash.to_string()).into();
+    let public_key_hash = OwnedPath::try_from(public_key_hash).map_err(Error::from)?;
+    concat(&ALLOWLIST, &public_key_hash).map_err(Error::from)
+}
+
 /// Compute the path /accounts/{tz1...}/nonce
 fn nonce_path(public_key_hash: &PublicKeyHash) -> Result<OwnedPath> {
     account_field_path(public_key_hash, "/nonce")
 }
 
+/// Compute the path /accounts/{tz1...}/cooldown
+fn cooldown_path(public_key_hash: &PublicKeyHash) -> Result<OwnedPath> {
+    account_field_path(public_key_hash, "/cooldown")
+}
 
 /// Compute the path of the different field of a receipt
-fn receipt_field_path(receipt: &Receipt, field_path: &str) -> Result<OwnedPath> {
-    let receipt_path = format!("/{}", receipt.hash().to_string());
+fn receipt_field_path(hash: &Blake2b, field_path: &str) -> Result<OwnedPath> {
+    let receipt_path = format!("/{}", hash.to_string());
     let receipt_path = OwnedPath::try_from(receipt_path).map_err(Error::from)?;
     let receipt_path = concat(&RECEIPTS, &receipt_path)?;
 
@@ -47,8 +101,28 @@ fn receipt_field_path(receipt: &Receipt, field_path: &str) -> Result<OwnedPath>
 }
 
 /// Compute the path of the success field of a receipt
-fn receipt_success_path(receipt: &Receipt) -> Result<OwnedPath> {
-    receipt_field_path(receipt, "/success")
+fn receipt_success_path(hash: &Blake2b) -> Result<OwnedPath> {
+    receipt_field_path(hash, "/success")
+}
+
+/// Compute the path of the level field of a receipt
+fn receipt_level_path(hash: &Blake2b) -> Result<OwnedPath> {
+    receipt_field_path(hash, "/level")
+}
+
+/// Compute the path of the batch field of a receipt
+fn receipt_batch_path(hash: &Blake2b) -> Result<OwnedPath> {
+    receipt_field_path(hash, "/batch")
+}
+
+/// Compute the path of the index field of a receipt
+fn receipt_index_path(hash: &Blake2b) -> Result<OwnedPath> {
+    receipt_field_path(hash, "/index")
+}
+
+/// Compute the path of the error field of a receipt
+fn receipt_error_path(hash: &Blake2b) -> Result<OwnedPath> {
+    receipt_field_path(hash, "/error")
 }
 
 ///  Check if a path exists
@@ -84,6 +158,49 @@ fn store_u64<'a, R: Runtime>(host: &mut R, path: &impl Path, u64: &'a u64) -> Re
         .map(|_| u64)
 }
 
+/// Read the whole value at a given path
+pub fn read_bytes<R: Runtime>(host: &mut R, path: &impl Path) -> Result<Option<Vec<u8>>> {
+    let is_exists = exists(host, path)?;
+    if !is_exists {
+        return Ok(None);
+    }
+
+    let size = host.store_value_size(path)?;
+    let mut bytes = Vec::with_capacity(size);
+    while bytes.len() < size {
+        let chunk_size = usize::min(MAX_FILE_CHUNK_SIZE, size - bytes.len());
+        let chunk = host.store_read(path, bytes.len(), chunk_size)?;
+        if chunk.is_empty() {
+            return Err(Error::StateDeserializarion);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+/// Read an u32 from a given path
+pub fn read_u32<R: Runtime>(host: &mut R, path: &impl Path) -> Result<Option<u32>> {
+    let is_exists = exists(host, path)?;
+    if !is_exists {
+        return Ok(None);
+    }
+
+    let mut buffer = [0_u8; 4];
+    match host.store_read_slice(path, 0, &mut buffer) {
+        Ok(4) => Ok(Some(u32::from_be_bytes(buffer))),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Store an u32 at a given path
+fn store_u32<R: Runtime>(host: &mut R, path: &impl Path, u32: u32) -> Result<()> {
+    let data = u32.to_be_bytes();
+
+    host.store_write(path, &data, 0)
+        .map_err(Error::from)
+        .map(|_| ())
+}
+
 /// Stores a boolean at a given path
 fn store_bool<R: Runtime>(host: &mut R, path: &impl Path, bool: bool) -> Result<()> {
     let data = match bool {
@@ -96,6 +213,20 @@ fn store_bool<R: Runtime>(host: &mut R, path: &impl Path, bool: bool) -> Result<
         .map(|_| ())
 }
 
+/// Read a boolean from a given path
+pub fn read_bool<R: Runtime>(host: &mut R, path: &impl Path) -> Result<Option<bool>> {
+    let is_exists = exists(host, path)?;
+    if !is_exists {
+        return Ok(None);
+    }
+
+    let mut buffer = [0_u8; 1];
+    match host.store_read_slice(path, 0, &mut buffer) {
+        Ok(1) => Ok(Some(buffer[0] == 0x01)),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
 /// Read the account of the user
 pub fn read_account<R: Runtime>(host: &mut R, public_key_hash: PublicKeyHash) -> Result<Account> {
     let nonce_path = nonce_path(&public_key_hash)?;
@@ -119,9 +250,543 @@ pub fn store_account<'a, R: Runtime>(host: &mut R, account: &'a Account) -> Resu
     Ok(account)
 }
 
-/// Store a tweet to the location /tweets/{tz...}
+/// Read the cooldown of an account
+pub fn read_cooldown<R: Runtime>(host: &mut R, public_key_hash: &PublicKeyHash) -> Result<Cooldown> {
+    let path = cooldown_path(public_key_hash)?;
+    if !exists(host, &path)? {
+        return Ok(Cooldown::default());
+    }
+    let mut bytes = [0; COOLDOWN_SIZE];
+    match host.store_read_slice(&path, 0, &mut bytes) {
+        Ok(COOLDOWN_SIZE) => Cooldown::from_bytes(&bytes),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Store the cooldown of an account next to its nonce, in a single value
+pub fn store_cooldown<R: Runtime>(
+    host: &mut R,
+    public_key_hash: &PublicKeyHash,
+    cooldown: &Cooldown,
+) -> Result<()> {
+    let path = cooldown_path(public_key_hash)?;
+    host.store_write(&path, &cooldown.to_bytes(), 0).map_err(Error::from)
+}
+
+/// Read the cooldown configuration under /config/cooldown
+///
+/// The default configuration is used when it is not set
+pub fn read_cooldown_config<R: Runtime>(host: &mut R) -> Result<CooldownConfig> {
+    let default = CooldownConfig::default();
+    let max_pixels = read_u32(host, &COOLDOWN_MAX_PIXELS)?.unwrap_or(default.max_pixels);
+    let levels = read_u32(host, &COOLDOWN_LEVELS)?.unwrap_or(default.levels);
+    Ok(CooldownConfig { max_pixels, levels })
+}
+
+/// Store the cooldown configuration under /config/cooldown
+pub fn store_cooldown_config<R: Runtime>(host: &mut R, config: &CooldownConfig) -> Result<()> {
+    store_u32(host, &COOLDOWN_MAX_PIXELS, config.max_pixels)?;
+    store_u32(host, &COOLDOWN_LEVELS, config.levels)
+}
+
+/// Read an i64 from a given path
+pub fn read_i64<R: Runtime>(host: &mut R, path: &impl Path) -> Result<Option<i64>> {
+    Ok(read_u64(host, path)?.map(|value| value as i64))
+}
+
+/// Store an i64 at a given path, or delete the path if there is no value
+fn store_optional_i64<R: Runtime>(host: &mut R, path: &impl Path, value: Option<i64>) -> Result<()> {
+    match value {
+        Some(value) => store_u64(host, path, &(value as u64)).map(|_| ()),
+        None if exists(host, path)? => host.store_delete(path).map_err(Error::from),
+        None => Ok(()),
+    }
+}
+
+/// Read the period of the event under /config/event, set by the installer or by the governance
+///
+/// The setup of the installer is written by `upgrade-client get-setup-config`.
+pub fn read_event_window<R: Runtime>(host: &mut R) -> Result<EventWindow> {
+    Ok(EventWindow {
+        start: read_i64(host, &EVENT_START)?,
+        end: read_i64(host, &EVENT_END)?,
+    })
+}
+
+/// Store the period of the event under /config/event, a missing bound is deleted
+pub fn store_event_window<R: Runtime>(host: &mut R, window: &EventWindow) -> Result<()> {
+    store_optional_i64(host, &EVENT_START, window.start)?;
+    store_optional_i64(host, &EVENT_END, window.end)
+}
+
+/// Store the timestamp of the current level under /level, as used by the time-based rules
+pub fn store_level_info<R: Runtime>(host: &mut R, info: &InfoPerLevel) -> Result<()> {
+    let timestamp = info.predecessor_timestamp.i64() as u64;
+    store_u64(host, &LEVEL_TIMESTAMP, &timestamp).map(|_| ())
+}
+
+/// Read the L1 timestamp of the current level, which is the timestamp of its predecessor block
+pub fn read_level_timestamp<R: Runtime>(host: &mut R) -> Result<Option<i64>> {
+    read_i64(host, &LEVEL_TIMESTAMP)
+}
+
+/// Read the canvas configuration under /config/canvas
+pub fn read_canvas_config<R: Runtime>(host: &mut R) -> Result<CanvasConfig> {
+    let default = CanvasConfig::default();
+    let width = read_u32(host, &CANVAS_WIDTH)?.unwrap_or(default.width);
+    let height = read_u32(host, &CANVAS_HEIGHT)?.unwrap_or(default.height);
+    let palette = match read_bytes(host, &CANVAS_PALETTE)? {
+        Some(bytes) => Some(CanvasConfig::palette_from_bytes(&bytes)?),
+        None => None,
+    };
+    Ok(CanvasConfig {
+        width,
+        height,
+        palette,
+    })
+}
+
+/// Store the canvas configuration under /config/canvas
+pub fn store_canvas_config<R: Runtime>(host: &mut R, config: &CanvasConfig) -> Result<()> {
+    store_u32(host, &CANVAS_WIDTH, config.width)?;
+    store_u32(host, &CANVAS_HEIGHT, config.height)?;
+    if exists(host, &CANVAS_PALETTE)? {
+        host.store_delete(&CANVAS_PALETTE)?;
+    }
+    if let Some(palette) = &config.palette {
+        let palette = CanvasConfig::palette_to_bytes(palette);
+        host.store_write(&CANVAS_PALETTE, &palette, 0)?;
+    }
+    Ok(())
+}
+
+/// Store the default canvas dimensions, unless they were already set
+///
+/// The palette is left untouched, it can be revealed by the installer
+pub fn init_canvas_config<R: Runtime>(host: &mut R) -> Result<()> {
+    let default = CanvasConfig::default();
+    if !exists(host, &CANVAS_WIDTH)? {
+        store_u32(host, &CANVAS_WIDTH, default.width)?;
+    }
+    if !exists(host, &CANVAS_HEIGHT)? {
+        store_u32(host, &CANVAS_HEIGHT, default.height)?;
+    }
+    Ok(())
+}
+
+/// Add or remove an account from the allowlist
+pub fn store_allowed<R: Runtime>(
+    host: &mut R,
+    public_key_hash: &PublicKeyHash,
+    allowed: bool,
+) -> Result<()> {
+    let path = allowlist_path(public_key_hash)?;
+    match allowed {
+        true => store_bool(host, &path, true),
+        false if exists(host, &path)? => host.store_delete(&path).map_err(Error::from),
+        false => Ok(()),
+    }
+}
+
+/// Returns true if the account can place pixels
+///
+/// Every account is allowed while the allowlist is not enabled
+pub fn is_allowed<R: Runtime>(host: &mut R, public_key_hash: &PublicKeyHash) -> Result<bool> {
+    let enabled = read_bool(host, &ALLOWLIST_ENABLED)?.unwrap_or_default();
+    if !enabled {
+        return Ok(true);
+    }
+    exists(host, &allowlist_path(public_key_hash)?)
+}
+
+/// Turn the allowlist on or off
+pub fn store_allowlist_enabled<R: Runtime>(host: &mut R, enabled: bool) -> Result<()> {
+    store_bool(host, &ALLOWLIST_ENABLED, enabled)
+}
+
+/// Read the keys of the sequencers whose batches are accepted
+///
+/// They are stored under /config/sequencers as a concatenation of raw ed25519 public keys,
+/// so the installer config can reveal them there at origination
+pub fn read_sequencers<R: Runtime>(host: &mut R) -> Result<Vec<PublicKey>> {
+    let bytes = match read_bytes(host, &SEQUENCERS)? {
+        Some(bytes) => bytes,
+        None => return Ok(vec![PublicKey::from_b58(SEQUENCER_PK).unwrap()]),
+    };
+    if bytes.len() % ED25519_PUBLIC_KEY_SIZE != 0 {
+        return Err(Error::StateDeserializarion);
+    }
+    bytes
+        .chunks_exact(ED25519_PUBLIC_KEY_SIZE)
+        .map(|bytes| PublicKey::from_ed25519_bytes(bytes).map_err(|_| Error::StateDeserializarion))
+        .collect()
+}
+
+/// Store the keys of the sequencers whose batches are accepted
+///
+/// Batches are signed with ed25519 keys only
+pub fn store_sequencers<R: Runtime>(host: &mut R, sequencers: &[PublicKey]) -> Result<()> {
+    if sequencers.is_empty() {
+        return Err(Error::NoSequencer);
+    }
+    if !sequencers.iter().all(|key| matches!(key, PublicKey::Ed25519(_))) {
+        return Err(Error::InvalidSequencerKey);
+    }
+    let bytes: Vec<u8> = sequencers.iter().flat_map(PublicKey::to_bytes).collect();
+    if exists(host, &SEQUENCERS)? {
+        host.store_delete(&SEQUENCERS)?;
+    }
+    host.store_write(&SEQUENCERS, &bytes, 0)
+        .map_err(Error::from)
+}
+
+/// Store the default sequencer key, unless the installer config revealed the sequencer keys
+///
+/// The setup of the installer is written by `upgrade-client get-setup-config`.
+pub fn init_sequencers<R: Runtime>(host: &mut R) -> Result<()> {
+    if exists(host, &SEQUENCERS)? {
+        return Ok(());
+    }
+    let sequencer = PublicKey::from_b58(SEQUENCER_PK).unwrap();
+    store_sequencers(host, &[sequencer])
+}
+
+/// Read the index and the merkle root of the last accepted batch
+///
+/// The root is a full preimage hash, including the tag of the hashing scheme
+pub fn read_last_batch<R: Runtime>(host: &mut R) -> Result<Option<(u64, [u8; PREIMAGE_HASH_SIZE])>> {
+    let index = match read_u64(host, &LAST_BATCH_INDEX)? {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let mut root = [0_u8; PREIMAGE_HASH_SIZE];
+    match host.store_read_slice(&LAST_BATCH_ROOT, 0, &mut root) {
+        Ok(PREIMAGE_HASH_SIZE) => Ok(Some((index, root))),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Read the level of the last accepted batch, 0 if there is none
+pub fn read_last_batch_level<R: Runtime>(host: &mut R) -> Result<u32> {
+    Ok(read_u32(host, &LAST_BATCH_LEVEL)?.unwrap_or_default())
+}
+
+/// Store the last accepted batch under /sequencer/last_batch
+pub fn store_last_batch<R: Runtime>(host: &mut R, header: &BatchHeader) -> Result<()> {
+    store_u64(host, &LAST_BATCH_INDEX, &header.index)?;
+    store_u32(host, &LAST_BATCH_LEVEL, header.level)?;
+    host.store_write(&LAST_BATCH_ROOT, &header.merkle_root, 0)
+        .map_err(Error::from)
+}
+
+/// Position of the next message of a batch interrupted by a reboot
+#[derive(Debug, PartialEq, Clone)]
+pub struct BatchCursor {
+    /// Root hash of the batch
+    pub root: [u8; PREIMAGE_HASH_SIZE],
+    /// Index of the batch, as in its header
+    pub batch: u64,
+    /// Level of the inbox message of the batch
+    pub level: u32,
+    /// Index of the next message in the batch
+    pub index: u32,
+    /// Index of the content page where the next message starts
+    pub page: u32,
+    /// Offset of the next message in its page
+    pub offset: u32,
+}
+
+impl BatchCursor {
+    /// Cursor at the first message of a batch
+    pub fn start(root: [u8; PREIMAGE_HASH_SIZE], batch: u64, level: u32) -> Self {
+        BatchCursor {
+            root,
+            batch,
+            level,
+            index: 0,
+            page: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Size of the encoding of a cursor: the root, the index of the batch and 4 u32
+const BATCH_CURSOR_SIZE: usize = PREIMAGE_HASH_SIZE + 8 + 4 * 4;
+
+/// Read the cursor of the interrupted batch, if any
+pub fn read_batch_cursor<R: Runtime>(host: &mut R) -> Result<Option<BatchCursor>> {
+    let bytes = match read_bytes(host, &BATCH_CURSOR)? {
+        Some(bytes) if bytes.len() == BATCH_CURSOR_SIZE => bytes,
+        Some(_) => return Err(Error::StateDeserializarion),
+        None => return Ok(None),
+    };
+    let (root, fields) = bytes.split_at(PREIMAGE_HASH_SIZE);
+    let (batch, fields) = fields.split_at(8);
+    let field = |i: usize| u32::from_be_bytes(fields[4 * i..4 * (i + 1)].try_into().unwrap());
+    Ok(Some(BatchCursor {
+        root: root.try_into().unwrap(),
+        batch: u64::from_be_bytes(batch.try_into().unwrap()),
+        level: field(0),
+        index: field(1),
+        page: field(2),
+        offset: field(3),
+    }))
+}
+
+/// Store the cursor of a batch interrupted by a reboot under /sequencer/cursor
+pub fn store_batch_cursor<R: Runtime>(host: &mut R, cursor: &BatchCursor) -> Result<()> {
+    let mut bytes = Vec::with_capacity(BATCH_CURSOR_SIZE);
+    bytes.extend_from_slice(&cursor.root);
+    bytes.extend_from_slice(&cursor.batch.to_be_bytes());
+    for field in [cursor.level, cursor.index, cursor.page, cursor.offset] {
+        bytes.extend_from_slice(&field.to_be_bytes());
+    }
+    host.store_write(&BATCH_CURSOR, &bytes, 0)
+        .map_err(Error::from)
+}
+
+/// Delete the cursor once the batch is completely processed
+pub fn delete_batch_cursor<R: Runtime>(host: &mut R) -> Result<()> {
+    if exists(host, &BATCH_CURSOR)? {
+        host.store_delete(&BATCH_CURSOR)?;
+    }
+    Ok(())
+}
+
+/// Statistics of the inbox of a level
+#[derive(Debug, PartialEq, Clone)]
+pub struct LevelStats {
+    pub level: u32,
+    /// Number of inbox messages, including the internal messages of the protocol
+    pub inbox_messages: u64,
+    /// Number of user messages applied
+    pub accepted: u64,
+    /// Number of user messages rejected
+    pub rejected: u64,
+}
+
+impl LevelStats {
+    pub fn new(level: u32) -> Self {
+        LevelStats {
+            level,
+            inbox_messages: 0,
+            accepted: 0,
+            rejected: 0,
+        }
+    }
+}
+
+/// Number of levels whose statistics are kept
+///
+/// The statistics are stored in a ring: a level takes the slot of the level `STATS_LEVELS` before.
+pub const STATS_LEVELS: u32 = 10_000;
+
+/// Compute the path /stats/{slot}/{field} of the statistics of a level
+fn level_stats_path(level: u32, field: &str) -> Result<OwnedPath> {
+    let path: Vec<u8> = format!("/stats/{}/{}", level % STATS_LEVELS, field).into();
+    OwnedPath::try_from(path).map_err(Error::from)
+}
+
+/// Read the statistics of a level, empty if the level has not been seen yet
+/// or if its slot has been taken by a later level
+pub fn read_level_stats<R: Runtime>(host: &mut R, level: u32) -> Result<LevelStats> {
+    if read_u32(host, &level_stats_path(level, "level")?)? != Some(level) {
+        return Ok(LevelStats::new(level));
+    }
+    let mut read = |field| -> Result<u64> {
+        let path = level_stats_path(level, field)?;
+        Ok(read_u64(host, &path)?.unwrap_or_default())
+    };
+    Ok(LevelStats {
+        level,
+        inbox_messages: read("inbox_messages")?,
+        accepted: read("accepted")?,
+        rejected: read("rejected")?,
+    })
+}
+
+/// Store the statistics of a level in its slot of /stats, with the level they belong to
+pub fn store_level_stats<R: Runtime>(host: &mut R, stats: &LevelStats) -> Result<()> {
+    store_u32(host, &level_stats_path(stats.level, "level")?, stats.level)?;
+    let fields = [
+        ("inbox_messages", &stats.inbox_messages),
+        ("accepted", &stats.accepted),
+        ("rejected", &stats.rejected),
+    ];
+    for (field, value) in fields {
+        store_u64(host, &level_stats_path(stats.level, field)?, value)?;
+    }
+    Ok(())
+}
+
+/// Read the contract receiving the hash of the final canvas
+pub fn read_snapshot_destination<R: Runtime>(host: &mut R) -> Result<Option<SnapshotDestination>> {
+    match read_bytes(host, &SNAPSHOT_DESTINATION)? {
+        Some(bytes) => {
+            let destination = String::from_utf8(bytes)?;
+            SnapshotDestination::parse(&destination).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Store the contract receiving the hash of the final canvas under /config/snapshot/destination
+pub fn store_snapshot_destination<R: Runtime>(
+    host: &mut R,
+    destination: &SnapshotDestination,
+) -> Result<()> {
+    host.store_write(&SNAPSHOT_DESTINATION, destination.to_b58().as_bytes(), 0)
+        .map_err(Error::from)
+}
+
+/// Returns true once the canvas is frozen by the governance
+pub fn is_frozen<R: Runtime>(host: &mut R) -> Result<bool> {
+    Ok(read_bool(host, &FROZEN)?.unwrap_or(false))
+}
+
+/// Hash of the first rows of the frozen canvas
+#[derive(Debug, PartialEq)]
+pub struct SnapshotProgress {
+    /// Next row to hash
+    pub row: u32,
+    /// Hash of the previous rows
+    pub hash: [u8; SNAPSHOT_HASH_SIZE],
+}
+
+/// Freeze the canvas and start its snapshot from the first row
+pub fn freeze<R: Runtime>(host: &mut R, progress: &SnapshotProgress) -> Result<()> {
+    store_bool(host, &FROZEN, true)?;
+    store_snapshot_progress(host, progress)
+}
+
+/// Read the progress of the snapshot, if the snapshot is not over
+pub fn read_snapshot_progress<R: Runtime>(host: &mut R) -> Result<Option<SnapshotProgress>> {
+    let row = match read_u32(host, &SNAPSHOT_ROW)? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let mut hash = [0; SNAPSHOT_HASH_SIZE];
+    match host.store_read_slice(&SNAPSHOT_PARTIAL_HASH, 0, &mut hash) {
+        Ok(SNAPSHOT_HASH_SIZE) => Ok(Some(SnapshotProgress { row, hash })),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Store the progress of the snapshot under /snapshot
+pub fn store_snapshot_progress<R: Runtime>(host: &mut R, progress: &SnapshotProgress) -> Result<()> {
+    store_u32(host, &SNAPSHOT_ROW, progress.row)?;
+    host.store_write(&SNAPSHOT_PARTIAL_HASH, &progress.hash, 0)
+        .map_err(Error::from)
+}
+
+/// Store the hash of the final canvas under /snapshot/hash, the snapshot is then over
+pub fn store_snapshot_hash<R: Runtime>(host: &mut R, hash: &[u8; SNAPSHOT_HASH_SIZE]) -> Result<()> {
+    host.store_delete(&SNAPSHOT_ROW)?;
+    host.store_delete(&SNAPSHOT_PARTIAL_HASH)?;
+    host.store_write(&SNAPSHOT_HASH, hash, 0)
+        .map_err(Error::from)
+}
+
+/// Read the hash of the final canvas, once the snapshot is over
+pub fn read_snapshot_hash<R: Runtime>(host: &mut R) -> Result<Option<Vec<u8>>> {
+    read_bytes(host, &SNAPSHOT_HASH)
+}
+
+/// Read the color of a pixel from its chunk, the pixels never placed are blank
+pub fn read_pixel_color<R: Runtime>(host: &mut R, x: u32, y: u32) -> Result<[u8; 3]> {
+    let (chunk, offset) = chunk_location(x);
+    let path = chunk_path(y, chunk)?;
+    if !exists(host, &path)? {
+        return Ok(BLANK_PIXEL);
+    }
+    let mut color = [0; 3];
+    match host.store_read_slice(&path, offset, &mut color) {
+        Ok(3) => Ok(color),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Read the RGB bytes of a row of the canvas, chunk by chunk
+pub fn read_canvas_row<R: Runtime>(host: &mut R, y: u32, width: u32) -> Result<Vec<u8>> {
+    let mut row = Vec::with_capacity(chunks_per_row(width) as usize * CHUNK_SIZE);
+    for chunk in 0..chunks_per_row(width) {
+        let path = chunk_path(y, chunk)?;
+        if !exists(host, &path)? {
+            row.extend_from_slice(&blank_chunk());
+            continue;
+        }
+        let mut bytes = [0; CHUNK_SIZE];
+        match host.store_read_slice(&path, 0, &mut bytes) {
+            Ok(CHUNK_SIZE) => row.extend_from_slice(&bytes),
+            _ => return Err(Error::StateDeserializarion),
+        }
+    }
+    row.truncate(width as usize * BLANK_PIXEL.len());
+    Ok(row)
+}
+
+/// Write the color of a pixel in its chunk
+///
+/// The chunk is created blank the first time one of its pixels is placed,
+/// then the pixels are written in place at their offset.
+pub fn store_pixel_color<R: Runtime>(host: &mut R, x: u32, y: u32, color: &[u8; 3]) -> Result<()> {
+    let (chunk, offset) = chunk_location(x);
+    let path = chunk_path(y, chunk)?;
+    if !exists(host, &path)? {
+        host.store_write(&path, &blank_chunk(), 0)?;
+    }
+    host.store_write(&path, color, offset).map_err(Error::from)
+}
+
+/// Read a pixel stored with the layout used before the chunks, if it has been placed
+pub fn read_legacy_pixel<R: Runtime>(host: &mut R, x: u32, y: u32) -> Result<Option<[u8; 3]>> {
+    let path = legacy_pixel_path(x, y)?;
+    if !exists(host, &path)? {
+        return Ok(None);
+    }
+    let mut color = [0; 3];
+    match host.store_read_slice(&path, 0, &mut color) {
+        Ok(3) => Ok(Some(color)),
+        _ => Err(Error::StateDeserializarion),
+    }
+}
+
+/// Read the next row of the canvas to migrate to the chunks
+///
+/// The migration starts when the pixels of the previous layout are found under /image,
+/// which happens on the first run after an upgrade of the kernel.
+pub fn read_canvas_migration<R: Runtime>(host: &mut R) -> Result<Option<u32>> {
+    match read_u32(host, &CANVAS_MIGRATION_ROW)? {
+        Some(row) => Ok(Some(row)),
+        None if exists(host, &LEGACY_IMAGE)? => Ok(Some(0)),
+        None => Ok(None),
+    }
+}
+
+pub fn store_canvas_migration<R: Runtime>(host: &mut R, row: u32) -> Result<()> {
+    store_u32(host, &CANVAS_MIGRATION_ROW, row)
+}
+
+/// Delete the pixels of the previous layout once all the rows are migrated
+pub fn end_canvas_migration<R: Runtime>(host: &mut R) -> Result<()> {
+    host.store_delete(&LEGACY_IMAGE)?;
+    if exists(host, &CANVAS_MIGRATION_ROW)? {
+        host.store_delete(&CANVAS_MIGRATION_ROW)?;
+    }
+    Ok(())
+}
+
+/// Store a pixel in its chunk under /canvas/{y}
+///
+/// The placer and the level are recorded in the history of the pixel,
+/// the PIXEL_HISTORY_SIZE last records of the pixel kept at its offset in /pixels/{y}/{chunk}.
+/// The record of a version is written in place at the slot `version % PIXEL_HISTORY_SIZE`,
+/// the version of the pixel being the highest one of its history.
+/// The number of pixels placed by the account is kept with its cooldown.
 pub fn store_pixel<'a, R: Runtime>(
     host: &mut R,
+    owner: &PublicKeyHash,
+    level: u32,
     place_pixel: &'a PlacePixel,
 ) -> Result<&'a PlacePixel> {
     let PlacePixel {
@@ -130,19 +795,197 @@ pub fn store_pixel<'a, R: Runtime>(
         color,
     } = place_pixel;
     debug_msg!(host, "Placing pixel: {:?},{:?}:{:?}\n", x, y, color) ;
-    let path : OwnedPath = pixel_path(x.clone(), y.clone())?;
-    host.store_write(&path, color, 0)
-    .map_err(Error::from)
-    .map(|_| place_pixel)?;
+    store_pixel_color(host, *x, *y, color)?;
+
+    let (path, offset) = history_location(*x, *y)?;
+    if !exists(host, &path)? {
+        store_empty_attribution(host, &path)?;
+    }
+    let version = match read_history(host, &path, offset)?.first() {
+        Some(record) => record.version + 1,
+        None => 1,
+    };
+    let record = PixelRecord {
+        color: *color,
+        owner: owner.clone(),
+        level,
+        version,
+    };
+    host.store_write(&path, &record.to_bytes(), offset + history_slot(version))?;
+
     Ok(place_pixel)
 }
 
+/// Size of the history of a pixel
+const HISTORY_SIZE: usize = PIXEL_HISTORY_SIZE as usize * PIXEL_RECORD_SIZE;
+
+/// Size of the value holding the histories of the pixels of a chunk
+const ATTRIBUTION_SIZE: usize = CHUNK_WIDTH as usize * HISTORY_SIZE;
+
+/// Path of the attribution of the chunk of a pixel, and offset of the history of the pixel in it
+fn history_location(x: u32, y: u32) -> Result<(OwnedPath, usize)> {
+    let (chunk, offset) = chunk_location(x);
+    let path = attribution_path(y, chunk)?;
+    Ok((path, offset / BLANK_PIXEL.len() * HISTORY_SIZE))
+}
+
+/// Offset of the record of a version in the history of a pixel
+fn history_slot(version: u64) -> usize {
+    (version % PIXEL_HISTORY_SIZE) as usize * PIXEL_RECORD_SIZE
+}
+
+/// Create the attribution of a chunk the first time one of its pixels is placed,
+/// with all the slots empty
+fn store_empty_attribution<R: Runtime>(host: &mut R, path: &impl Path) -> Result<()> {
+    let zeros = [0; MAX_FILE_CHUNK_SIZE];
+    let mut size = 0;
+    while size < ATTRIBUTION_SIZE {
+        let len = usize::min(MAX_FILE_CHUNK_SIZE, ATTRIBUTION_SIZE - size);
+        host.store_write(path, &zeros[..len], size)?;
+        size += len;
+    }
+    Ok(())
+}
+
+/// Read the records of the history of a pixel, from the most recent one to the oldest kept one
+///
+/// The empty slots, whose version is 0, are skipped
+fn read_history<R: Runtime>(
+    host: &mut R,
+    path: &impl Path,
+    offset: usize,
+) -> Result<Vec<PixelRecord>> {
+    let mut history = [0; HISTORY_SIZE];
+    match host.store_read_slice(path, offset, &mut history) {
+        Ok(HISTORY_SIZE) => (),
+        _ => return Err(Error::StateDeserializarion),
+    }
+    let mut records = Vec::new();
+    for slot in history.chunks(PIXEL_RECORD_SIZE) {
+        let record = PixelRecord::from_bytes(slot)?;
+        if record.version > 0 {
+            records.push(record);
+        }
+    }
+    records.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(records)
+}
+
+/// Read the number of times a pixel has been painted
+pub fn read_pixel_version<R: Runtime>(host: &mut R, x: u32, y: u32) -> Result<u64> {
+    let record = read_pixel_record(host, x, y)?;
+    Ok(record.map_or(0, |record| record.version))
+}
+
+/// Read the last record of a pixel, if it has been painted
+pub fn read_pixel_record<R: Runtime>(host: &mut R, x: u32, y: u32) -> Result<Option<PixelRecord>> {
+    Ok(read_pixel_history(host, x, y)?.into_iter().next())
+}
+
+/// Read the history of a pixel, from the most recent record to the oldest kept one
+pub fn read_pixel_history<R: Runtime>(host: &mut R, x: u32, y: u32) -> Result<Vec<PixelRecord>> {
+    let (path, offset) = history_location(x, y)?;
+    if !exists(host, &path)? {
+        return Ok(Vec::new());
+    }
+    read_history(host, &path, offset)
+}
+
+/// Read the number of pixels placed by an account
+pub fn read_account_pixels<R: Runtime>(host: &mut R, public_key_hash: &PublicKeyHash) -> Result<u64> {
+    Ok(read_cooldown(host, public_key_hash)?.total)
+}
+
 
-// Stores a receipt under /receipt/{hash}
+/// Stores a receipt under /receipts/{hash}
+///
+/// A successful receipt is never overwritten,
+/// so that replaying a message cannot hide that it has already been applied
 pub fn store_receipt<'a, R: Runtime>(host: &mut R, receipt: &'a Receipt) -> Result<&'a Receipt> {
-    let success_path = receipt_success_path(receipt)?;
+    let hash = receipt.hash();
+    let success_path = receipt_success_path(hash)?;
+    if let Some(true) = read_bool(host, &success_path)? {
+        return Ok(receipt);
+    }
 
     store_bool(host, &success_path, receipt.success())?;
+    store_u64(host, &receipt_level_path(hash)?, &receipt.level().into())?;
+    store_u64(host, &receipt_batch_path(hash)?, &receipt.batch())?;
+    store_u64(host, &receipt_index_path(hash)?, &receipt.index().into())?;
+
+    let error_path = receipt_error_path(hash)?;
+    match receipt.error() {
+        Some(code) => store_u32(host, &error_path, code)?,
+        None if exists(host, &error_path)? => host.store_delete(&error_path)?,
+        None => (),
+    }
 
     Ok(receipt)
 }
+
+/// Reads the success field of the receipt of the given message hash
+pub fn read_receipt_success<R: Runtime>(host: &mut R, hash: &Blake2b) -> Result<Option<bool>> {
+    let success_path = receipt_success_path(hash)?;
+    read_bool(host, &success_path)
+}
+
+/// Durable storage of the kernel, as the state of the transitions
+pub struct DurableStorage<'a, R: Runtime>(pub &'a mut R);
+
+impl<'a, R: Runtime> Storage for DurableStorage<'a, R> {
+    fn is_allowed(&mut self, public_key_hash: &PublicKeyHash) -> Result<bool> {
+        is_allowed(self.0, public_key_hash)
+    }
+
+    fn read_nonce(&mut self, public_key_hash: &PublicKeyHash) -> Result<Nonce> {
+        Ok(read_account(self.0, public_key_hash.clone())?.nonce)
+    }
+
+    fn store_nonce(&mut self, public_key_hash: &PublicKeyHash, nonce: &Nonce) -> Result<()> {
+        let account = Account {
+            public_key_hash: public_key_hash.clone(),
+            nonce: Nonce(nonce.0),
+        };
+        store_account(self.0, &account).map(|_| ())
+    }
+
+    fn is_frozen(&mut self) -> Result<bool> {
+        is_frozen(self.0)
+    }
+
+    fn read_event_window(&mut self) -> Result<EventWindow> {
+        read_event_window(self.0)
+    }
+
+    fn read_level_timestamp(&mut self) -> Result<Option<i64>> {
+        read_level_timestamp(self.0)
+    }
+
+    fn read_canvas_config(&mut self) -> Result<CanvasConfig> {
+        read_canvas_config(self.0)
+    }
+
+    fn read_cooldown_config(&mut self) -> Result<CooldownConfig> {
+        read_cooldown_config(self.0)
+    }
+
+    fn read_cooldown(&mut self, public_key_hash: &PublicKeyHash) -> Result<Cooldown> {
+        read_cooldown(self.0, public_key_hash)
+    }
+
+    fn store_cooldown(
+        &mut self,
+        public_key_hash: &PublicKeyHash,
+        cooldown: &Cooldown,
+    ) -> Result<()> {
+        store_cooldown(self.0, public_key_hash, cooldown)
+    }
+
+    fn read_pixel(&mut self, x: u32, y: u32) -> Result<[u8; 3]> {
+        read_pixel_color(self.0, x, y)
+    }
+
+    fn store_pixel(&mut self, owner: &PublicKeyHash, level: u32, pixel: &PlacePixel) -> Result<()> {
+        store_pixel(self.0, owner, level, pixel).map(|_| ())
+    }
+}
@@ -0,0 +1,81 @@
+use lib::framing::MAX_BATCH_PAGES;
+
+/// Maximum number of ticks of a kernel run in the PVM
+const MAX_TICKS: u64 = 11_000_000_000;
+
+/// Ticks kept in reserve for the work which is not accounted, e.g. the debug messages
+const SAFETY_MARGIN: u64 = 1_000_000_000;
+
+/// Estimated cost of reading and parsing an inbox message,
+/// including the verification of the signature of a batch header
+pub const TICKS_PER_INBOX_MESSAGE: u64 = 100_000_000;
+
+/// Estimated cost of revealing a DAC page and decoding its messages
+pub const TICKS_PER_PAGE: u64 = 20_000_000;
+
+/// Estimated cost of revealing a DAC page already processed before a reboot
+pub const TICKS_PER_SKIPPED_PAGE: u64 = 5_000_000;
+
+/// Estimated cost of a user message: its share of the batch verification of the signatures,
+/// nonce, cooldown, pixel and receipt
+pub const TICKS_PER_USER_MESSAGE: u64 = 60_000_000;
+
+/// Estimated cost of verifying a signature on its own, after the batch verification failed
+pub const TICKS_PER_SIGNATURE: u64 = 40_000_000;
+
+/// Estimated cost of reading a pixel and hashing it in the snapshot of the canvas
+pub const TICKS_PER_PIXEL_READ: u64 = 200_000;
+
+/// Estimated cost of moving a pixel from the layout used before the chunks to its chunk
+pub const TICKS_PER_PIXEL_MIGRATION: u64 = 400_000;
+
+// A batch resumed at its last page is still processed in a single run
+const _: () = assert!(
+    MAX_BATCH_PAGES as u64 * TICKS_PER_SKIPPED_PAGE
+        + TICKS_PER_INBOX_MESSAGE
+        + TICKS_PER_PAGE
+        + TICKS_PER_USER_MESSAGE
+        + TICKS_PER_SIGNATURE
+        < MAX_TICKS - SAFETY_MARGIN
+);
+
+/// Estimation of the ticks consumed by the current kernel run
+///
+/// The estimation is conservative: the kernel reboots before reaching the tick limit of the PVM,
+/// and continues its work in the next run.
+pub struct TickCounter {
+    consumed: u64,
+    budget: u64,
+}
+
+impl TickCounter {
+    pub fn new() -> Self {
+        Self::with_budget(MAX_TICKS - SAFETY_MARGIN)
+    }
+
+    pub fn with_budget(budget: u64) -> Self {
+        TickCounter { consumed: 0, budget }
+    }
+
+    /// Accounts for some work done
+    pub fn consume(&mut self, ticks: u64) {
+        self.consumed = self.consumed.saturating_add(ticks);
+    }
+
+    /// Returns true if some work of the given cost fits in the remaining budget
+    pub fn can_afford(&self, ticks: u64) -> bool {
+        self.affordable(ticks) > 0
+    }
+
+    /// Number of units of work of the given cost fitting in the remaining budget
+    pub fn affordable(&self, ticks: u64) -> u64 {
+        self.budget.saturating_sub(self.consumed) / ticks
+    }
+}
+
+impl Default for TickCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
@@ -0,0 +1,151 @@
+use crate::constants::{CANVAS_HEIGHT, CANVAS_WIDTH};
+use crate::error::*;
+use crate::message::PlacePixel;
+
+/// Color of the pixels which have never been placed, the canvas starts white
+pub const BLANK_PIXEL: [u8; 3] = [255, 255, 255];
+
+/// Number of pixels of a chunk
+///
+/// The rows of the canvas are stored as chunks of consecutive pixels,
+/// each chunk is a single value holding the RGB bytes of its pixels.
+pub const CHUNK_WIDTH: u32 = 256;
+
+/// Size of the value of a chunk
+pub const CHUNK_SIZE: usize = CHUNK_WIDTH as usize * BLANK_PIXEL.len();
+
+/// Index of the chunk holding the pixel of the given column, and offset of the pixel in the chunk
+pub fn chunk_location(x: u32) -> (u32, usize) {
+    let offset = (x % CHUNK_WIDTH) as usize * BLANK_PIXEL.len();
+    (x / CHUNK_WIDTH, offset)
+}
+
+/// Number of chunks of a row of the given width, the last one may be partially used
+pub fn chunks_per_row(width: u32) -> u32 {
+    (width + CHUNK_WIDTH - 1) / CHUNK_WIDTH
+}
+
+/// A chunk whose pixels have never been placed
+pub fn blank_chunk() -> Vec<u8> {
+    BLANK_PIXEL.repeat(CHUNK_WIDTH as usize)
+}
+
+/// Dimensions of the canvas, and the colors a pixel can be painted with
+///
+/// When there is no palette, every color is allowed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanvasConfig {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Option<Vec<[u8; 3]>>,
+}
+
+impl Default for CanvasConfig {
+    fn default() -> Self {
+        CanvasConfig {
+            width: CANVAS_WIDTH,
+            height: CANVAS_HEIGHT,
+            palette: None,
+        }
+    }
+}
+
+impl CanvasConfig {
+    /// Verify that the pixel is inside the canvas and uses a color of the palette
+    pub fn validate(&self, pixel: &PlacePixel) -> Result<()> {
+        if pixel.x >= self.width || pixel.y >= self.height {
+            return Err(Error::PixelOutOfBounds);
+        }
+        match &self.palette {
+            Some(palette) if !palette.contains(&pixel.color) => Err(Error::InvalidColor),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes the palette as a sequence of 3 bytes colors
+    pub fn palette_to_bytes(palette: &[[u8; 3]]) -> Vec<u8> {
+        palette.iter().flatten().copied().collect()
+    }
+
+    /// Decodes a palette encoded as a sequence of 3 bytes colors
+    pub fn palette_from_bytes(bytes: &[u8]) -> Result<Vec<[u8; 3]>> {
+        if bytes.len() % 3 != 0 {
+            return Err(Error::StateDeserializarion);
+        }
+        Ok(bytes
+            .chunks_exact(3)
+            .map(|color| [color[0], color[1], color[2]])
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_location, chunks_per_row, CanvasConfig, CHUNK_SIZE};
+    use crate::error::Error;
+    use crate::message::PlacePixel;
+
+    #[test]
+    fn test_out_of_bounds_pixel() {
+        let config = CanvasConfig {
+            width: 10,
+            height: 20,
+            palette: None,
+        };
+        let pixel = PlacePixel {
+            x: 10,
+            y: 0,
+            color: [0, 0, 0],
+        };
+        assert!(matches!(config.validate(&pixel), Err(Error::PixelOutOfBounds)));
+
+        let pixel = PlacePixel {
+            x: 9,
+            y: 19,
+            color: [0, 0, 0],
+        };
+        assert!(config.validate(&pixel).is_ok());
+    }
+
+    #[test]
+    fn test_palette() {
+        let config = CanvasConfig {
+            width: 10,
+            height: 10,
+            palette: Some(vec![[0, 0, 0], [255, 255, 255]]),
+        };
+        let pixel = PlacePixel {
+            x: 1,
+            y: 1,
+            color: [255, 0, 0],
+        };
+        assert!(matches!(config.validate(&pixel), Err(Error::InvalidColor)));
+
+        let pixel = PlacePixel {
+            x: 1,
+            y: 1,
+            color: [255, 255, 255],
+        };
+        assert!(config.validate(&pixel).is_ok());
+    }
+
+    #[test]
+    fn test_palette_encoding() {
+        let palette = vec![[1, 2, 3], [4, 5, 6]];
+        let bytes = CanvasConfig::palette_to_bytes(&palette);
+        assert_eq!(bytes, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(CanvasConfig::palette_from_bytes(&bytes).unwrap(), palette);
+        assert!(CanvasConfig::palette_from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_location() {
+        assert_eq!(chunk_location(0), (0, 0));
+        assert_eq!(chunk_location(255), (0, CHUNK_SIZE - 3));
+        assert_eq!(chunk_location(256), (1, 0));
+        assert_eq!(chunk_location(1023), (3, CHUNK_SIZE - 3));
+        assert_eq!(chunks_per_row(1024), 4);
+        assert_eq!(chunks_per_row(1000), 4);
+        assert_eq!(chunks_per_row(10), 1);
+    }
+}
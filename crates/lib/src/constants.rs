@@ -3,3 +3,18 @@ pub const MAGIC_BYTE: u8 = 0x74;
 pub const L1_GOVERNANCE_CONTRACT_ADDRESS: &str = "KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy";
 
 pub const SEQUENCER_PK : &str ="edpkutAiHYgY54ZWQ1A43nvJj7ugweUHG6sbV53WjX8pqnmiaNWHih";
+
+/// Maximum number of pixels an account can place during a cooldown window
+pub const COOLDOWN_MAX_PIXELS: u32 = 5;
+
+/// Duration of a cooldown window, in levels
+pub const COOLDOWN_LEVELS: u32 = 4;
+
+/// Width of the canvas, used at genesis
+pub const CANVAS_WIDTH: u32 = 1024;
+
+/// Height of the canvas, used at genesis
+pub const CANVAS_HEIGHT: u32 = 1024;
+
+/// Number of previous versions kept in the history of each pixel
+pub const PIXEL_HISTORY_SIZE: u64 = 8;
@@ -0,0 +1,176 @@
+use crate::constants::{COOLDOWN_LEVELS, COOLDOWN_MAX_PIXELS};
+use crate::error::*;
+
+/// Number of pixels an account can place within a window of levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CooldownConfig {
+    pub max_pixels: u32,
+    pub levels: u32,
+}
+
+impl Default for CooldownConfig {
+    fn default() -> Self {
+        CooldownConfig {
+            max_pixels: COOLDOWN_MAX_PIXELS,
+            levels: COOLDOWN_LEVELS,
+        }
+    }
+}
+
+/// Size of an encoded configuration
+pub const COOLDOWN_CONFIG_SIZE: usize = 8;
+
+impl CooldownConfig {
+    /// Encodes the maximum number of pixels and the number of levels (big endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(COOLDOWN_CONFIG_SIZE);
+        bytes.extend_from_slice(&self.max_pixels.to_be_bytes());
+        bytes.extend_from_slice(&self.levels.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a configuration, at least one pixel has to be allowed per window
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; COOLDOWN_CONFIG_SIZE] = bytes
+            .try_into()
+            .map_err(|_| Error::InvalidGovernanceMessage)?;
+        let (max_pixels, levels) = bytes.split_at(4);
+        let config = CooldownConfig {
+            max_pixels: u32::from_be_bytes(max_pixels.try_into().unwrap()),
+            levels: u32::from_be_bytes(levels.try_into().unwrap()),
+        };
+        match config.max_pixels {
+            0 => Err(Error::InvalidGovernanceMessage),
+            _ => Ok(config),
+        }
+    }
+}
+
+/// Pixels placed by an account during its current window
+///
+/// The total number of pixels placed by the account is kept along,
+/// so that both are updated by the same write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cooldown {
+    pub window_start: u32,
+    pub pixels: u32,
+    pub total: u64,
+}
+
+/// Size of an encoded cooldown
+pub const COOLDOWN_SIZE: usize = 16;
+
+impl Cooldown {
+    /// Returns the cooldown after placing a pixel at the given level
+    ///
+    /// A new window starts at the first pixel placed after the previous window has elapsed
+    pub fn place(self, config: &CooldownConfig, level: u32) -> Result<Cooldown> {
+        let window_end = self.window_start.saturating_add(config.levels);
+        let total = self.total + 1;
+        if self.pixels == 0 || level >= window_end {
+            Ok(Cooldown {
+                window_start: level,
+                pixels: 1,
+                total,
+            })
+        } else if self.pixels < config.max_pixels {
+            Ok(Cooldown {
+                pixels: self.pixels + 1,
+                total,
+                ..self
+            })
+        } else {
+            Err(Error::CooldownNotElapsed)
+        }
+    }
+
+    /// Encodes the start of the window, its pixels and the total (big endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(COOLDOWN_SIZE);
+        bytes.extend_from_slice(&self.window_start.to_be_bytes());
+        bytes.extend_from_slice(&self.pixels.to_be_bytes());
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a cooldown encoded with [`Cooldown::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; COOLDOWN_SIZE] =
+            bytes.try_into().map_err(|_| Error::StateDeserializarion)?;
+        let (window_start, bytes) = bytes.split_at(4);
+        let (pixels, total) = bytes.split_at(4);
+        Ok(Cooldown {
+            window_start: u32::from_be_bytes(window_start.try_into().unwrap()),
+            pixels: u32::from_be_bytes(pixels.try_into().unwrap()),
+            total: u64::from_be_bytes(total.try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cooldown, CooldownConfig};
+
+    const CONFIG: CooldownConfig = CooldownConfig {
+        max_pixels: 2,
+        levels: 3,
+    };
+
+    #[test]
+    fn test_cooldown_limits_pixels_per_window() {
+        let cooldown = Cooldown::default();
+        let cooldown = cooldown.place(&CONFIG, 10).unwrap();
+        let cooldown = cooldown.place(&CONFIG, 11).unwrap();
+
+        assert_eq!(
+            cooldown,
+            Cooldown {
+                window_start: 10,
+                pixels: 2,
+                total: 2,
+            }
+        );
+        assert!(cooldown.place(&CONFIG, 12).is_err());
+    }
+
+    #[test]
+    fn test_cooldown_resets_after_window() {
+        let cooldown = Cooldown {
+            window_start: 10,
+            pixels: 2,
+            total: 5,
+        };
+        let cooldown = cooldown.place(&CONFIG, 13).unwrap();
+
+        assert_eq!(
+            cooldown,
+            Cooldown {
+                window_start: 13,
+                pixels: 1,
+                total: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cooldown_config_round_trip() {
+        let bytes = CONFIG.to_bytes();
+        assert_eq!(bytes, [0, 0, 0, 2, 0, 0, 0, 3]);
+        assert_eq!(CooldownConfig::from_bytes(&bytes).unwrap(), CONFIG);
+        assert!(CooldownConfig::from_bytes(&bytes[1..]).is_err());
+        assert!(CooldownConfig::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 3]).is_err());
+    }
+
+    #[test]
+    fn test_cooldown_round_trip() {
+        let cooldown = Cooldown {
+            window_start: 10,
+            pixels: 2,
+            total: 7,
+        };
+        let bytes = cooldown.to_bytes();
+        assert_eq!(bytes, [0, 0, 0, 10, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(Cooldown::from_bytes(&bytes).unwrap(), cooldown);
+        assert!(Cooldown::from_bytes(&bytes[1..]).is_err());
+    }
+}
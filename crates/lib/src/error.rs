@@ -21,10 +21,25 @@ pub enum ReadInputError {
 #[derive(Debug)]
 pub enum Error {
     FromUtf8(std::string::FromUtf8Error),
+    SerdeJson(serde_json_wasm::de::Error),
     Runtime(tezos_smart_rollup::host::RuntimeError),
     Ed25519Compact(ed25519_compact::Error),
     InvalidSignature,
     InvalidNonce,
+    CooldownNotElapsed,
+    EventNotStarted,
+    CanvasFrozen,
+    PixelOutOfBounds,
+    InvalidColor,
+    NotAllowed,
+    InvalidGovernanceMessage,
+    NoSequencer,
+    InvalidSequencerKey,
+    InvalidBatchHeader,
+    InvalidBatchMessage,
+    InvalidRollupAddress,
+    InvalidUserMessage,
+    InvalidSnapshotDestination,
     PathError(tezos_smart_rollup::storage::path::PathError),
     StateDeserializarion,
     BinError(tezos_data_encoding::enc::BinError),
@@ -36,10 +51,25 @@ impl ToString for Error {
     fn to_string(&self) -> String {
         let err = match self {
             Error::FromUtf8(_) => "Cannot convert bytes to string",
+            Error::SerdeJson(_) => "Cannot deserialize the message",
             Error::Runtime(_) => "Runtime error, caused by host function",
             Error::Ed25519Compact(_) => "Cannot deserialize Ed25519",
             Error::InvalidSignature => "Invalid signature",
             Error::InvalidNonce => "Invalid nonce",
+            Error::CooldownNotElapsed => "Too many pixels placed, wait for the cooldown",
+            Error::EventNotStarted => "The event has not started yet",
+            Error::CanvasFrozen => "The event is over, the canvas is frozen",
+            Error::PixelOutOfBounds => "Pixel is outside of the canvas",
+            Error::InvalidColor => "Color is not in the palette",
+            Error::NotAllowed => "Account is not in the allowlist",
+            Error::InvalidGovernanceMessage => "Invalid governance message",
+            Error::NoSequencer => "At least one sequencer is required",
+            Error::InvalidSequencerKey => "Sequencer keys have to be ed25519 keys",
+            Error::InvalidBatchHeader => "Batch is missing, reordered or duplicated",
+            Error::InvalidBatchMessage => "Cannot decode the batch message",
+            Error::InvalidRollupAddress => "Invalid rollup address",
+            Error::InvalidUserMessage => "Cannot decode the message",
+            Error::InvalidSnapshotDestination => "The snapshot destination has to be a KT1 contract",
             Error::PathError(_) => "Invalid path",
             Error::StateDeserializarion => "State deserialization",
             Error::BinError(_) => "Cannot serialize michelson to binary",
@@ -50,6 +80,41 @@ impl ToString for Error {
     }
 }
 
+impl Error {
+    /// Stable code of the error, recorded in the receipts of the rejected messages
+    ///
+    /// A code is never reused, new errors get new codes
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::FromUtf8(_) => 1,
+            Error::SerdeJson(_) => 2,
+            Error::Runtime(_) => 3,
+            Error::Ed25519Compact(_) => 4,
+            Error::InvalidSignature => 5,
+            Error::InvalidNonce => 6,
+            Error::CooldownNotElapsed => 7,
+            Error::EventNotStarted => 8,
+            Error::CanvasFrozen => 9,
+            Error::PixelOutOfBounds => 10,
+            Error::InvalidColor => 11,
+            Error::NotAllowed => 12,
+            Error::InvalidGovernanceMessage => 13,
+            Error::NoSequencer => 14,
+            Error::InvalidSequencerKey => 15,
+            Error::InvalidBatchHeader => 16,
+            Error::InvalidBatchMessage => 17,
+            Error::InvalidRollupAddress => 18,
+            Error::InvalidUserMessage => 19,
+            Error::InvalidSnapshotDestination => 20,
+            Error::PathError(_) => 21,
+            Error::StateDeserializarion => 22,
+            Error::BinError(_) => 23,
+            Error::EntrypointError(_) => 24,
+            Error::GenericError(_) => 25,
+        }
+    }
+}
+
 macro_rules! register_error {
     ($name:ident, $error:ty) => {
         impl From<$error> for Error {
@@ -61,6 +126,7 @@ macro_rules! register_error {
 }
 
 register_error!(FromUtf8, std::string::FromUtf8Error);
+register_error!(SerdeJson, serde_json_wasm::de::Error);
 register_error!(Ed25519Compact, ed25519_compact::Error);
 register_error!(PathError, tezos_smart_rollup::storage::path::PathError);
 register_error!(Runtime, tezos_smart_rollup::host::RuntimeError);
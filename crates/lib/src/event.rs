@@ -0,0 +1,120 @@
+use crate::error::*;
+
+/// Period during which pixels can be placed, as L1 timestamps in seconds
+///
+/// Without a start, the event is open until its end.
+/// Without an end, the canvas is never frozen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EventWindow {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+/// Size of an encoded bound: a presence flag followed by the timestamp
+const BOUND_SIZE: usize = 1 + 8;
+
+/// Size of an encoded window
+pub const EVENT_WINDOW_SIZE: usize = 2 * BOUND_SIZE;
+
+fn encode_bound(bound: Option<i64>, bytes: &mut Vec<u8>) {
+    match bound {
+        Some(timestamp) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        None => bytes.extend_from_slice(&[0; BOUND_SIZE]),
+    }
+}
+
+fn decode_bound(bytes: &[u8]) -> Result<Option<i64>> {
+    match bytes {
+        [0, padding @ ..] if padding.iter().all(|byte| *byte == 0) => Ok(None),
+        [1, timestamp @ ..] => timestamp
+            .try_into()
+            .map(|timestamp| Some(i64::from_be_bytes(timestamp)))
+            .map_err(|_| Error::InvalidGovernanceMessage),
+        _ => Err(Error::InvalidGovernanceMessage),
+    }
+}
+
+impl EventWindow {
+    /// Verify that pixels can be placed at the given timestamp
+    ///
+    /// The timestamp of the level is unknown only if the kernel has not read any InfoPerLevel,
+    /// the window is then not enforced.
+    pub fn check(&self, timestamp: Option<i64>) -> Result<()> {
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => return Ok(()),
+        };
+        match (self.start, self.end) {
+            (Some(start), _) if timestamp < start => Err(Error::EventNotStarted),
+            (_, Some(end)) if timestamp >= end => Err(Error::CanvasFrozen),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes each bound as a flag (0 for no bound, 1 otherwise) and the timestamp (big endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(EVENT_WINDOW_SIZE);
+        encode_bound(self.start, &mut bytes);
+        encode_bound(self.end, &mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != EVENT_WINDOW_SIZE {
+            return Err(Error::InvalidGovernanceMessage);
+        }
+        let (start, end) = bytes.split_at(BOUND_SIZE);
+        Ok(EventWindow {
+            start: decode_bound(start)?,
+            end: decode_bound(end)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventWindow;
+
+    #[test]
+    fn test_event_window() {
+        let window = EventWindow {
+            start: Some(100),
+            end: Some(200),
+        };
+        assert!(window.check(Some(99)).is_err());
+        assert!(window.check(Some(100)).is_ok());
+        assert!(window.check(Some(199)).is_ok());
+        assert!(window.check(Some(200)).is_err());
+        assert!(window.check(None).is_ok());
+        assert!(EventWindow::default().check(Some(0)).is_ok());
+    }
+
+    #[test]
+    fn test_event_window_round_trip() {
+        let windows = [
+            EventWindow::default(),
+            EventWindow {
+                start: Some(-1),
+                end: None,
+            },
+            EventWindow {
+                start: None,
+                end: Some(1_700_000_000),
+            },
+        ];
+        for window in windows {
+            let bytes = window.to_bytes();
+            assert_eq!(bytes.len(), super::EVENT_WINDOW_SIZE);
+            assert_eq!(EventWindow::from_bytes(&bytes).unwrap(), window);
+        }
+        assert!(EventWindow::from_bytes(&[2; super::EVENT_WINDOW_SIZE]).is_err());
+
+        // The timestamp of a missing bound has to be empty
+        let mut bytes = EventWindow::default().to_bytes();
+        bytes[1] = 1;
+        assert!(EventWindow::from_bytes(&bytes).is_err());
+    }
+}
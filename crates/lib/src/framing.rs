@@ -0,0 +1,163 @@
+use crate::dac::V0SliceContentPage;
+
+/// Size of the length prefix of a frame
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Maximum number of content pages of a batch, the pages after it are ignored by the kernel
+///
+/// An interrupted batch is resumed by revealing its pages again from the first one,
+/// so the kernel has to be able to skip all of them in a single run.
+pub const MAX_BATCH_PAGES: usize = 1000;
+
+/// Maximum size of the frames of a batch, see `MAX_BATCH_PAGES`
+pub const MAX_BATCH_SIZE: usize = MAX_BATCH_PAGES * V0SliceContentPage::MAX_CONTENT_SIZE;
+
+/// Packs the messages of a batch into the contents of DAC pages
+///
+/// Each message is prefixed by its length (u32, big endian),
+/// the frames are concatenated and the result is cut into chunks fitting in a content page.
+/// A message can span several pages.
+pub fn pack(messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut stream = Vec::new();
+    for message in messages {
+        stream.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        stream.extend_from_slice(message);
+    }
+    stream
+        .chunks(V0SliceContentPage::MAX_CONTENT_SIZE)
+        .map(Vec::from)
+        .collect()
+}
+
+/// Rebuilds the messages from the contents of the pages of a batch
+///
+/// The pages are pushed in order, complete messages are returned by `next_frame`,
+/// an incomplete message is kept until the next page is pushed.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    offset: usize,
+    /// Number of bytes dropped from the buffer
+    drained: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the content of the next page
+    pub fn push(&mut self, content: &[u8]) {
+        self.buffer.drain(..self.offset);
+        self.drained += self.offset;
+        self.offset = 0;
+        self.buffer.extend_from_slice(content);
+    }
+
+    /// Returns the next complete message, if any
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let remaining = &self.buffer[self.offset..];
+        if remaining.len() < LENGTH_PREFIX_SIZE {
+            return None;
+        }
+        let (prefix, remaining) = remaining.split_at(LENGTH_PREFIX_SIZE);
+        let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if remaining.len() < length {
+            return None;
+        }
+        let frame = remaining[..length].to_vec();
+        self.offset += LENGTH_PREFIX_SIZE + length;
+        Some(frame)
+    }
+
+    /// Position of the next message in the stream of the pushed pages
+    pub fn position(&self) -> usize {
+        self.drained + self.offset
+    }
+
+    /// Returns true if there is no pending bytes
+    ///
+    /// Pending bytes at the end of a batch means the last message is truncated
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.buffer.len()
+    }
+
+    /// Pending bytes, the start of a message whose end has not been pushed yet
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer[self.offset..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack, FrameDecoder};
+    use crate::dac::V0SliceContentPage;
+
+    fn decode(pages: &[Vec<u8>]) -> (Vec<Vec<u8>>, bool) {
+        let mut decoder = FrameDecoder::new();
+        let mut messages = Vec::new();
+        for page in pages {
+            decoder.push(page);
+            while let Some(message) = decoder.next_frame() {
+                messages.push(message);
+            }
+        }
+        (messages, decoder.is_empty())
+    }
+
+    #[test]
+    fn test_small_messages_share_a_page() {
+        let messages: Vec<Vec<u8>> = (0..10).map(|i| vec![i; 100]).collect();
+        let pages = pack(&messages);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].len(), 10 * (4 + 100));
+        assert_eq!(decode(&pages), (messages, true));
+    }
+
+    #[test]
+    fn test_messages_span_pages() {
+        let messages: Vec<Vec<u8>> = (0..90).map(|i| vec![i; 119]).collect();
+        let pages = pack(&messages);
+        assert_eq!(pages.len(), 3);
+        assert!(pages
+            .iter()
+            .all(|page| page.len() <= V0SliceContentPage::MAX_CONTENT_SIZE));
+        assert_eq!(decode(&pages), (messages, true));
+
+        let large = vec![vec![7; 3 * V0SliceContentPage::MAX_CONTENT_SIZE], vec![8]];
+        assert_eq!(decode(&pack(&large)), (large, true));
+    }
+
+    #[test]
+    fn test_truncated_message_is_pending() {
+        let messages = vec![vec![1; 10], vec![2; 10]];
+        let mut pages = pack(&messages);
+        pages[0].pop();
+        let (decoded, is_empty) = decode(&pages);
+        assert_eq!(decoded, vec![vec![1; 10]]);
+        assert!(!is_empty);
+    }
+
+    #[test]
+    fn test_position_of_the_next_message() {
+        let messages = vec![vec![1; 10], vec![2; 20], vec![3; 30]];
+        let stream = pack(&messages).concat();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&stream[..20]);
+        assert_eq!(decoder.next_frame(), Some(vec![1; 10]));
+        assert_eq!(decoder.position(), 14);
+        assert_eq!(decoder.next_frame(), None);
+
+        decoder.push(&stream[20..]);
+        assert_eq!(decoder.next_frame(), Some(vec![2; 20]));
+        assert_eq!(decoder.position(), 38);
+        assert_eq!(decoder.next_frame(), Some(vec![3; 30]));
+        assert_eq!(decoder.position(), stream.len());
+    }
+
+    #[test]
+    fn test_empty_messages() {
+        let messages = vec![vec![], vec![1], vec![]];
+        assert_eq!(decode(&pack(&messages)), (messages, true));
+    }
+}
@@ -0,0 +1,235 @@
+use tezos_smart_rollup::core_unsafe::PREIMAGE_HASH_SIZE;
+
+use crate::cooldown::CooldownConfig;
+use crate::error::*;
+use crate::event::EventWindow;
+use crate::public_key::PublicKey;
+use crate::public_key_hash::PublicKeyHash;
+use crate::snapshot::SnapshotDestination;
+
+/// First byte of the tagged messages
+const GOVERNANCE_VERSION: u8 = 0x01;
+
+/// First byte of a root hash, the tag of the Blake2b reveal hashes
+const LEGACY_UPGRADE_PREFIX: u8 = 0x00;
+
+const UPGRADE_KERNEL_TAG: u8 = 0x00;
+const ALLOWLIST_ADD_TAG: u8 = 0x01;
+const ALLOWLIST_REMOVE_TAG: u8 = 0x02;
+const ENABLE_ALLOWLIST_TAG: u8 = 0x03;
+const ADD_SEQUENCER_TAG: u8 = 0x04;
+const REMOVE_SEQUENCER_TAG: u8 = 0x05;
+const SET_EVENT_WINDOW_TAG: u8 = 0x06;
+const FREEZE_TAG: u8 = 0x07;
+const SET_SNAPSHOT_DESTINATION_TAG: u8 = 0x08;
+const SET_COOLDOWN_TAG: u8 = 0x09;
+
+/// Messages sent by the governance contract, as the bytes content of a ticket
+///
+/// A message is the version byte, a tag byte and its payload.
+/// Public key hashes and public keys are encoded as b58 strings.
+/// A root hash of PREIMAGE_HASH_SIZE bytes, without version nor tag, is a kernel upgrade:
+/// this is what the governance contract has always sent.
+/// It is told apart by its first byte, the tag of the Blake2b reveal hashes,
+/// which is never the version byte.
+#[derive(Debug, PartialEq)]
+pub enum GovernanceMessage {
+    /// Install the kernel revealed from the given root hash
+    UpgradeKernel([u8; PREIMAGE_HASH_SIZE]),
+    /// Allow an account to place pixels
+    AllowlistAdd(PublicKeyHash),
+    /// Forbid an account to place pixels
+    AllowlistRemove(PublicKeyHash),
+    /// Turn the allowlist on or off
+    EnableAllowlist(bool),
+    /// Accept the batches signed by the given sequencer key
+    AddSequencer(PublicKey),
+    /// Stop accepting the batches signed by the given sequencer key
+    RemoveSequencer(PublicKey),
+    /// Set the period during which pixels can be placed
+    SetEventWindow(EventWindow),
+    /// Stop the placement of pixels and send the hash of the final canvas to L1
+    Freeze,
+    /// Set the contract receiving the hash of the final canvas
+    SetSnapshotDestination(SnapshotDestination),
+    /// Set the number of pixels an account can place within a window of levels
+    SetCooldown(CooldownConfig),
+}
+
+impl GovernanceMessage {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [LEGACY_UPGRADE_PREFIX, ..] => bytes
+                .try_into()
+                .map(GovernanceMessage::UpgradeKernel)
+                .map_err(|_| Error::InvalidGovernanceMessage),
+            [GOVERNANCE_VERSION, message @ ..] => Self::parse_tagged(message),
+            _ => Err(Error::InvalidGovernanceMessage),
+        }
+    }
+
+    fn parse_tagged(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [UPGRADE_KERNEL_TAG, root_hash @ ..] => root_hash
+                .try_into()
+                .map(GovernanceMessage::UpgradeKernel)
+                .map_err(|_| Error::InvalidGovernanceMessage),
+            [ALLOWLIST_ADD_TAG, public_key_hash @ ..] => {
+                parse_public_key_hash(public_key_hash).map(GovernanceMessage::AllowlistAdd)
+            }
+            [ALLOWLIST_REMOVE_TAG, public_key_hash @ ..] => {
+                parse_public_key_hash(public_key_hash).map(GovernanceMessage::AllowlistRemove)
+            }
+            [ENABLE_ALLOWLIST_TAG, 0x00] => Ok(GovernanceMessage::EnableAllowlist(false)),
+            [ENABLE_ALLOWLIST_TAG, 0x01] => Ok(GovernanceMessage::EnableAllowlist(true)),
+            [ADD_SEQUENCER_TAG, public_key @ ..] => {
+                parse_public_key(public_key).map(GovernanceMessage::AddSequencer)
+            }
+            [REMOVE_SEQUENCER_TAG, public_key @ ..] => {
+                parse_public_key(public_key).map(GovernanceMessage::RemoveSequencer)
+            }
+            [SET_EVENT_WINDOW_TAG, window @ ..] => {
+                EventWindow::from_bytes(window).map(GovernanceMessage::SetEventWindow)
+            }
+            [FREEZE_TAG] => Ok(GovernanceMessage::Freeze),
+            [SET_SNAPSHOT_DESTINATION_TAG, destination @ ..] => {
+                let destination =
+                    std::str::from_utf8(destination).map_err(|_| Error::InvalidGovernanceMessage)?;
+                SnapshotDestination::parse(destination).map(GovernanceMessage::SetSnapshotDestination)
+            }
+            [SET_COOLDOWN_TAG, config @ ..] => {
+                CooldownConfig::from_bytes(config).map(GovernanceMessage::SetCooldown)
+            }
+            _ => Err(Error::InvalidGovernanceMessage),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![GOVERNANCE_VERSION];
+        match self {
+            GovernanceMessage::UpgradeKernel(root_hash) => {
+                bytes.push(UPGRADE_KERNEL_TAG);
+                bytes.extend_from_slice(root_hash);
+            }
+            GovernanceMessage::AllowlistAdd(public_key_hash) => {
+                bytes.push(ALLOWLIST_ADD_TAG);
+                bytes.extend_from_slice(public_key_hash.to_string().as_bytes());
+            }
+            GovernanceMessage::AllowlistRemove(public_key_hash) => {
+                bytes.push(ALLOWLIST_REMOVE_TAG);
+                bytes.extend_from_slice(public_key_hash.to_string().as_bytes());
+            }
+            GovernanceMessage::EnableAllowlist(enabled) => {
+                bytes.push(ENABLE_ALLOWLIST_TAG);
+                bytes.push(*enabled as u8);
+            }
+            GovernanceMessage::AddSequencer(public_key) => {
+                bytes.push(ADD_SEQUENCER_TAG);
+                bytes.extend_from_slice(public_key.to_b58().as_bytes());
+            }
+            GovernanceMessage::RemoveSequencer(public_key) => {
+                bytes.push(REMOVE_SEQUENCER_TAG);
+                bytes.extend_from_slice(public_key.to_b58().as_bytes());
+            }
+            GovernanceMessage::SetEventWindow(window) => {
+                bytes.push(SET_EVENT_WINDOW_TAG);
+                bytes.extend_from_slice(&window.to_bytes());
+            }
+            GovernanceMessage::Freeze => bytes.push(FREEZE_TAG),
+            GovernanceMessage::SetSnapshotDestination(destination) => {
+                bytes.push(SET_SNAPSHOT_DESTINATION_TAG);
+                bytes.extend_from_slice(destination.to_b58().as_bytes());
+            }
+            GovernanceMessage::SetCooldown(config) => {
+                bytes.push(SET_COOLDOWN_TAG);
+                bytes.extend_from_slice(&config.to_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+fn parse_public_key_hash(bytes: &[u8]) -> Result<PublicKeyHash> {
+    let public_key_hash =
+        std::str::from_utf8(bytes).map_err(|_| Error::InvalidGovernanceMessage)?;
+    PublicKeyHash::from_b58(public_key_hash)
+}
+
+fn parse_public_key(bytes: &[u8]) -> Result<PublicKey> {
+    let public_key = std::str::from_utf8(bytes).map_err(|_| Error::InvalidGovernanceMessage)?;
+    PublicKey::from_b58(public_key).map_err(|_| Error::InvalidGovernanceMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GovernanceMessage;
+    use crate::cooldown::CooldownConfig;
+    use crate::event::EventWindow;
+    use crate::public_key::PublicKey;
+    use crate::public_key_hash::PublicKeyHash;
+    use crate::snapshot::SnapshotDestination;
+
+    #[test]
+    fn test_legacy_upgrade() {
+        let mut root_hash = [7; 33];
+        root_hash[0] = 0x00;
+        let message = GovernanceMessage::parse(&root_hash).unwrap();
+        assert_eq!(message, GovernanceMessage::UpgradeKernel(root_hash));
+
+        // Only root hashes are legacy upgrades
+        assert!(GovernanceMessage::parse(&root_hash[..32]).is_err());
+        root_hash[0] = 0x01;
+        assert!(GovernanceMessage::parse(&root_hash).is_err());
+    }
+
+    #[test]
+    fn test_malformed_message_is_not_an_upgrade() {
+        // A tagged message as long as a root hash
+        let mut bytes = vec![0x01, 0x01];
+        bytes.extend_from_slice(&[b'x'; 31]);
+        assert_eq!(bytes.len(), 33);
+        assert!(GovernanceMessage::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_governance_message_round_trip() {
+        let tz1 = PublicKeyHash::from_b58("tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv").unwrap();
+        let pkey =
+            PublicKey::from_b58("edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK").unwrap();
+        let messages = vec![
+            GovernanceMessage::UpgradeKernel([7; 33]),
+            GovernanceMessage::AllowlistAdd(tz1.clone()),
+            GovernanceMessage::AllowlistRemove(tz1),
+            GovernanceMessage::EnableAllowlist(true),
+            GovernanceMessage::EnableAllowlist(false),
+            GovernanceMessage::AddSequencer(pkey.clone()),
+            GovernanceMessage::RemoveSequencer(pkey),
+            GovernanceMessage::SetEventWindow(EventWindow {
+                start: Some(1_700_000_000),
+                end: None,
+            }),
+            GovernanceMessage::Freeze,
+            GovernanceMessage::SetSnapshotDestination(
+                SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint").unwrap(),
+            ),
+            GovernanceMessage::SetCooldown(CooldownConfig {
+                max_pixels: 10,
+                levels: 2,
+            }),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(GovernanceMessage::parse(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_invalid_governance_message() {
+        assert!(GovernanceMessage::parse(&[0x01, 0x03, 0x02]).is_err());
+        assert!(GovernanceMessage::parse(&[0x01, 0x01, 0xff]).is_err());
+        assert!(GovernanceMessage::parse(&[0x01, 0x42]).is_err());
+        assert!(GovernanceMessage::parse(&[0x01, 0x09, 0x00, 0x01]).is_err());
+        assert!(GovernanceMessage::parse(&[0x03, 0x00]).is_err());
+        assert!(GovernanceMessage::parse(&[]).is_err());
+    }
+}
@@ -1,12 +1,20 @@
 pub mod account;
+pub mod canvas;
+pub mod cooldown;
 pub mod error;
+pub mod event;
+pub mod governance;
 pub mod hash;
 pub mod message;
 pub mod nonce;
+pub mod pixel;
 pub mod public_key;
 pub mod public_key_hash;
 pub mod receipt;
 pub mod signature;
+pub mod snapshot;
+pub mod transition;
 pub mod place;
 pub mod dac;
+pub mod framing;
 pub mod constants;
\ No newline at end of file
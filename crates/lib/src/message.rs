@@ -1,12 +1,36 @@
 use crate::constants::MAGIC_BYTE;
+use crate::error::{Error, Result};
 use crate::hash::Blake2b;
 use crate::nonce::Nonce;
 use crate::public_key::PublicKey;
-use crate::signature::Signature;
+use crate::signature::{
+    micheline_payload, verify_batch, Signature, ED25519_SIGNATURE_SIZE, SIGNED_MESSAGE_PREFIX,
+};
+use nom::{
+    bytes::complete::take,
+    combinator::map,
+    error::{ErrorKind, ParseError},
+    number::complete::{be_u32, be_u64, be_u8},
+    sequence::tuple,
+};
 use serde::{Deserialize, Serialize};
 use tezos_crypto_rs::hash::HashTrait;
-use tezos_data_encoding::enc::BinWriter;
+use tezos_data_encoding::enc::{BinResult, BinWriter};
+use tezos_data_encoding::nom::{NomReader, NomResult};
 use tezos_smart_rollup::core_unsafe::PREIMAGE_HASH_SIZE;
+use tezos_smart_rollup_encoding::smart_rollup::SmartRollupAddress;
+
+/// Version of the binary encoding of the user messages whose inner hash is signed directly
+pub const RAW_USER_MESSAGE_VERSION: u8 = 1;
+
+/// Version of the binary encoding of the user messages signed by a wallet
+pub const MICHELINE_USER_MESSAGE_VERSION: u8 = 2;
+
+/// Text signed by wallets, followed by the hex of the hash of the inner
+pub const MICHELINE_SIGNED_TEXT: &str = "tezos-place ";
+
+/// Tag of the PlacePixel content in the binary encoding
+const PLACE_PIXEL_TAG: u8 = 0;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct PlacePixel {
@@ -27,14 +51,59 @@ pub struct Inner {
 }
 
 impl Inner {
+    pub fn new(nonce: Nonce, content: Content) -> Self {
+        Inner { nonce, content }
+    }
+
     /// Returns the nonce of the inner
     pub fn nonce(&self) -> &Nonce {
         &self.nonce
     }
+
+    /// Verify that the nonce of the inner is the next nonce of an account
+    ///
+    /// Otherwise the message is a replay (or is out of order) and is rejected.
+    pub fn verify_nonce(&self, nonce: &Nonce) -> Result<()> {
+        if self.nonce == nonce.next() {
+            Ok(())
+        } else {
+            Err(Error::InvalidNonce)
+        }
+    }
+}
+
+/// What is signed by the user, selected by the version of the message
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SigningScheme {
+    /// The hash of the inner is signed
+    #[default]
+    Raw,
+    /// The hash of the inner is signed by a wallet, as a Micheline packed string:
+    /// "Tezos Signed Message: tezos-place <hex of the hash of the inner>"
+    Micheline,
+}
+
+impl SigningScheme {
+    fn version(&self) -> u8 {
+        match self {
+            SigningScheme::Raw => RAW_USER_MESSAGE_VERSION,
+            SigningScheme::Micheline => MICHELINE_USER_MESSAGE_VERSION,
+        }
+    }
+
+    fn from_version(version: u8) -> Option<Self> {
+        match version {
+            RAW_USER_MESSAGE_VERSION => Some(SigningScheme::Raw),
+            MICHELINE_USER_MESSAGE_VERSION => Some(SigningScheme::Micheline),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct UserMessage {
+    #[serde(default)]
+    scheme: SigningScheme,
     pkey: PublicKey,
     signature: Signature,
     pub inner: Inner,
@@ -56,14 +125,48 @@ impl UserMessage {
         &self.inner
     }
 
-    /// Returns the hash of the message
+    /// Returns the hash of the signed message, the hash of its binary encoding
+    ///
+    /// Unlike the hash of the inner, it differs for each signature of the same inner
     pub fn hash(&self) -> Blake2b {
-        self.inner.hash()
+        Blake2b::from(&self.to_bytes())
+    }
+
+    /// Returns how the message is signed
+    pub fn scheme(&self) -> SigningScheme {
+        self.scheme
+    }
+
+    /// Data signed by the user, according to the signing scheme
+    pub fn signed_data(&self) -> Vec<u8> {
+        let hash = self.inner.hash();
+        match self.scheme {
+            SigningScheme::Raw => hash.as_ref().to_vec(),
+            SigningScheme::Micheline => micheline_payload(&micheline_signed_message(&hash)),
+        }
+    }
+
+    /// Verifies the signature of the inner, according to the signing scheme
+    pub fn verify_signature(&self) -> Result<()> {
+        self.signature.verify(&self.pkey, &self.signed_data())
     }
 
     pub fn new(skey: ed25519_compact::SecretKey, inner: Inner) -> Self {
-        let data_to_hash = inner.hash();
-        let data_to_hash = Blake2b::from(data_to_hash.as_ref());
+        UserMessage::new_with_scheme(skey, inner, SigningScheme::Raw)
+    }
+
+    pub fn new_with_scheme(
+        skey: ed25519_compact::SecretKey,
+        inner: Inner,
+        scheme: SigningScheme,
+    ) -> Self {
+        let hash = inner.hash();
+        let data_to_hash = match scheme {
+            SigningScheme::Raw => Blake2b::from(hash.as_ref()),
+            SigningScheme::Micheline => {
+                Blake2b::from(&micheline_payload(&micheline_signed_message(&hash)))
+            }
+        };
         let signature = skey.sign(data_to_hash, None);
         let signature = signature.as_ref();
         let signature = tezos_crypto_rs::hash::Ed25519Signature::try_from_bytes(signature).unwrap();
@@ -73,64 +176,427 @@ impl UserMessage {
         let pkey = tezos_crypto_rs::hash::PublicKeyEd25519::try_from_bytes(pkey).unwrap();
         let pkey = PublicKey::Ed25519(pkey);
         UserMessage {
+            scheme,
             pkey,
             signature,
             inner,
         }
     }
+
+    /// Binary encoding of the message, as stored in the DAC pages
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing in a vector cannot fail
+        self.bin_write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Decodes a message, all the bytes have to be consumed
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match UserMessage::nom_read(bytes) {
+            Ok(([], message)) => Ok(message),
+            _ => Err(Error::InvalidUserMessage),
+        }
+    }
+}
+
+/// Verifies the signatures of several messages at once
+///
+/// If an error is returned, the messages have to be verified one by one to find the invalid ones
+pub fn verify_signatures(messages: &[&UserMessage]) -> Result<()> {
+    let signed_data: Vec<Vec<u8>> = messages.iter().map(|message| message.signed_data()).collect();
+    let signed: Vec<(&Signature, &PublicKey, &[u8])> = messages
+        .iter()
+        .zip(signed_data.iter())
+        .map(|(message, data)| (&message.signature, &message.pkey, data.as_slice()))
+        .collect();
+    verify_batch(&signed)
+}
+
+/// Message signed by a wallet for the given hash of an inner
+pub fn micheline_signed_message(hash: &Blake2b) -> String {
+    format!(
+        "{}{}{}",
+        SIGNED_MESSAGE_PREFIX,
+        MICHELINE_SIGNED_TEXT,
+        hash.to_string()
+    )
 }
 
 impl Inner {
+    /// Binary encoding of the inner, this is what is hashed
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing in a vector cannot fail
+        self.bin_write(&mut bytes).unwrap();
+        bytes
+    }
+
     /// Hash of the message
     /// This hash is what the client should signed
     pub fn hash(&self) -> Blake2b {
-        let json = serde_json_wasm::to_string(self).unwrap();
-        println!("=========== hashing data: {}", json);
-        let hash = Blake2b::from(json.as_bytes());
-        println!("{}", hash.to_string());
-        hash
+        Blake2b::from(&self.to_bytes())
+    }
+}
+
+/// PlacePixel is encoded as x and y (u32, big endian) followed by the color
+impl BinWriter for PlacePixel {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        output.extend_from_slice(&self.x.to_be_bytes());
+        output.extend_from_slice(&self.y.to_be_bytes());
+        output.extend_from_slice(&self.color);
+        Ok(())
     }
 }
 
-#[derive(Deserialize, Serialize)]
+impl NomReader for PlacePixel {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        map(
+            tuple((be_u32, be_u32, take(3_usize))),
+            |(x, y, color): (u32, u32, &[u8])| PlacePixel {
+                x,
+                y,
+                color: [color[0], color[1], color[2]],
+            },
+        )(input)
+    }
+}
+
+/// Content is encoded as a tag followed by the content
+impl BinWriter for Content {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        match self {
+            Content::PlacePixel(pixel) => {
+                output.push(PLACE_PIXEL_TAG);
+                pixel.bin_write(output)
+            }
+        }
+    }
+}
+
+impl NomReader for Content {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        let (input, tag) = be_u8(input)?;
+        match tag {
+            PLACE_PIXEL_TAG => map(PlacePixel::nom_read, Content::PlacePixel)(input),
+            _ => Err(nom::Err::Error(ParseError::from_error_kind(
+                input,
+                ErrorKind::Tag,
+            ))),
+        }
+    }
+}
+
+/// Inner is encoded as the nonce (u64, big endian) followed by the content
+impl BinWriter for Inner {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        output.extend_from_slice(&self.nonce.0.to_be_bytes());
+        self.content.bin_write(output)
+    }
+}
+
+impl NomReader for Inner {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        map(tuple((be_u64, Content::nom_read)), |(nonce, content)| Inner {
+            nonce: Nonce(nonce),
+            content,
+        })(input)
+    }
+}
+
+/// UserMessage is encoded as the version of the encoding (which selects the signing scheme),
+/// the public key, the signature and the inner
+impl BinWriter for UserMessage {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        output.push(self.scheme.version());
+        self.pkey.bin_write(output)?;
+        self.signature.bin_write(output)?;
+        self.inner.bin_write(output)
+    }
+}
+
+impl NomReader for UserMessage {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        let (input, version) = be_u8(input)?;
+        let scheme = match SigningScheme::from_version(version) {
+            Some(scheme) => scheme,
+            None => {
+                return Err(nom::Err::Error(ParseError::from_error_kind(
+                    input,
+                    ErrorKind::Tag,
+                )))
+            }
+        };
+        map(
+            tuple((PublicKey::nom_read, Signature::nom_read, Inner::nom_read)),
+            move |(pkey, signature, inner)| UserMessage {
+                scheme,
+                pkey,
+                signature,
+                inner,
+            },
+        )(input)
+    }
+}
+
+/// Version of the batch header format
+pub const BATCH_VERSION: u8 = 3;
+
+/// Size of an encoded batch header
+pub const BATCH_HEADER_SIZE: usize = 1 + 8 + 4 + PREIMAGE_HASH_SIZE + PREIMAGE_HASH_SIZE;
+
+/// Size of an encoded batch message, without the magic byte
+pub const BATCH_MESSAGE_SIZE: usize = BATCH_HEADER_SIZE + ED25519_SIGNATURE_SIZE;
+
+/// Header of a batch of user messages, signed by the sequencer
+///
+/// Batches are chained: each header refers to the merkle root of the previous batch,
+/// so the kernel can detect missing, reordered or duplicated batches.
+/// The level is the one the sequencer built the batch for, it never decreases from one batch
+/// to the next and cannot exceed the level at which the batch is included in the inbox.
+///
+/// Roots are full preimage hashes, including the tag of the hashing scheme.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchHeader {
+    pub version: u8,
+    /// Index of the batch, starting at 0
+    pub index: u64,
+    /// L1 level the batch was built for
+    pub level: u32,
+    /// Merkle root of the previous batch, zeros for the first batch
+    pub previous_root: [u8; PREIMAGE_HASH_SIZE],
+    /// Merkle root of the DAC pages of the batch
+    pub merkle_root: [u8; PREIMAGE_HASH_SIZE],
+}
+
+impl BatchHeader {
+    /// Header of the first batch of the sequencer
+    pub fn first(merkle_root: [u8; PREIMAGE_HASH_SIZE], level: u32) -> Self {
+        BatchHeader {
+            version: BATCH_VERSION,
+            index: 0,
+            level,
+            previous_root: [0; PREIMAGE_HASH_SIZE],
+            merkle_root,
+        }
+    }
+
+    /// Header of the batch following this one
+    pub fn next(&self, merkle_root: [u8; PREIMAGE_HASH_SIZE], level: u32) -> Self {
+        BatchHeader {
+            version: BATCH_VERSION,
+            index: self.index + 1,
+            level,
+            previous_root: self.merkle_root,
+            merkle_root,
+        }
+    }
+
+    /// Encodes the header: version, index and level (big endian), previous root and root
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version];
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.level.to_be_bytes());
+        bytes.extend_from_slice(&self.previous_root);
+        bytes.extend_from_slice(&self.merkle_root);
+        bytes
+    }
+
+    /// Decodes a header encoded by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != BATCH_HEADER_SIZE {
+            return Err(Error::InvalidBatchMessage);
+        }
+        let (version, bytes) = bytes.split_at(1);
+        let (index, bytes) = bytes.split_at(8);
+        let (level, bytes) = bytes.split_at(4);
+        let (previous_root, merkle_root) = bytes.split_at(PREIMAGE_HASH_SIZE);
+        Ok(BatchHeader {
+            version: version[0],
+            index: u64::from_be_bytes(index.try_into().map_err(|_| Error::InvalidBatchMessage)?),
+            level: u32::from_be_bytes(level.try_into().map_err(|_| Error::InvalidBatchMessage)?),
+            previous_root: previous_root.try_into().map_err(|_| Error::InvalidBatchMessage)?,
+            merkle_root: merkle_root.try_into().map_err(|_| Error::InvalidBatchMessage)?,
+        })
+    }
+}
+
+/// Batch of user messages, posted by the sequencer in the inbox
+///
+/// The signature covers the magic byte and the address of the rollup,
+/// so a batch cannot be replayed on another rollup.
+#[derive(Debug)]
 pub struct Message {
     pub signature: Signature,
-    // FIXME: this is wrong. I should just figureo ut how to serizlie the 33 bytes.
-    // I should also inlcude the magic byte 
-    pub unprefixed_merkle_root: [u8; (PREIMAGE_HASH_SIZE - 1)],
+    pub header: BatchHeader,
 }
+
 impl Message {
     pub fn new(
         skey: ed25519_compact::SecretKey,
-        unprefixed_merkle_root: [u8; (PREIMAGE_HASH_SIZE - 1)],
+        rollup_address: &[u8; 20],
+        header: BatchHeader,
     ) -> Self {
-        let data_to_sign = Blake2b::from(unprefixed_merkle_root.as_ref());
-        // FIXME: signing this is wrong. See above comment.
+        let data_to_sign = Blake2b::from(&Message::signed_bytes(rollup_address, &header));
         let signature = skey.sign(data_to_sign, None);
         let signature = signature.as_ref();
         let signature = tezos_crypto_rs::hash::Ed25519Signature::try_from_bytes(signature).unwrap();
         let signature = Signature::Ed25519(signature);
-        Message {
-            signature,
-            unprefixed_merkle_root,
+        Message { signature, header }
+    }
+
+    /// Bytes signed by the sequencer
+    pub fn signed_bytes(rollup_address: &[u8; 20], header: &BatchHeader) -> Vec<u8> {
+        let mut bytes = vec![MAGIC_BYTE];
+        bytes.extend_from_slice(rollup_address);
+        bytes.extend_from_slice(&header.to_bytes());
+        bytes
+    }
+
+    /// Encodes the message as an external message: magic byte, header and signature
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![MAGIC_BYTE];
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    /// Decodes a message, the magic byte being already removed
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != BATCH_MESSAGE_SIZE {
+            return Err(Error::InvalidBatchMessage);
         }
+        let (header, signature) = bytes.split_at(BATCH_HEADER_SIZE);
+        let header = BatchHeader::from_bytes(header)?;
+        let signature = Signature::from_ed25519_bytes(signature)?;
+        Ok(Message { signature, header })
     }
 }
 
+/// Decodes a b58 rollup address (sr1...) into the raw address signed by the sequencer
+pub fn raw_rollup_address(address: &str) -> Result<[u8; 20]> {
+    let address =
+        SmartRollupAddress::from_b58check(address).map_err(|_| Error::InvalidRollupAddress)?;
+    address
+        .hash()
+        .0
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::InvalidRollupAddress)
+}
+
 impl BinWriter for Message {
-    fn bin_write(&self, output: &mut Vec<u8>) -> tezos_data_encoding::enc::BinResult {
-        let bytes: Vec<u8> = serde_json_wasm::to_vec(&self).unwrap();
-        output.extend_from_slice(&[MAGIC_BYTE]);
-        output.extend_from_slice(&bytes);
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        output.extend_from_slice(&self.to_bytes());
         Ok(())
     }
 }
 
+/// Header of a batch, as logged by the sequencer before the binary encoding
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LegacyBatchHeader {
+    pub version: u8,
+    pub index: u64,
+    pub previous_root: [u8; (PREIMAGE_HASH_SIZE - 1)],
+    pub unprefixed_merkle_root: [u8; (PREIMAGE_HASH_SIZE - 1)],
+}
+
+/// Batch message, as logged by the sequencer before the binary encoding
+///
+/// The first logs only contain the root of the batch,
+/// later ones contain a chained header.
+/// In both cases the root is a reveal hash without its tag.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LegacyMessage {
+    pub signature: Signature,
+    #[serde(default)]
+    pub header: Option<LegacyBatchHeader>,
+    #[serde(default)]
+    pub unprefixed_merkle_root: Option<[u8; (PREIMAGE_HASH_SIZE - 1)]>,
+}
+
+/// Adds the tag of the reveal hashing scheme to an unprefixed root
+fn prefix_root(unprefixed_root: &[u8; (PREIMAGE_HASH_SIZE - 1)]) -> [u8; PREIMAGE_HASH_SIZE] {
+    let mut root = [0; PREIMAGE_HASH_SIZE];
+    root[1..].copy_from_slice(unprefixed_root);
+    root
+}
+
+impl LegacyMessage {
+    /// Full preimage hash of the root of the batch
+    pub fn merkle_root(&self) -> Option<[u8; PREIMAGE_HASH_SIZE]> {
+        match (&self.header, &self.unprefixed_merkle_root) {
+            (Some(header), _) => Some(prefix_root(&header.unprefixed_merkle_root)),
+            (None, Some(root)) => Some(prefix_root(root)),
+            (None, None) => None,
+        }
+    }
+
+    /// Chained header of the batch, converted to the current format
+    ///
+    /// Batches logged before the headers were introduced are not chained,
+    /// the level of the legacy batches is unknown and set to 0.
+    pub fn header(&self) -> Option<BatchHeader> {
+        let header = self.header.as_ref()?;
+        let previous_root = if header.previous_root == [0; (PREIMAGE_HASH_SIZE - 1)] {
+            [0; PREIMAGE_HASH_SIZE]
+        } else {
+            prefix_root(&header.previous_root)
+        };
+        Some(BatchHeader {
+            version: BATCH_VERSION,
+            index: header.index,
+            level: 0,
+            previous_root,
+            merkle_root: prefix_root(&header.unprefixed_merkle_root),
+        })
+    }
+}
+
+/// A line of the log of the sequencer
+///
+/// Current logs contain the hex encoding of the external message,
+/// older logs contain the JSON encoding of the message.
+#[derive(Debug)]
+pub enum LoggedMessage {
+    Legacy(LegacyMessage),
+    Batch(Message),
+}
+
+impl LoggedMessage {
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim();
+        if let Ok(bytes) = hex::decode(line) {
+            return match bytes.split_first() {
+                Some((&MAGIC_BYTE, bytes)) => Message::parse(bytes).map(LoggedMessage::Batch),
+                _ => Err(Error::InvalidBatchMessage),
+            };
+        }
+        let message = serde_json_wasm::from_str(line)?;
+        Ok(LoggedMessage::Legacy(message))
+    }
+
+    /// Header of the logged batch, if it is chained
+    pub fn header(&self) -> Option<BatchHeader> {
+        match self {
+            LoggedMessage::Legacy(message) => message.header(),
+            LoggedMessage::Batch(message) => Some(message.header.clone()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tezos_crypto_rs::hash::HashTrait;
 
-    use super::{Content, Inner, PlacePixel};
+    use tezos_data_encoding::enc::BinWriter;
+
+    use super::{
+        micheline_signed_message, raw_rollup_address, verify_signatures, BatchHeader, Content,
+        Inner, LoggedMessage, Message, PlacePixel, SigningScheme, BATCH_MESSAGE_SIZE,
+    };
+    use crate::constants::MAGIC_BYTE;
+    use crate::public_key::PublicKey;
     use crate::{hash::Blake2b, message::UserMessage, nonce::Nonce};
 
     #[test]
@@ -184,11 +650,12 @@ mod tests {
         let message = UserMessage::new(sk, inner);
         insta::assert_json_snapshot!(message, @r###"
         {
+          "scheme": "Raw",
           "pkey": {
             "Ed25519": "edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB"
           },
           "signature": {
-            "Ed25519": "edsigtpxbt1mWVGykfTE2D87DybgTY7PmvB4Nhg7N3Xuof6DsvGNwNVsXkWa65SLMsvQfav9FwxcEfnZPCvQiWgUnNFjxvCFwDs"
+            "Ed25519": "edsigu2oaBP5DGR6afA6622uWCiZdBe4DbBUErownJrD6Xp2W1M7mx7vkq6bY5eKhGZ6yEVB9rVKkFVrKUdgAjGtL1WZRLq6Brh"
           },
           "inner": {
             "nonce": 777,
@@ -210,4 +677,220 @@ mod tests {
         let message_str = r#"{"pkey":{"Ed25519":"edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB"},"signature":{"Ed25519":"edsigtrE8dQEskw8KQsbZuCGaFBtcTr2NiYeEWKvvuRnJE53fzA3njuCUnyX6JWJbCKz8aT8HgHJjAYfw8ryLPKAQ2Mjn4rc4LL"},"inner":{"nonce":777,"content":{"PlacePixel":{"x":227,"y":357,"color": [0, 1, 2]}}}} "#;
         let _message: UserMessage = serde_json_wasm::from_str(&message_str).unwrap();
     }
+
+    /// Secret key of edpktfpdouHjAze9TeFcihdpeMng7FSCWbY4BozpSffZ9z85nyyBBB
+    fn secret_key() -> ed25519_compact::SecretKey {
+        let seed = ed25519_compact::Seed::new([
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31,
+        ]);
+        ed25519_compact::KeyPair::from_seed(seed).sk
+    }
+
+    fn place_pixel(nonce: u64, x: u32, y: u32, color: [u8; 3]) -> Inner {
+        Inner::new(
+            Nonce(nonce),
+            Content::PlacePixel(PlacePixel { x, y, color }),
+        )
+    }
+
+    #[test]
+    fn test_user_message_golden_vectors() {
+        // (inner, encoded inner, hash of the inner, encoded message)
+        let vectors = [
+            (
+                place_pixel(777, 1, 2, [1, 2, 3]),
+                "0000000000000309000000000100000002010203",
+                "87c3a645e23a216715f067072914ea1691a985cbaea23f11963c0b78e7e6d11f",
+                "010003a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b800dda3a8e94f08375ed548563a1885d374de721add789239c15327df30102fc4ae4a302712c665e437690486f5c8b635c15ca8b6ac603ea91f72c575574b5c59060000000000000309000000000100000002010203",
+            ),
+            (
+                place_pixel(1, 4, 5, [255, 0, 0]),
+                "0000000000000001000000000400000005ff0000",
+                "e8ea6d1cede72767b635bac930bbce8793ee7591afe4edb267a4c5f5a166b58d",
+                "010003a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b800e3e7e674296ee3b751f9c35838e5c5802e1906d9877b8cc344aadc55b29baa12326b522eda3995d2de3cde63b68416d4fae3c9297b0491638439fd481feb71060000000000000001000000000400000005ff0000",
+            ),
+        ];
+
+        for (inner, inner_hex, hash, message_hex) in vectors {
+            assert_eq!(hex::encode(inner.to_bytes()), inner_hex);
+            assert_eq!(inner.hash().to_string(), hash);
+
+            let message = UserMessage::new(secret_key(), inner);
+            let bytes = message.to_bytes();
+            assert_eq!(hex::encode(&bytes), message_hex);
+
+            let decoded = UserMessage::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.to_bytes(), bytes);
+            assert_eq!(decoded.hash().to_string(), Blake2b::from(&bytes).to_string());
+            assert!(decoded.verify_signature().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_micheline_signed_user_message() {
+        let inner = place_pixel(1, 4, 5, [255, 0, 0]);
+        assert_eq!(
+            micheline_signed_message(&inner.hash()),
+            "Tezos Signed Message: tezos-place e8ea6d1cede72767b635bac930bbce8793ee7591afe4edb267a4c5f5a166b58d"
+        );
+
+        let message = UserMessage::new_with_scheme(secret_key(), inner, SigningScheme::Micheline);
+        let bytes = message.to_bytes();
+        assert_eq!(
+            hex::encode(&bytes),
+            "020003a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b800d3ef6718a115ae4cafbe7383b6fa60bed48a18fb0ccdcd2060641389044881a7abd57fc021bd9ca8bc1ec27e22b0a485e632d4988dae8cc85ddd5517e7e2a5000000000000000001000000000400000005ff0000"
+        );
+        assert_eq!(
+            message.signature().to_b58(),
+            "edsigu1XwKnpUeKXF8gZebBfMM7wwUhk7rfeaSmeG4MBA7PaiK9fVL3VdvH9VjvyLG9Ga5Gv5FcVfHaRhBF7JCQGnpzS8UZBEsq"
+        );
+
+        let decoded = UserMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.scheme(), SigningScheme::Micheline);
+        assert!(decoded.verify_signature().is_ok());
+
+        // The same signature does not hold in the raw scheme
+        let mut raw = bytes.clone();
+        raw[0] = 1;
+        let raw = UserMessage::from_bytes(&raw).unwrap();
+        assert_eq!(raw.scheme(), SigningScheme::Raw);
+        assert!(raw.verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_signatures_are_verified_in_batch() {
+        let messages: Vec<UserMessage> = (0..10)
+            .map(|i| {
+                let scheme = match i % 2 {
+                    0 => SigningScheme::Raw,
+                    _ => SigningScheme::Micheline,
+                };
+                UserMessage::new_with_scheme(secret_key(), place_pixel(i, 4, 5, [0, 0, 0]), scheme)
+            })
+            .collect();
+        let references: Vec<&UserMessage> = messages.iter().collect();
+        assert!(verify_signatures(&references).is_ok());
+
+        let mut bytes = messages[3].to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] = 1;
+        let forged = UserMessage::from_bytes(&bytes).unwrap();
+        let mut references = references;
+        references.push(&forged);
+        assert!(verify_signatures(&references).is_err());
+    }
+
+    #[test]
+    fn test_invalid_user_messages_are_rejected() {
+        let bytes = UserMessage::new(secret_key(), place_pixel(1, 4, 5, [255, 0, 0])).to_bytes();
+        assert!(UserMessage::from_bytes(&bytes).is_ok());
+
+        // Unknown version
+        let mut unknown_version = bytes.clone();
+        unknown_version[0] = 0;
+        assert!(UserMessage::from_bytes(&unknown_version).is_err());
+
+        // Unknown content
+        let mut unknown_content = bytes.clone();
+        unknown_content[bytes.len() - 12] = 1;
+        assert!(UserMessage::from_bytes(&unknown_content).is_err());
+
+        // Trailing and missing bytes
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(UserMessage::from_bytes(&trailing).is_err());
+        assert!(UserMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // JSON messages are not accepted anymore
+        let json = serde_json_wasm::to_vec(&UserMessage::from_bytes(&bytes).unwrap()).unwrap();
+        assert!(UserMessage::from_bytes(&json).is_err());
+    }
+
+    #[test]
+    fn test_batch_headers_are_chained() {
+        let first = BatchHeader::first([1; 33], 10);
+        let second = first.next([2; 33], 12);
+
+        assert_eq!(first.index, 0);
+        assert_eq!(first.previous_root, [0; 33]);
+        assert_eq!(second.index, 1);
+        assert_eq!(second.level, 12);
+        assert_eq!(second.previous_root, [1; 33]);
+        assert_eq!(second.merkle_root, [2; 33]);
+
+        let bytes = second.to_bytes();
+        assert_eq!(bytes.len(), 1 + 8 + 4 + 33 + 33);
+        assert_eq!(&bytes[..13], &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 12]);
+        assert_eq!(BatchHeader::from_bytes(&bytes).unwrap(), second);
+    }
+
+    #[test]
+    fn test_batch_message_round_trip() {
+        let seed = ed25519_compact::Seed::new([7; 32]);
+        let ed25519_compact::KeyPair { pk, sk } = ed25519_compact::KeyPair::from_seed(seed);
+        let pkey = tezos_crypto_rs::hash::PublicKeyEd25519::try_from_bytes(pk.as_ref()).unwrap();
+        let pkey = PublicKey::Ed25519(pkey);
+        let rollup_address = [3; 20];
+
+        let mut root = [0; 33];
+        root[1..].copy_from_slice(&[9; 32]);
+        let message = Message::new(sk, &rollup_address, BatchHeader::first(root, 1));
+
+        let mut bytes = Vec::new();
+        message.bin_write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 1 + BATCH_MESSAGE_SIZE);
+        assert_eq!(bytes[0], MAGIC_BYTE);
+
+        let decoded = Message::parse(&bytes[1..]).unwrap();
+        assert_eq!(decoded.header, message.header);
+        assert_eq!(decoded.header.merkle_root, root);
+
+        let signed = Message::signed_bytes(&rollup_address, &decoded.header);
+        assert!(decoded.signature.verify(&pkey, &signed).is_ok());
+
+        // The signature does not hold for another rollup
+        let other = Message::signed_bytes(&[4; 20], &decoded.header);
+        assert!(decoded.signature.verify(&pkey, &other).is_err());
+
+        assert!(Message::parse(&bytes[2..]).is_err());
+    }
+
+    #[test]
+    fn test_raw_rollup_address() {
+        let address = raw_rollup_address("sr1V6huFSUBUujzubUCg9nNXqpzfG9t4XD1h").unwrap();
+        assert_eq!(address.len(), 20);
+        assert!(raw_rollup_address("tz1").is_err());
+    }
+
+    #[test]
+    fn test_logged_messages_are_decoded() {
+        let seed = ed25519_compact::Seed::new([7; 32]);
+        let sk = ed25519_compact::KeyPair::from_seed(seed).sk;
+        let header = BatchHeader::first([5; 33], 1).next([6; 33], 2);
+        let message = Message::new(sk, &[3; 20], header.clone());
+        let logged = LoggedMessage::parse(&hex::encode(message.to_bytes())).unwrap();
+        assert_eq!(logged.header(), Some(header));
+
+        // Log written before the batch headers
+        let legacy = r#"{"signature":{"Ed25519":"edsigtyGakLbDznj2PsFNdmVLUokrxqegTzGgD3nxLyUDGssgziCN2Y6FCV1WH21Bcwsy5N7eYNmPfHX43BTTCQDguLhunCPGak"},"unprefixed_merkle_root":[193,127,226,118,2,139,173,43,116,243,218,43,159,159,176,98,136,227,62,115,116,169,161,235,221,42,10,36,208,15,139,84]}"#;
+        let LoggedMessage::Legacy(legacy) = LoggedMessage::parse(legacy).unwrap() else {
+            panic!("expected a legacy message");
+        };
+        let root = legacy.merkle_root().unwrap();
+        assert_eq!(root[0], 0);
+        assert_eq!(&root[1..3], &[193, 127]);
+        assert!(legacy.header().is_none());
+
+        // Log written with the first version of the batch headers
+        let chained = format!(
+            r#"{{"signature":{{"Ed25519":"edsigtyGakLbDznj2PsFNdmVLUokrxqegTzGgD3nxLyUDGssgziCN2Y6FCV1WH21Bcwsy5N7eYNmPfHX43BTTCQDguLhunCPGak"}},"header":{{"version":1,"index":3,"previous_root":{:?},"unprefixed_merkle_root":{:?}}}}}"#,
+            [1u8; 32], [2u8; 32]
+        );
+        let header = LoggedMessage::parse(&chained).unwrap().header().unwrap();
+        assert_eq!(header.index, 3);
+        assert_eq!(header.previous_root[0], 0);
+        assert_eq!(&header.previous_root[1..], &[1; 32]);
+        assert_eq!(&header.merkle_root[1..], &[2; 32]);
+    }
 }
@@ -0,0 +1,67 @@
+use crate::error::*;
+use crate::public_key_hash::{PublicKeyHash, PUBLIC_KEY_HASH_SIZE};
+
+/// Size of an encoded record, all the records have the same size
+pub const PIXEL_RECORD_SIZE: usize = 8 + 4 + 3 + PUBLIC_KEY_HASH_SIZE;
+
+/// A pixel painted on the canvas, with who painted it and when
+#[derive(Debug, PartialEq, Clone)]
+pub struct PixelRecord {
+    pub color: [u8; 3],
+    pub owner: PublicKeyHash,
+    pub level: u32,
+    /// Number of times the pixel has been painted, including this one
+    pub version: u64,
+}
+
+impl PixelRecord {
+    /// Encodes the record as: version (8 bytes) ++ level (4 bytes) ++ color (3 bytes) ++ owner (21 bytes)
+    ///
+    /// The fixed size lets the records be written in place in the history of a pixel
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PIXEL_RECORD_SIZE);
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.level.to_be_bytes());
+        bytes.extend_from_slice(&self.color);
+        bytes.extend_from_slice(&self.owner.to_bytes());
+        bytes
+    }
+
+    /// Decodes a record encoded with [`PixelRecord::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != PIXEL_RECORD_SIZE {
+            return Err(Error::StateDeserializarion);
+        }
+        let (version, bytes) = bytes.split_at(8);
+        let (level, bytes) = bytes.split_at(4);
+        let (color, owner) = bytes.split_at(3);
+
+        Ok(PixelRecord {
+            color: [color[0], color[1], color[2]],
+            owner: PublicKeyHash::from_bytes(owner)?,
+            level: u32::from_be_bytes(level.try_into().unwrap()),
+            version: u64::from_be_bytes(version.try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PixelRecord, PIXEL_RECORD_SIZE};
+    use crate::public_key_hash::PublicKeyHash;
+
+    #[test]
+    fn test_pixel_record_encoding() {
+        let record = PixelRecord {
+            color: [1, 2, 3],
+            owner: PublicKeyHash::from_b58("tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv").unwrap(),
+            level: 42,
+            version: 7,
+        };
+        let bytes = record.to_bytes();
+
+        assert_eq!(bytes.len(), PIXEL_RECORD_SIZE);
+        assert_eq!(PixelRecord::from_bytes(&bytes).unwrap(), record);
+        assert!(PixelRecord::from_bytes(&bytes[..PIXEL_RECORD_SIZE - 1]).is_err());
+    }
+}
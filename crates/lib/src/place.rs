@@ -1,36 +1,140 @@
 use image::png::PngEncoder;
 use image::{GenericImage, GenericImageView, Rgb, DynamicImage};
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
 use std::path::PathBuf;
 
-use crate::message::{Content, PlacePixel, UserMessage};
+use crate::canvas::{chunks_per_row, CanvasConfig, BLANK_PIXEL, CHUNK_WIDTH};
+use crate::cooldown::{Cooldown, CooldownConfig};
+use crate::error::*;
+use crate::event::EventWindow;
+use crate::message::{PlacePixel, UserMessage};
+use crate::nonce::Nonce;
+use crate::public_key_hash::PublicKeyHash;
+use crate::transition::{self, Storage};
+
+/// Configuration of the rollup the transitions depend on, read by the sequencer from the rollup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RollupConfig {
+    /// Accounts of the allowlist, indexed by their tz1, or None if the allowlist is disabled
+    pub allowlist: Option<HashSet<String>>,
+    pub frozen: bool,
+    pub event_window: EventWindow,
+    /// L1 timestamp of the last level processed by the kernel
+    pub level_timestamp: Option<i64>,
+    pub canvas: CanvasConfig,
+    pub cooldown: CooldownConfig,
+}
+
+/// State of the accounts and of the canvas of the rollup, read by the sequencer at startup
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RollupState {
+    /// Nonce of each account, indexed by their tz1
+    pub nonces: HashMap<String, Nonce>,
+    /// Cooldown of each account, indexed by their tz1
+    pub cooldowns: HashMap<String, Cooldown>,
+    /// RGB bytes of the chunks of the canvas which have been written, indexed by row and chunk
+    pub chunks: HashMap<(u32, u32), Vec<u8>>,
+}
 
 #[derive(Debug)]
 pub struct PlaceState {
     pub img: image::RgbImage,
     img_buf: Option<Vec<u8>>,
     path: PathBuf,
+    /// Cooldown of each account, indexed by their tz1
+    cooldowns: HashMap<String, Cooldown>,
+    /// Nonce of the last message accepted from each account, indexed by their tz1
+    nonces: HashMap<String, Nonce>,
+    config: RollupConfig,
+    /// Level of L1 the next batch is built for, at which the kernel applies its messages
+    level: u32,
 }
 
 impl PlaceState {
-    // TODO: make this a result
-    pub fn set_pixel(&mut self, message: UserMessage) -> (bool, UserMessage) {
-        let Content::PlacePixel(PlacePixel { x, y, color }) = message.inner().content;
-        println!(
-            "Setting pixel: {}, {}, rgb: {}, {}, {}",
-            x, y, color[0], color[1], color[2]
-        );
-        let color = Rgb([color[0], color[1], color[2]]);
-        let (width, height) = self.img.dimensions();
+    /// Place the pixel of a message on the preview of the canvas
+    ///
+    /// The message goes through the same transition as in the kernel, after its signature.
+    /// A rejected message does not consume the nonce of the account, as it is not batched.
+    pub fn set_pixel(&mut self, message: &UserMessage) -> Result<()> {
+        self.verify_message(message)?;
+        self.apply_message(message)
+    }
 
-        if x < width && y < height {
-            self.img.put_pixel(x, y, color);
-            self.img_buf = None;
-            // self.img.save(self.path.clone()).unwrap();
-            (true, message)
-        } else {
-            (false, message)
+    /// Verify that `set_pixel` would accept a message, without changing the preview
+    pub fn verify_message(&mut self, message: &UserMessage) -> Result<()> {
+        message.verify_signature()?;
+        let public_key_hash = PublicKeyHash::from(message.public_key());
+        let level = self.level;
+        transition::verify(self, &public_key_hash, level, message.inner())
+    }
+
+    /// Place the pixel of a message whose signature is verified by `verify_message`
+    pub fn apply_message(&mut self, message: &UserMessage) -> Result<()> {
+        let public_key_hash = PublicKeyHash::from(message.public_key());
+        let level = self.level;
+        transition::apply(self, &public_key_hash, level, message.inner())
+    }
+
+    /// Nonce of the last message accepted from an account
+    pub fn nonce(&self, public_key_hash: &str) -> Nonce {
+        self.nonces
+            .get(public_key_hash)
+            .map(|nonce| Nonce(nonce.0))
+            .unwrap_or_default()
+    }
+
+    /// Apply a message already batched at the level of its batch, as the kernel does
+    ///
+    /// As in the kernel, the nonce is consumed even if the content is rejected,
+    /// and a message already applied by the kernel is rejected for its nonce.
+    pub fn replay_message(&mut self, message: &UserMessage, level: u32) -> Result<()> {
+        let public_key_hash = PublicKeyHash::from(message.public_key());
+        transition::apply(self, &public_key_hash, level, message.inner())
+    }
+
+    /// Start from the state of the rollup, replacing the accounts and the canvas of the preview
+    ///
+    /// The chunks which have never been written are blank.
+    /// The messages not applied by the kernel yet are replayed on top, see `replay_message`.
+    pub fn restore(&mut self, state: RollupState) {
+        self.nonces = state.nonces;
+        self.cooldowns = state.cooldowns;
+        for pixel in self.img.pixels_mut() {
+            *pixel = Rgb(BLANK_PIXEL);
+        }
+        let (width, height) = self.img.dimensions();
+        for ((y, chunk), bytes) in state.chunks {
+            if y >= height || chunk >= chunks_per_row(width) {
+                continue;
+            }
+            for (index, color) in bytes.chunks_exact(3).enumerate() {
+                let x = chunk * CHUNK_WIDTH + index as u32;
+                if x < width {
+                    self.img.put_pixel(x, y, Rgb([color[0], color[1], color[2]]));
+                }
+            }
         }
+        self.img_buf = None;
+    }
+
+    /// Follow the configuration of the rollup, as set at origination and by the governance
+    pub fn set_rollup_config(&mut self, config: RollupConfig) {
+        self.config = config;
+    }
+
+    /// Follow the head of L1: the next batch is built for the following level,
+    /// the first one it can be included at
+    ///
+    /// The level never decreases, as the kernel rejects a batch built for a lower level
+    /// than the previous one.
+    pub fn set_l1_head(&mut self, head_level: u32) {
+        self.level = self.level.max(head_level + 1);
+    }
+
+    /// Level of L1 the next batch is built for, at which the kernel applies its messages
+    pub fn level(&self) -> u32 {
+        self.level
     }
 
     pub fn save(&mut self)  {
@@ -73,6 +177,238 @@ impl PlaceState {
             img,
             img_buf: None,
             path,
+            cooldowns: HashMap::new(),
+            nonces: HashMap::new(),
+            config: RollupConfig::default(),
+            level: 0,
         }
     }
 }
+
+/// In-memory state of the sequencer
+///
+/// The configuration is the one of the rollup, see `set_rollup_config`.
+/// The messages are judged at the level of their batch, as in the kernel whatever the level
+/// at which the batch is included.
+/// The timestamp is the one of the last level processed by the kernel,
+/// so close to the bounds of the event the kernel may still reject a message accepted here.
+impl Storage for PlaceState {
+    fn is_allowed(&mut self, public_key_hash: &PublicKeyHash) -> Result<bool> {
+        Ok(match &self.config.allowlist {
+            Some(allowlist) => allowlist.contains(&public_key_hash.to_string()),
+            None => true,
+        })
+    }
+
+    fn read_nonce(&mut self, public_key_hash: &PublicKeyHash) -> Result<Nonce> {
+        Ok(self.nonce(&public_key_hash.to_string()))
+    }
+
+    fn store_nonce(&mut self, public_key_hash: &PublicKeyHash, nonce: &Nonce) -> Result<()> {
+        self.nonces.insert(public_key_hash.to_string(), Nonce(nonce.0));
+        Ok(())
+    }
+
+    fn is_frozen(&mut self) -> Result<bool> {
+        Ok(self.config.frozen)
+    }
+
+    fn read_event_window(&mut self) -> Result<EventWindow> {
+        Ok(self.config.event_window)
+    }
+
+    fn read_level_timestamp(&mut self) -> Result<Option<i64>> {
+        Ok(self.config.level_timestamp)
+    }
+
+    /// The canvas of the rollup, cropped to the preview
+    fn read_canvas_config(&mut self) -> Result<CanvasConfig> {
+        let (width, height) = self.img.dimensions();
+        Ok(CanvasConfig {
+            width: width.min(self.config.canvas.width),
+            height: height.min(self.config.canvas.height),
+            palette: self.config.canvas.palette.clone(),
+        })
+    }
+
+    fn read_cooldown_config(&mut self) -> Result<CooldownConfig> {
+        Ok(self.config.cooldown)
+    }
+
+    fn read_cooldown(&mut self, public_key_hash: &PublicKeyHash) -> Result<Cooldown> {
+        let cooldown = self.cooldowns.get(&public_key_hash.to_string());
+        Ok(cooldown.copied().unwrap_or_default())
+    }
+
+    fn store_cooldown(
+        &mut self,
+        public_key_hash: &PublicKeyHash,
+        cooldown: &Cooldown,
+    ) -> Result<()> {
+        self.cooldowns.insert(public_key_hash.to_string(), *cooldown);
+        Ok(())
+    }
+
+    fn read_pixel(&mut self, x: u32, y: u32) -> Result<[u8; 3]> {
+        Ok(self.img.get_pixel(x, y).0)
+    }
+
+    fn store_pixel(&mut self, _owner: &PublicKeyHash, _level: u32, pixel: &PlacePixel) -> Result<()> {
+        let PlacePixel { x, y, color } = *pixel;
+        println!(
+            "Setting pixel: {}, {}, rgb: {}, {}, {}",
+            x, y, color[0], color[1], color[2]
+        );
+        self.img.put_pixel(x, y, Rgb(color));
+        self.img_buf = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PlaceState, RollupConfig, RollupState};
+    use crate::canvas::{CanvasConfig, BLANK_PIXEL, CHUNK_WIDTH};
+    use crate::constants::COOLDOWN_MAX_PIXELS;
+    use crate::cooldown::{Cooldown, CooldownConfig};
+    use crate::error::Error;
+    use crate::message::{Content, Inner, PlacePixel, UserMessage};
+    use crate::nonce::Nonce;
+    use std::path::PathBuf;
+
+    fn user_message(nonce: u64, x: u32, y: u32) -> UserMessage {
+        let seed = ed25519_compact::Seed::new([7; 32]);
+        let sk = ed25519_compact::KeyPair::from_seed(seed).sk;
+        let content = Content::PlacePixel(PlacePixel {
+            x,
+            y,
+            color: [255, 0, 0],
+        });
+        UserMessage::new(sk, Inner::new(Nonce(nonce), content))
+    }
+
+    #[test]
+    fn test_messages_are_verified_before_placing_pixels() {
+        let mut place = PlaceState::new(PathBuf::from("/nonexistent/place.png"));
+        let first = user_message(1, 4, 5);
+        assert!(place.set_pixel(&first).is_ok());
+        assert_eq!(place.img.get_pixel(4, 5).0, [255, 0, 0]);
+
+        assert!(matches!(place.set_pixel(&first), Err(Error::InvalidNonce)));
+        assert!(matches!(place.set_pixel(&user_message(3, 4, 5)), Err(Error::InvalidNonce)));
+
+        // A rejected message does not consume the nonce
+        let out_of_bounds = user_message(2, 1024, 0);
+        assert!(matches!(place.set_pixel(&out_of_bounds), Err(Error::PixelOutOfBounds)));
+
+        let mut forged = user_message(2, 6, 7);
+        forged.inner.content = Content::PlacePixel(PlacePixel {
+            x: 8,
+            y: 9,
+            color: [0, 0, 0],
+        });
+        assert!(place.set_pixel(&forged).is_err());
+        assert_eq!(place.img.get_pixel(8, 9).0, [255, 255, 255]);
+
+        assert!(place.set_pixel(&user_message(2, 6, 7)).is_ok());
+        let public_key_hash =
+            crate::public_key_hash::PublicKeyHash::from(first.public_key()).to_string();
+        assert_eq!(place.nonce(&public_key_hash), Nonce(2));
+    }
+
+    #[test]
+    fn test_state_is_restored() {
+        let mut place = PlaceState::new(PathBuf::from("/nonexistent/place.png"));
+        assert!(place.set_pixel(&user_message(1, 0, 0)).is_ok());
+
+        let public_key_hash =
+            crate::public_key_hash::PublicKeyHash::from(user_message(1, 0, 0).public_key())
+                .to_string();
+        let cooldown = Cooldown {
+            window_start: 10,
+            pixels: COOLDOWN_MAX_PIXELS,
+            total: 7,
+        };
+        let mut chunk = BLANK_PIXEL.repeat(CHUNK_WIDTH as usize);
+        chunk[3..6].copy_from_slice(&[0, 0, 255]);
+        let mut state = RollupState::default();
+        state.nonces.insert(public_key_hash.clone(), Nonce(5));
+        state.cooldowns.insert(public_key_hash.clone(), cooldown);
+        state.chunks.insert((2, 1), chunk);
+        place.restore(state);
+        place.set_l1_head(10);
+
+        assert_eq!(place.img.get_pixel(0, 0).0, BLANK_PIXEL);
+        assert_eq!(place.img.get_pixel(CHUNK_WIDTH + 1, 2).0, [0, 0, 255]);
+        assert_eq!(place.nonce(&public_key_hash), Nonce(5));
+        assert!(matches!(
+            place.set_pixel(&user_message(6, 0, 0)),
+            Err(Error::CooldownNotElapsed)
+        ));
+
+        // A replayed message consumes its nonce even if its pixel is rejected, as in the kernel
+        assert!(matches!(
+            place.replay_message(&user_message(5, 0, 0), 11),
+            Err(Error::InvalidNonce)
+        ));
+        assert!(place.replay_message(&user_message(6, 0, 0), 11).is_err());
+        assert_eq!(place.nonce(&public_key_hash), Nonce(6));
+    }
+
+    #[test]
+    fn test_cooldown_follows_the_l1_head() {
+        let mut place = PlaceState::new(PathBuf::from("/nonexistent/place.png"));
+        place.set_rollup_config(RollupConfig {
+            cooldown: CooldownConfig {
+                max_pixels: 1,
+                levels: 2,
+            },
+            ..RollupConfig::default()
+        });
+        place.set_l1_head(10);
+        assert!(place.set_pixel(&user_message(1, 0, 0)).is_ok());
+        assert!(matches!(
+            place.set_pixel(&user_message(2, 0, 0)),
+            Err(Error::CooldownNotElapsed)
+        ));
+
+        // The window elapses with the levels of L1
+        place.set_l1_head(11);
+        assert!(place.set_pixel(&user_message(2, 0, 0)).is_err());
+        place.set_l1_head(12);
+        assert!(place.set_pixel(&user_message(2, 0, 0)).is_ok());
+
+        // The level of the batches never decreases
+        place.set_l1_head(11);
+        assert_eq!(place.level(), 13);
+    }
+
+    #[test]
+    fn test_rollup_config_is_enforced() {
+        let mut place = PlaceState::new(PathBuf::from("/nonexistent/place.png"));
+        let public_key_hash =
+            crate::public_key_hash::PublicKeyHash::from(user_message(1, 0, 0).public_key());
+        let mut config = RollupConfig {
+            allowlist: Some(Default::default()),
+            ..RollupConfig::default()
+        };
+        place.set_rollup_config(config.clone());
+        assert!(matches!(place.set_pixel(&user_message(1, 0, 0)), Err(Error::NotAllowed)));
+
+        config.allowlist = Some([public_key_hash.to_string()].into_iter().collect());
+        config.canvas = CanvasConfig {
+            palette: Some(vec![[0, 0, 0]]),
+            ..CanvasConfig::default()
+        };
+        place.set_rollup_config(config.clone());
+        assert!(matches!(place.set_pixel(&user_message(1, 0, 0)), Err(Error::InvalidColor)));
+
+        config.canvas.palette = Some(vec![[255, 0, 0]]);
+        place.set_rollup_config(config.clone());
+        assert!(place.set_pixel(&user_message(1, 0, 0)).is_ok());
+
+        config.frozen = true;
+        place.set_rollup_config(config);
+        assert!(matches!(place.set_pixel(&user_message(2, 0, 0)), Err(Error::CanvasFrozen)));
+    }
+}
@@ -1,28 +1,109 @@
+use nom::{bytes::complete::take, combinator::map, number::complete::be_u8};
 use serde::{Deserialize, Serialize};
-use tezos_crypto_rs::hash::PublicKeyEd25519;
+use tezos_crypto_rs::hash::{HashTrait, PublicKeyEd25519, PublicKeyP256, PublicKeySecp256k1};
+use tezos_data_encoding::enc::{BinResult, BinWriter};
+use tezos_data_encoding::nom::{NomReader, NomResult};
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+/// Size of a raw ed25519 public key
+pub const ED25519_PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of a compressed secp256k1 or P-256 public key
+pub const ECDSA_PUBLIC_KEY_SIZE: usize = 33;
+
+/// Tags of the keys in the binary encoding, as in Tezos
+const ED25519_TAG: u8 = 0;
+const SECP256K1_TAG: u8 = 1;
+const P256_TAG: u8 = 2;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub enum PublicKey {
     Ed25519(PublicKeyEd25519),
+    Secp256k1(PublicKeySecp256k1),
+    P256(PublicKeyP256),
 }
 impl PublicKey {
+    /// Returns the raw bytes of the key
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PublicKey::Ed25519(pk) => pk.as_ref().to_vec(),
+            PublicKey::Secp256k1(pk) => pk.as_ref().to_vec(),
+            PublicKey::P256(pk) => pk.as_ref().to_vec(),
+        }
+    }
+
+    /// Decodes a raw ed25519 public key
+    pub fn from_ed25519_bytes(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() != ED25519_PUBLIC_KEY_SIZE {
+            return Err("Invalid ed25519 public key size");
+        }
+        PublicKeyEd25519::try_from_bytes(data)
+            .map(PublicKey::Ed25519)
+            .map_err(|_| "Cannot decode ed25519 public key")
+    }
+
     pub fn to_b58(&self) -> String {
         match self {
             PublicKey::Ed25519(pk) => pk.to_base58_check(),
+            PublicKey::Secp256k1(pk) => pk.to_base58_check(),
+            PublicKey::P256(pk) => pk.to_base58_check(),
         }
     }
 
     pub fn from_b58(data: &str) -> Result<Self, &'static str> {
-        let ed25519 = PublicKeyEd25519::from_base58_check(data).ok();
-        match ed25519 {
-            Some(pkey) => Ok(PublicKey::Ed25519(pkey)),
-            None => Err("Cannot decode b58"),
+        if let Ok(pkey) = PublicKeyEd25519::from_base58_check(data) {
+            return Ok(PublicKey::Ed25519(pkey));
+        }
+        if let Ok(pkey) = PublicKeySecp256k1::from_base58_check(data) {
+            return Ok(PublicKey::Secp256k1(pkey));
         }
+        match PublicKeyP256::from_base58_check(data) {
+            Ok(pkey) => Ok(PublicKey::P256(pkey)),
+            Err(_) => Err("Cannot decode b58"),
+        }
+    }
+}
+
+/// A public key is encoded as a tag followed by the raw key
+impl BinWriter for PublicKey {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        let tag = match self {
+            PublicKey::Ed25519(_) => ED25519_TAG,
+            PublicKey::Secp256k1(_) => SECP256K1_TAG,
+            PublicKey::P256(_) => P256_TAG,
+        };
+        output.push(tag);
+        output.extend_from_slice(&self.to_bytes());
+        Ok(())
     }
 }
+
+impl NomReader for PublicKey {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        // The sizes are checked by take
+        let (input, tag) = be_u8(input)?;
+        match tag {
+            ED25519_TAG => map(take(ED25519_PUBLIC_KEY_SIZE), |bytes: &[u8]| {
+                PublicKey::Ed25519(PublicKeyEd25519::try_from_bytes(bytes).unwrap())
+            })(input),
+            SECP256K1_TAG => map(take(ECDSA_PUBLIC_KEY_SIZE), |bytes: &[u8]| {
+                PublicKey::Secp256k1(PublicKeySecp256k1::try_from_bytes(bytes).unwrap())
+            })(input),
+            P256_TAG => map(take(ECDSA_PUBLIC_KEY_SIZE), |bytes: &[u8]| {
+                PublicKey::P256(PublicKeyP256::try_from_bytes(bytes).unwrap())
+            })(input),
+            _ => Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                input,
+                nom::error::ErrorKind::Tag,
+            ))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::PublicKey;
+    use tezos_data_encoding::enc::BinWriter;
+    use tezos_data_encoding::nom::NomReader;
 
     #[test]
     fn test_ed25519_pk_deserialization() {
@@ -31,10 +112,71 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_ed25519_pk_bytes_round_trip() {
+        let pkey = PublicKey::from_b58("edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK").unwrap();
+        let bytes = pkey.to_bytes();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(PublicKey::from_ed25519_bytes(&bytes).unwrap(), pkey);
+    }
+
     #[test]
     fn test_ed25519_pk_serialization() {
         let pkey = "edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK";
         let serialized = PublicKey::from_b58(pkey).unwrap().to_b58();
         assert_eq!(pkey, &serialized)
     }
+
+    #[test]
+    fn test_ed25519_pk_binary_encoding() {
+        let pkey = PublicKey::from_b58("edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK").unwrap();
+        let mut bytes = Vec::new();
+        pkey.bin_write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 33);
+        assert_eq!(bytes[0], 0);
+
+        let (remaining, decoded) = PublicKey::nom_read(&bytes).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(decoded, pkey);
+
+        bytes[0] = 9;
+        assert!(PublicKey::nom_read(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_secp256k1_and_p256_pk_round_trip() {
+        let vectors = [
+            (
+                "sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV",
+                "0284bf7562262bbd6940085748f3be6afa52ae317155181ece31b66351ccffa4b0",
+                1,
+            ),
+            (
+                "p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS",
+                "02515c3d6eb9e396b904d3feca7f54fdcd0cc1e997bf375dca515ad0a6c3b4035f",
+                2,
+            ),
+        ];
+        for (b58, raw, tag) in vectors {
+            let pkey = PublicKey::from_b58(b58).unwrap();
+            assert_eq!(pkey.to_b58(), b58);
+            assert_eq!(hex::encode(pkey.to_bytes()), raw);
+
+            let mut bytes = Vec::new();
+            pkey.bin_write(&mut bytes).unwrap();
+            assert_eq!(bytes[0], tag);
+            assert_eq!(bytes.len(), 34);
+            let (remaining, decoded) = PublicKey::nom_read(&bytes).unwrap();
+            assert!(remaining.is_empty());
+            assert_eq!(decoded, pkey);
+        }
+        assert!(matches!(
+            PublicKey::from_b58("sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV"),
+            Ok(PublicKey::Secp256k1(_))
+        ));
+        assert!(matches!(
+            PublicKey::from_b58("p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS"),
+            Ok(PublicKey::P256(_))
+        ));
+    }
 }
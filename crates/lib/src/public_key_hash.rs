@@ -1,62 +1,94 @@
 use serde::{Deserialize, Serialize};
-use tezos_crypto_rs::hash::ContractTz1Hash;
+use tezos_crypto_rs::hash::{ContractTz1Hash, ContractTz2Hash, ContractTz3Hash, HashTrait};
 
 use crate::public_key::PublicKey;
 
 use crate::{error::Error, hash::Blake2b20};
 
-#[derive(Deserialize, Serialize)]
+/// Size of a public key hash in the binary encoding: a tag followed by the 20 bytes of the hash
+pub const PUBLIC_KEY_HASH_SIZE: usize = 21;
+
+/// Tags of the hashes in the binary encoding, as in Tezos
+const TZ1_TAG: u8 = 0;
+const TZ2_TAG: u8 = 1;
+const TZ3_TAG: u8 = 2;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub enum PublicKeyHash {
     Tz1(ContractTz1Hash),
+    Tz2(ContractTz2Hash),
+    Tz3(ContractTz3Hash),
 }
 
 impl ToString for PublicKeyHash {
     fn to_string(&self) -> String {
         match self {
             PublicKeyHash::Tz1(tz1) => tz1.to_base58_check(),
+            PublicKeyHash::Tz2(tz2) => tz2.to_base58_check(),
+            PublicKeyHash::Tz3(tz3) => tz3.to_base58_check(),
         }
     }
 }
 
 impl PublicKeyHash {
     pub fn from_b58(data: &str) -> Result<Self, Error> {
-        let tz1 = ContractTz1Hash::from_base58_check(data).ok();
-        match tz1 {
-            Some(tz1) => Ok(PublicKeyHash::Tz1(tz1)),
-            None => Err(Error::StateDeserializarion),
+        if let Ok(tz1) = ContractTz1Hash::from_base58_check(data) {
+            return Ok(PublicKeyHash::Tz1(tz1));
+        }
+        if let Ok(tz2) = ContractTz2Hash::from_base58_check(data) {
+            return Ok(PublicKeyHash::Tz2(tz2));
+        }
+        match ContractTz3Hash::from_base58_check(data) {
+            Ok(tz3) => Ok(PublicKeyHash::Tz3(tz3)),
+            Err(_) => Err(Error::StateDeserializarion),
+        }
+    }
+
+    /// Encodes the hash as its tag followed by its raw bytes
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_HASH_SIZE] {
+        let (tag, hash) = match self {
+            PublicKeyHash::Tz1(tz1) => (TZ1_TAG, tz1.as_ref().to_vec()),
+            PublicKeyHash::Tz2(tz2) => (TZ2_TAG, tz2.as_ref().to_vec()),
+            PublicKeyHash::Tz3(tz3) => (TZ3_TAG, tz3.as_ref().to_vec()),
+        };
+        let mut bytes = [0; PUBLIC_KEY_HASH_SIZE];
+        bytes[0] = tag;
+        bytes[1..].copy_from_slice(&hash);
+        bytes
+    }
+
+    /// Decodes a hash encoded with [`PublicKeyHash::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != PUBLIC_KEY_HASH_SIZE {
+            return Err(Error::StateDeserializarion);
         }
+        let (tag, hash) = (bytes[0], &bytes[1..]);
+        let public_key_hash = match tag {
+            TZ1_TAG => ContractTz1Hash::try_from_bytes(hash).map(PublicKeyHash::Tz1),
+            TZ2_TAG => ContractTz2Hash::try_from_bytes(hash).map(PublicKeyHash::Tz2),
+            TZ3_TAG => ContractTz3Hash::try_from_bytes(hash).map(PublicKeyHash::Tz3),
+            _ => return Err(Error::StateDeserializarion),
+        };
+        public_key_hash.map_err(|_| Error::StateDeserializarion)
     }
 }
 
 impl From<PublicKey> for PublicKeyHash {
     fn from(pkey: PublicKey) -> Self {
-        match pkey {
-            PublicKey::Ed25519(ed25519) => {
-                let data = ed25519.as_ref();
-                let hash = Blake2b20::from(data);
-
-                let res = ContractTz1Hash::try_from(hash.as_ref());
-                match res {
-                    Ok(res) => PublicKeyHash::Tz1(res),
-                    Err(_) => panic!(),
-                }
-            }
-        }
+        PublicKeyHash::from(&pkey)
     }
 }
 
+/// The hash of a key is the Blake2b (20 bytes) of its raw bytes
 impl<'a> From<&'a PublicKey> for PublicKeyHash {
     fn from(pkey: &'a PublicKey) -> Self {
+        let hash = Blake2b20::from(&pkey.to_bytes());
+        let hash = hash.as_ref();
+        // A Blake2b20 hash always has the size of a public key hash
         match pkey {
-            PublicKey::Ed25519(ed25519) => {
-                let data = ed25519.as_ref();
-                let hash = Blake2b20::from(data);
-                let res = ContractTz1Hash::try_from(hash.as_ref());
-                match res {
-                    Ok(res) => PublicKeyHash::Tz1(res),
-                    Err(_) => panic!(),
-                }
-            }
+            PublicKey::Ed25519(_) => PublicKeyHash::Tz1(ContractTz1Hash::try_from(hash).unwrap()),
+            PublicKey::Secp256k1(_) => PublicKeyHash::Tz2(ContractTz2Hash::try_from(hash).unwrap()),
+            PublicKey::P256(_) => PublicKeyHash::Tz3(ContractTz3Hash::try_from(hash).unwrap()),
         }
     }
 }
@@ -91,4 +123,39 @@ mod tests {
 
         assert_eq!(tz1, &result.to_string())
     }
+
+    #[test]
+    fn test_tz2_and_tz3_from_pkey() {
+        let vectors = [
+            (
+                "sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV",
+                "tz2JMcJCm8XXZGqZDYEKqEQ81r29xfY8FgfX",
+            ),
+            (
+                "p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS",
+                "tz3eMN7uTh8FG734or1EzSKwXKJQDdevUKLH",
+            ),
+        ];
+        for (pkey, pkh) in vectors {
+            let pkey = PublicKey::from_b58(pkey).unwrap();
+            let result = PublicKeyHash::from(&pkey);
+            assert_eq!(result.to_string(), pkh);
+            assert_eq!(PublicKeyHash::from_b58(pkh).unwrap(), result);
+        }
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        for pkh in [
+            "tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv",
+            "tz2JMcJCm8XXZGqZDYEKqEQ81r29xfY8FgfX",
+            "tz3eMN7uTh8FG734or1EzSKwXKJQDdevUKLH",
+        ] {
+            let pkh = PublicKeyHash::from_b58(pkh).unwrap();
+            let bytes = pkh.to_bytes();
+            assert_eq!(PublicKeyHash::from_bytes(&bytes).unwrap(), pkh);
+        }
+        assert!(PublicKeyHash::from_bytes(&[3; 21]).is_err());
+        assert!(PublicKeyHash::from_bytes(&[0; 20]).is_err());
+    }
 }
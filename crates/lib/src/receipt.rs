@@ -3,18 +3,27 @@ use crate::hash::*;
 
 /// definition of a receipt
 ///
-/// For now we don't need to know the cause of the error
-/// Because the receipt is only used in the front-end application to give user feedbacks
+/// A receipt is written for every processed user message,
+/// so the front-end application can tell the user why a pixel did not land
 pub struct Receipt {
     hash: Blake2b,
-    success: bool,
+    level: u32,
+    batch: u64,
+    index: u32,
+    error: Option<u32>,
 }
 
 impl Receipt {
-    pub fn new(hash: Blake2b, result: &Result<()>) -> Receipt {
+    /// Creates the receipt of the message at the given index of a batch
+    ///
+    /// The error is recorded as its stable code, see [`Error::code`]
+    pub fn new(hash: Blake2b, level: u32, batch: u64, index: u32, result: &Result<()>) -> Receipt {
         Receipt {
             hash,
-            success: result.is_ok(),
+            level,
+            batch,
+            index,
+            error: result.as_ref().err().map(Error::code),
         }
     }
 
@@ -25,6 +34,53 @@ impl Receipt {
 
     /// Returns a boolean that indicates if the receipt is a success or not
     pub fn success(&self) -> bool {
-        self.success
+        self.error.is_none()
+    }
+
+    /// Returns the level at which the message was processed
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Returns the index of the batch of the message
+    pub fn batch(&self) -> u64 {
+        self.batch
+    }
+
+    /// Returns the index of the message in its batch
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns the code of the reason of the failure, if any
+    pub fn error(&self) -> Option<u32> {
+        self.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Receipt;
+    use crate::{error::Error, hash::Blake2b};
+
+    #[test]
+    fn test_receipt_records_error() {
+        let data: &[u8] = [0x1, 0x2, 0x3, 0x4].as_slice();
+        let receipt = Receipt::new(Blake2b::from(data), 12, 7, 3, &Err(Error::InvalidNonce));
+
+        assert!(!receipt.success());
+        assert_eq!(receipt.error(), Some(Error::InvalidNonce.code()));
+        assert_eq!(receipt.level(), 12);
+        assert_eq!(receipt.batch(), 7);
+        assert_eq!(receipt.index(), 3);
+    }
+
+    #[test]
+    fn test_receipt_success() {
+        let data: &[u8] = [0x1, 0x2, 0x3, 0x4].as_slice();
+        let receipt = Receipt::new(Blake2b::from(data), 12, 7, 0, &Ok(()));
+
+        assert!(receipt.success());
+        assert_eq!(receipt.error(), None);
     }
 }
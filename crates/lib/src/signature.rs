@@ -1,15 +1,103 @@
 use crate::error::*;
 use crate::hash::Blake2b;
 use crate::public_key::PublicKey;
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
+use nom::{bytes::complete::take, combinator::map, number::complete::be_u8};
 use serde::{Deserialize, Serialize};
-use tezos_crypto_rs::hash::Ed25519Signature;
+use sha2::{Digest, Sha512};
+use tezos_crypto_rs::hash::{Ed25519Signature, HashTrait, P256Signature, Secp256k1Signature};
+use tezos_crypto_rs::PublicKeySignatureVerifier;
+use tezos_data_encoding::enc::{BinResult, BinWriter};
+use tezos_data_encoding::nom::{NomReader, NomResult};
+
+/// Size of a raw ed25519 signature
+pub const ED25519_SIGNATURE_SIZE: usize = 64;
+
+/// Size of a raw secp256k1 or P-256 signature (r and s)
+pub const ECDSA_SIGNATURE_SIZE: usize = 64;
+
+/// Tags of the signatures in the binary encoding, same as the tags of the keys
+const ED25519_TAG: u8 = 0;
+const SECP256K1_TAG: u8 = 1;
+const P256_TAG: u8 = 2;
+
+/// Prefix of the messages signed off-chain by Tezos wallets
+pub const SIGNED_MESSAGE_PREFIX: &str = "Tezos Signed Message: ";
+
+/// Wraps a message in the envelope signed by Tezos wallets
+///
+/// The message is a Micheline packed string: the 0x05 prefix of packed data,
+/// the 0x01 tag of strings, the length (u32, big endian) and the bytes of the string
+pub fn micheline_payload(message: &str) -> Vec<u8> {
+    let mut payload = vec![0x05, 0x01];
+    payload.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    payload.extend_from_slice(message.as_bytes());
+    payload
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Signature {
     Ed25519(Ed25519Signature),
+    Secp256k1(Secp256k1Signature),
+    P256(P256Signature),
+}
+
+/// Verifies a secp256k1 or P-256 signature of the Blake2b hash of the message
+fn verify_ecdsa<PK>(pkey: &PK, signature: &PK::Signature, message: &[u8]) -> Result<()>
+where
+    PK: PublicKeySignatureVerifier,
+{
+    match pkey.verify_signature(signature, message) {
+        Ok(true) => Ok(()),
+        _ => Err(Error::InvalidSignature),
+    }
 }
 
 impl Signature {
+    /// Returns the raw bytes of the signature
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Signature::Ed25519(sig) => sig.as_ref().to_vec(),
+            Signature::Secp256k1(sig) => sig.as_ref().to_vec(),
+            Signature::P256(sig) => sig.as_ref().to_vec(),
+        }
+    }
+
+    /// Decodes a raw ed25519 signature
+    pub fn from_ed25519_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != ED25519_SIGNATURE_SIZE {
+            return Err(Error::InvalidSignature);
+        }
+        Ed25519Signature::try_from_bytes(data)
+            .map(Signature::Ed25519)
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    pub fn to_b58(&self) -> String {
+        match self {
+            Signature::Ed25519(sig) => sig.to_base58_check(),
+            Signature::Secp256k1(sig) => sig.to_base58_check(),
+            Signature::P256(sig) => sig.to_base58_check(),
+        }
+    }
+
+    pub fn from_b58(data: &str) -> std::result::Result<Self, &'static str> {
+        if let Ok(sig) = Ed25519Signature::from_base58_check(data) {
+            return Ok(Signature::Ed25519(sig));
+        }
+        if let Ok(sig) = Secp256k1Signature::from_base58_check(data) {
+            return Ok(Signature::Secp256k1(sig));
+        }
+        match P256Signature::from_base58_check(data) {
+            Ok(sig) => Ok(Signature::P256(sig)),
+            Err(_) => Err("Cannot decode b58"),
+        }
+    }
+
+    /// Verifies the signature of the Blake2b hash of the message
     pub fn verify(&self, public_key: &PublicKey, message: &[u8]) -> Result<()> {
         match (self, public_key) {
             (Signature::Ed25519(sig), PublicKey::Ed25519(pkey)) => {
@@ -25,32 +113,174 @@ impl Signature {
                 pkey.verify(data, &signature)
                     .map_err(|_| Error::InvalidSignature)
             }
+            (Signature::Secp256k1(sig), PublicKey::Secp256k1(pkey)) => {
+                verify_ecdsa(pkey, sig, message)
+            }
+            (Signature::P256(sig), PublicKey::P256(pkey)) => verify_ecdsa(pkey, sig, message),
+            _ => Err(Error::InvalidSignature),
         }
     }
+
+    /// Verifies the signature of a message signed by a wallet, see `micheline_payload`
+    pub fn verify_micheline(&self, public_key: &PublicKey, message: &str) -> Result<()> {
+        self.verify(public_key, &micheline_payload(message))
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use tezos_crypto_rs::hash::Ed25519Signature;
+/// Verification equation of an ed25519 signature `(R, s)` by the key `A`: `[s]B = R + [k]A`
+struct Ed25519Equation {
+    r: EdwardsPoint,
+    a: EdwardsPoint,
+    s: Scalar,
+    k: Scalar,
+}
 
-    use super::Signature;
-    use crate::public_key::PublicKey;
+/// Decodes a point from its canonical encoding, unless it has a small order
+fn decode_point(bytes: &[u8]) -> Option<EdwardsPoint> {
+    let mut encoding = [0; 32];
+    encoding.copy_from_slice(bytes);
+    let compressed = CompressedEdwardsY(encoding);
+    let point = compressed.decompress()?;
+    match point.compress() == compressed && !point.is_small_order() {
+        true => Some(point),
+        false => None,
+    }
+}
 
-    impl Signature {
-        pub fn to_b58(&self) -> String {
-            match self {
-                Signature::Ed25519(sig) => sig.to_base58_check(),
-            }
+impl Ed25519Equation {
+    /// The equation of a signature of the Blake2b hash of a message,
+    /// None if the signature or the key do not have the expected encoding
+    fn new(signature: &[u8], public_key: &[u8], hash: &Blake2b) -> Option<Self> {
+        if signature.len() != ED25519_SIGNATURE_SIZE || public_key.len() != 32 {
+            return None;
         }
+        let r = decode_point(&signature[..32])?;
+        let a = decode_point(public_key)?;
+        let mut s = [0; 32];
+        s.copy_from_slice(&signature[32..]);
+        let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(s))?;
+
+        let mut hasher = Sha512::new();
+        hasher.update(&signature[..32]);
+        hasher.update(public_key);
+        hasher.update(hash.as_ref());
+        let mut k = [0; 64];
+        k.copy_from_slice(&hasher.finalize());
+        let k = Scalar::from_bytes_mod_order_wide(&k);
+        Some(Ed25519Equation { r, a, s, k })
+    }
+}
+
+/// Coefficient of the i-th equation in the batch: 128 bits of the hash of the seed and of i
+fn batch_coefficient(seed: &Blake2b, i: usize) -> Scalar {
+    let mut data = seed.as_ref().to_vec();
+    data.extend_from_slice(&(i as u64).to_be_bytes());
+    let mut coefficient = [0; 32];
+    coefficient[..16].copy_from_slice(&Blake2b::from(data.as_slice()).as_ref()[..16]);
+    Scalar::from_bytes_mod_order(coefficient)
+}
 
-        pub fn from_b58(data: &str) -> std::result::Result<Self, &'static str> {
-            let ed25519 = Ed25519Signature::from_base58_check(data).ok();
-            match ed25519 {
-                Some(pkey) => Ok(Signature::Ed25519(pkey)),
-                None => Err("Cannot decode b58"),
+/// Verifies several signatures at once, each one over the Blake2b hash of its message
+///
+/// The ed25519 equations are checked together, as a single linear combination,
+/// which is much cheaper than one by one.
+/// The coefficients of the combination are derived from the hash of all the signatures,
+/// keys and messages, rather than drawn at random, so that the result is deterministic.
+/// The other signatures, and the ed25519 ones with a non-canonical point or scalar
+/// or a point of small order, are verified one by one with `Signature::verify`.
+///
+/// An error does not tell which signature is invalid:
+/// the signatures have to be verified one by one to find it.
+pub fn verify_batch(signed: &[(&Signature, &PublicKey, &[u8])]) -> Result<()> {
+    let mut equations = Vec::new();
+    let mut transcript = Vec::new();
+    for (signature, public_key, message) in signed {
+        let equation = match (signature, public_key) {
+            (Signature::Ed25519(sig), PublicKey::Ed25519(pkey)) => {
+                let hash = Blake2b::from(*message);
+                let equation = Ed25519Equation::new(sig.as_ref(), pkey.as_ref(), &hash);
+                if equation.is_some() {
+                    transcript.extend_from_slice(sig.as_ref());
+                    transcript.extend_from_slice(pkey.as_ref());
+                    transcript.extend_from_slice(hash.as_ref());
+                }
+                equation
             }
+            _ => None,
+        };
+        match equation {
+            Some(equation) => equations.push(equation),
+            None => signature.verify(public_key, message)?,
         }
     }
+    if equations.is_empty() {
+        return Ok(());
+    }
+
+    // sum z_i ([s_i]B - R_i - [k_i]A_i) is the identity if each equation holds
+    let seed = Blake2b::from(transcript.as_slice());
+    let mut base = Scalar::from(0u64);
+    let mut scalars = Vec::with_capacity(2 * equations.len() + 1);
+    let mut points = Vec::with_capacity(2 * equations.len() + 1);
+    for (i, equation) in equations.iter().enumerate() {
+        let z = batch_coefficient(&seed, i);
+        base += z * equation.s;
+        scalars.push(-z);
+        points.push(equation.r);
+        scalars.push(-(z * equation.k));
+        points.push(equation.a);
+    }
+    scalars.push(base);
+    points.push(ED25519_BASEPOINT_POINT);
+    match EdwardsPoint::vartime_multiscalar_mul(scalars, points).is_identity() {
+        true => Ok(()),
+        false => Err(Error::InvalidSignature),
+    }
+}
+
+/// A signature is encoded as a tag followed by the raw signature
+impl BinWriter for Signature {
+    fn bin_write(&self, output: &mut Vec<u8>) -> BinResult {
+        let tag = match self {
+            Signature::Ed25519(_) => ED25519_TAG,
+            Signature::Secp256k1(_) => SECP256K1_TAG,
+            Signature::P256(_) => P256_TAG,
+        };
+        output.push(tag);
+        output.extend_from_slice(&self.to_bytes());
+        Ok(())
+    }
+}
+
+impl NomReader for Signature {
+    fn nom_read(input: &[u8]) -> NomResult<Self> {
+        // The sizes are checked by take
+        let (input, tag) = be_u8(input)?;
+        match tag {
+            ED25519_TAG => map(take(ED25519_SIGNATURE_SIZE), |bytes: &[u8]| {
+                Signature::Ed25519(Ed25519Signature::try_from_bytes(bytes).unwrap())
+            })(input),
+            SECP256K1_TAG => map(take(ECDSA_SIGNATURE_SIZE), |bytes: &[u8]| {
+                Signature::Secp256k1(Secp256k1Signature::try_from_bytes(bytes).unwrap())
+            })(input),
+            P256_TAG => map(take(ECDSA_SIGNATURE_SIZE), |bytes: &[u8]| {
+                Signature::P256(P256Signature::try_from_bytes(bytes).unwrap())
+            })(input),
+            _ => Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                input,
+                nom::error::ErrorKind::Tag,
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_data_encoding::enc::BinWriter;
+    use tezos_data_encoding::nom::NomReader;
+
+    use super::{micheline_payload, verify_batch, Signature};
+    use crate::public_key::PublicKey;
 
     #[test]
     fn test_ed25519_signature_deserialization() {
@@ -79,4 +309,78 @@ mod tests {
         let verification = signature.verify(&pkey, data);
         assert!(verification.is_ok());
     }
+
+    #[test]
+    fn test_secp256k1_and_p256_signature_verification() {
+        let vectors = [
+            (
+                "sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV",
+                "spsig1Cx3GKn8DJpe4xuFTBKdQQw5Q23DhAWBCju9Kpbh1zVGqisR3e6qtsTzRtfjZy8iG9ZsqRhQqbmkTYEAreNpVJdMaNGCMK",
+            ),
+            (
+                "p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS",
+                "p2sigqbyaavSXipdnkQPsnYDW7rRNtP4e3vvjgMjNqxENb7TMYRQuZyrDx9MehGPyBHVmmDespcnjWHeyNyTLYBajbzttWbV9r",
+            ),
+        ];
+        for (pkey, signature) in vectors {
+            let pkey = PublicKey::from_b58(pkey).unwrap();
+            let signature = Signature::from_b58(signature).unwrap();
+            assert!(signature.verify(&pkey, b"Hello world").is_ok());
+            assert!(signature.verify(&pkey, b"Hello world!").is_err());
+
+            let mut bytes = Vec::new();
+            signature.bin_write(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), 65);
+            let (_, decoded) = Signature::nom_read(&bytes).unwrap();
+            assert_eq!(decoded.to_b58(), signature.to_b58());
+        }
+    }
+
+    #[test]
+    fn test_signature_of_another_curve_is_rejected() {
+        let signature = Signature::from_b58("spsig1Cx3GKn8DJpe4xuFTBKdQQw5Q23DhAWBCju9Kpbh1zVGqisR3e6qtsTzRtfjZy8iG9ZsqRhQqbmkTYEAreNpVJdMaNGCMK").unwrap();
+        let pkey =
+            PublicKey::from_b58("p2pk65BzdHxurDXXfTiRaiFJMwHXDB1mNi8z5Yr1ByoBZYRSXoXioBS").unwrap();
+        assert!(signature.verify(&pkey, b"Hello world").is_err());
+    }
+
+    #[test]
+    fn test_micheline_payload() {
+        let payload = micheline_payload("Tezos Signed Message: hello");
+        assert_eq!(
+            hex::encode(payload),
+            "05010000001b\
+             54657a6f73205369676e6564204d6573736167653a2068656c6c6f"
+        );
+    }
+
+    #[test]
+    fn test_batch_verification() {
+        let ed25519 = Signature::from_b58("edsigu1mRCtZquLvspcxaYXVZdsKKSqHnXevnrmh1T63Dq1Rr8M1giVLvapiDFK6TQCEyY6xytdGnKgZyVSHDVnub7puy54bD1y").unwrap();
+        let ed25519_pkey =
+            PublicKey::from_b58("edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK").unwrap();
+        let secp256k1 = Signature::from_b58("spsig1Cx3GKn8DJpe4xuFTBKdQQw5Q23DhAWBCju9Kpbh1zVGqisR3e6qtsTzRtfjZy8iG9ZsqRhQqbmkTYEAreNpVJdMaNGCMK").unwrap();
+        let secp256k1_pkey =
+            PublicKey::from_b58("sppk7aK6iq8vaTFNMrJd2LfjqYuEWzZCxB7n7UC4GRN1zN98vVwJDrV").unwrap();
+        let message: &[u8] = b"Hello world";
+
+        assert!(verify_batch(&[]).is_ok());
+        assert!(verify_batch(&[
+            (&ed25519, &ed25519_pkey, message),
+            (&ed25519, &ed25519_pkey, message),
+            (&secp256k1, &secp256k1_pkey, message),
+        ])
+        .is_ok());
+
+        assert!(verify_batch(&[
+            (&ed25519, &ed25519_pkey, message),
+            (&ed25519, &ed25519_pkey, b"Hello world!"),
+        ])
+        .is_err());
+        assert!(verify_batch(&[
+            (&ed25519, &ed25519_pkey, message),
+            (&secp256k1, &secp256k1_pkey, b"Hello world!"),
+        ])
+        .is_err());
+    }
 }
@@ -0,0 +1,132 @@
+use tezos_data_encoding::enc::BinWriter;
+use tezos_smart_rollup::michelson::MichelsonBytes;
+use tezos_smart_rollup::outbox::{OutboxMessage, OutboxMessageTransaction};
+use tezos_smart_rollup::types::{Contract, Entrypoint};
+
+use crate::canvas::BLANK_PIXEL;
+use crate::error::*;
+use crate::hash::Blake2b;
+
+/// Size of the hash of the canvas
+pub const SNAPSHOT_HASH_SIZE: usize = 32;
+
+/// Hash of the canvas before its first row
+pub const INITIAL_SNAPSHOT_HASH: [u8; SNAPSHOT_HASH_SIZE] = [0; SNAPSHOT_HASH_SIZE];
+
+/// Adds a row of the canvas to the hash of the previous rows
+///
+/// The row is the RGB bytes of its pixels, from left to right,
+/// and the hash is Blake2b(previous hash ++ Blake2b(row)).
+/// Hashing the canvas row by row lets the kernel spread the work over several runs,
+/// and anyone can recompute the hash from the raw RGB bytes of the final image.
+pub fn hash_row(
+    previous: &[u8; SNAPSHOT_HASH_SIZE],
+    row: &[u8],
+) -> [u8; SNAPSHOT_HASH_SIZE] {
+    let row_hash = Blake2b::from(row);
+    let mut data = previous.to_vec();
+    data.extend_from_slice(row_hash.as_ref());
+    Blake2b::from(&data).as_ref().try_into().unwrap()
+}
+
+/// Hash of a whole canvas, from the RGB bytes of its rows
+pub fn hash_canvas(rgb: &[u8], width: u32) -> [u8; SNAPSHOT_HASH_SIZE] {
+    rgb.chunks(width as usize * BLANK_PIXEL.len())
+        .fold(INITIAL_SNAPSHOT_HASH, |hash, row| hash_row(&hash, row))
+}
+
+/// Contract receiving the hash of the final canvas, written as "KT1...%entrypoint"
+///
+/// Without entrypoint, the default entrypoint of the contract is called.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotDestination {
+    pub contract: Contract,
+    pub entrypoint: Entrypoint,
+}
+
+impl SnapshotDestination {
+    pub fn parse(destination: &str) -> Result<Self> {
+        let (contract, entrypoint) = match destination.split_once('%') {
+            Some((contract, entrypoint)) => (contract, entrypoint),
+            None => (destination, ""),
+        };
+        let contract = match Contract::from_b58check(contract) {
+            Ok(contract @ Contract::Originated(_)) => contract,
+            _ => return Err(Error::InvalidSnapshotDestination),
+        };
+        let entrypoint = Entrypoint::try_from(entrypoint.to_string())?;
+        Ok(SnapshotDestination {
+            contract,
+            entrypoint,
+        })
+    }
+
+    pub fn to_b58(&self) -> String {
+        format!("{}%{}", self.contract.to_b58check(), self.entrypoint.name())
+    }
+
+    /// Outbox message calling the destination with the hash of the canvas, as Michelson bytes
+    pub fn outbox_message(&self, hash: &[u8; SNAPSHOT_HASH_SIZE]) -> Result<Vec<u8>> {
+        let transaction = OutboxMessageTransaction {
+            parameters: MichelsonBytes(hash.to_vec()),
+            destination: self.contract.clone(),
+            entrypoint: self.entrypoint.clone(),
+        };
+        let message = OutboxMessage::AtomicTransactionBatch(vec![transaction].into());
+        let mut bytes = Vec::new();
+        message.bin_write(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_canvas, hash_row, SnapshotDestination, INITIAL_SNAPSHOT_HASH};
+    use tezos_data_encoding::nom::NomReader;
+    use tezos_smart_rollup::michelson::MichelsonBytes;
+    use tezos_smart_rollup::outbox::OutboxMessage;
+
+    #[test]
+    fn test_canvas_is_hashed_row_by_row() {
+        let rgb: Vec<u8> = (0..2 * 3 * 3).collect();
+        let hash = hash_row(&INITIAL_SNAPSHOT_HASH, &rgb[..6]);
+        let hash = hash_row(&hash, &rgb[6..12]);
+        let hash = hash_row(&hash, &rgb[12..]);
+        assert_eq!(hash_canvas(&rgb, 2), hash);
+
+        let mut other = rgb.clone();
+        other[17] = 0;
+        assert_ne!(hash_canvas(&other, 2), hash);
+    }
+
+    #[test]
+    fn test_snapshot_destination() {
+        let destination =
+            SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint").unwrap();
+        assert_eq!(destination.entrypoint.name(), "mint");
+        assert_eq!(
+            destination.to_b58(),
+            "KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint"
+        );
+        let default = SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy").unwrap();
+        assert_eq!(default.entrypoint.name(), "default");
+
+        assert!(SnapshotDestination::parse("tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv").is_err());
+        assert!(SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%m!nt").is_err());
+    }
+
+    #[test]
+    fn test_outbox_message() {
+        let destination =
+            SnapshotDestination::parse("KT1Nh5Tk43kDmdDrfVfboWCcpukGHMtt6cqy%mint").unwrap();
+        let bytes = destination.outbox_message(&[7; 32]).unwrap();
+
+        let (remaining, message) = OutboxMessage::<MichelsonBytes>::nom_read(&bytes).unwrap();
+        assert!(remaining.is_empty());
+        let OutboxMessage::AtomicTransactionBatch(batch) = message;
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].parameters, MichelsonBytes(vec![7; 32]));
+        assert_eq!(batch[0].destination, destination.contract);
+        assert_eq!(batch[0].entrypoint, destination.entrypoint);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::canvas::CanvasConfig;
+use crate::cooldown::{Cooldown, CooldownConfig};
+use crate::error::*;
+use crate::event::EventWindow;
+use crate::message::{Content, Inner, PlacePixel};
+use crate::nonce::Nonce;
+use crate::public_key_hash::PublicKeyHash;
+
+/// State read and written by the transitions
+///
+/// The kernel implements it over the durable storage,
+/// and the sequencer over its in-memory preview of the canvas.
+pub trait Storage {
+    /// Returns true if the account can place pixels, always true when the allowlist is disabled
+    fn is_allowed(&mut self, public_key_hash: &PublicKeyHash) -> Result<bool>;
+
+    /// Nonce of the last message of the account
+    fn read_nonce(&mut self, public_key_hash: &PublicKeyHash) -> Result<Nonce>;
+
+    fn store_nonce(&mut self, public_key_hash: &PublicKeyHash, nonce: &Nonce) -> Result<()>;
+
+    fn is_frozen(&mut self) -> Result<bool>;
+
+    fn read_event_window(&mut self) -> Result<EventWindow>;
+
+    /// L1 timestamp of the current level, if known
+    fn read_level_timestamp(&mut self) -> Result<Option<i64>>;
+
+    fn read_canvas_config(&mut self) -> Result<CanvasConfig>;
+
+    fn read_cooldown_config(&mut self) -> Result<CooldownConfig>;
+
+    fn read_cooldown(&mut self, public_key_hash: &PublicKeyHash) -> Result<Cooldown>;
+
+    fn store_cooldown(&mut self, public_key_hash: &PublicKeyHash, cooldown: &Cooldown)
+        -> Result<()>;
+
+    /// Color of a pixel, the pixels never placed are blank
+    fn read_pixel(&mut self, x: u32, y: u32) -> Result<[u8; 3]>;
+
+    /// Store the color of a pixel placed by an account at the given level
+    fn store_pixel(&mut self, owner: &PublicKeyHash, level: u32, pixel: &PlacePixel)
+        -> Result<()>;
+}
+
+/// Verify that the account is allowed to place pixels
+fn verify_allowlist<S: Storage>(state: &mut S, public_key_hash: &PublicKeyHash) -> Result<()> {
+    match state.is_allowed(public_key_hash)? {
+        true => Ok(()),
+        false => Err(Error::NotAllowed),
+    }
+}
+
+/// Verify that a pixel can be placed, and returns the cooldown of the account once it is placed
+///
+/// The pixel is rejected if the canvas is frozen, outside of the event, if it is outside of the
+/// canvas, if its color is not in the palette, or if the account has exceeded its cooldown
+fn verify_pixel<S: Storage>(
+    state: &mut S,
+    public_key_hash: &PublicKeyHash,
+    level: u32,
+    pixel: &PlacePixel,
+) -> Result<Cooldown> {
+    if state.is_frozen()? {
+        return Err(Error::CanvasFrozen);
+    }
+    let timestamp = state.read_level_timestamp()?;
+    state.read_event_window()?.check(timestamp)?;
+
+    let canvas = state.read_canvas_config()?;
+    canvas.validate(pixel)?;
+
+    let config = state.read_cooldown_config()?;
+    let cooldown = state.read_cooldown(public_key_hash)?;
+    cooldown.place(&config, level)
+}
+
+/// Place a pixel on the canvas, and save its attribution
+pub fn place_pixel<S: Storage>(
+    state: &mut S,
+    public_key_hash: &PublicKeyHash,
+    level: u32,
+    pixel: &PlacePixel,
+) -> Result<()> {
+    let cooldown = verify_pixel(state, public_key_hash, level, pixel)?;
+    state.store_cooldown(public_key_hash, &cooldown)?;
+    state.store_pixel(public_key_hash, level, pixel)
+}
+
+/// Apply the inner of a message whose signature has been verified
+///
+/// - verify the account is allowed to place pixels
+/// - verify the nonce of the message, which is consumed even if the content is rejected
+/// - handle the content
+pub fn apply<S: Storage>(
+    state: &mut S,
+    public_key_hash: &PublicKeyHash,
+    level: u32,
+    inner: &Inner,
+) -> Result<()> {
+    verify_allowlist(state, public_key_hash)?;
+    let nonce = state.read_nonce(public_key_hash)?;
+    inner.verify_nonce(&nonce)?;
+    state.store_nonce(public_key_hash, &nonce.next())?;
+
+    match &inner.content {
+        Content::PlacePixel(pixel) => place_pixel(state, public_key_hash, level, pixel),
+    }
+}
+
+/// Verify that `apply` would accept the inner, without changing the state
+///
+/// The sequencer only batches the messages accepted entirely,
+/// as the kernel consumes the nonce of a message even if its content is rejected.
+pub fn verify<S: Storage>(
+    state: &mut S,
+    public_key_hash: &PublicKeyHash,
+    level: u32,
+    inner: &Inner,
+) -> Result<()> {
+    verify_allowlist(state, public_key_hash)?;
+    inner.verify_nonce(&state.read_nonce(public_key_hash)?)?;
+
+    match &inner.content {
+        Content::PlacePixel(pixel) => verify_pixel(state, public_key_hash, level, pixel).map(|_| ()),
+    }
+}
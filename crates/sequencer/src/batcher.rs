@@ -0,0 +1,371 @@
+use lib::framing::MAX_BATCH_SIZE;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Size of the length prefix of each transaction in a batch
+const FRAME_PREFIX_SIZE: usize = 4;
+
+/// Size of the smallest transaction: version, ed25519 key and signature, and a PlacePixel inner
+const MIN_TX_SIZE: usize = 1 + (1 + 32) + (1 + 64) + 20;
+
+/// Maximum number of transactions of a batch, the smallest ones filling `MAX_BATCH_SIZE`
+const MAX_BATCH_TXS: usize = MAX_BATCH_SIZE / (FRAME_PREFIX_SIZE + MIN_TX_SIZE);
+
+/// Maximum latency of a transaction: a day
+const MAX_LATENCY_MS: usize = 24 * 60 * 60 * 1000;
+
+/// When the queue of transactions is flushed into batches, and how large the batches are
+///
+/// A batch is flushed as soon as the queue holds a full batch:
+/// `max_txs` transactions or `max_bytes` bytes, whichever comes first,
+/// or once its oldest transaction has waited for `max_latency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    pub max_latency: Duration,
+    pub max_txs: usize,
+    /// Size of the framed transactions of a batch, before it is cut into DAC pages
+    pub max_bytes: usize,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            max_latency: Duration::from_secs(10),
+            max_txs: 10_000,
+            max_bytes: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Invalid value for {0}: {1}")]
+    Invalid(&'static str, String),
+    #[error("{0} cannot be 0")]
+    Zero(&'static str),
+    #[error("{0} cannot exceed {1}")]
+    TooLarge(&'static str, usize),
+}
+
+/// Value of a variable of the environment, or the default if it is not set
+///
+/// The value cannot be 0, nor exceed the given maximum.
+fn env_or(name: &'static str, default: usize, max: usize) -> Result<usize, PolicyError> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| PolicyError::Invalid(name, value))?,
+        Err(_) => default,
+    };
+    match value {
+        0 => Err(PolicyError::Zero(name)),
+        value if value > max => Err(PolicyError::TooLarge(name, max)),
+        value => Ok(value),
+    }
+}
+
+/// Size of a transaction in a batch
+fn frame_size(tx: &[u8]) -> usize {
+    FRAME_PREFIX_SIZE + tx.len()
+}
+
+impl FlushPolicy {
+    /// Reads the policy from SEQUENCER_MAX_LATENCY_MS, SEQUENCER_MAX_BATCH_TXS
+    /// and SEQUENCER_MAX_BATCH_BYTES, falling back to the default of each
+    ///
+    /// The batches have to fit in the pages processed by the kernel, see `MAX_BATCH_SIZE`.
+    pub fn from_env() -> Result<Self, PolicyError> {
+        let default = FlushPolicy::default();
+        let max_latency = env_or(
+            "SEQUENCER_MAX_LATENCY_MS",
+            default.max_latency.as_millis() as usize,
+            MAX_LATENCY_MS,
+        )?;
+        Ok(FlushPolicy {
+            max_latency: Duration::from_millis(max_latency as u64),
+            max_txs: env_or("SEQUENCER_MAX_BATCH_TXS", default.max_txs, MAX_BATCH_TXS)?,
+            max_bytes: env_or(
+                "SEQUENCER_MAX_BATCH_BYTES",
+                default.max_bytes,
+                MAX_BATCH_SIZE,
+            )?,
+        })
+    }
+
+    /// Returns true if the queue holds at least a full batch, to be flushed without waiting
+    pub fn is_full(&self, queue: &TxQueue) -> bool {
+        queue.len() >= self.max_txs || queue.bytes() >= self.max_bytes
+    }
+
+    /// Returns true if the oldest transaction of the queue has waited for `max_latency`
+    pub fn is_expired(&self, queue: &TxQueue, now: Instant) -> bool {
+        match queue.oldest() {
+            Some(oldest) => now >= oldest + self.max_latency,
+            None => false,
+        }
+    }
+
+    /// Time at which the oldest transaction of the queue will have waited for `max_latency`
+    pub fn deadline(&self, queue: &TxQueue) -> Option<Instant> {
+        queue.oldest().map(|oldest| oldest + self.max_latency)
+    }
+
+    /// Number of transactions at the head of the queue going in the next batch
+    ///
+    /// A batch only holds transactions verified at the same level, see `TxQueue::level`.
+    /// A transaction larger than `max_bytes` goes alone in its batch.
+    pub fn next_batch(&self, queue: &TxQueue) -> usize {
+        let mut bytes = 0;
+        let mut txs = 0;
+        for tx in queue.head().take(self.max_txs) {
+            bytes += frame_size(tx);
+            if bytes > self.max_bytes && txs > 0 {
+                break;
+            }
+            txs += 1;
+        }
+        txs
+    }
+}
+
+/// Transactions waiting for their batch, in order, with the level at which each was verified
+/// and the time at which it was queued
+///
+/// The size of the framed transactions is kept along, to check if the queue holds a full batch.
+#[derive(Debug, Default)]
+pub struct TxQueue {
+    txs: VecDeque<(Instant, u32, Vec<u8>)>,
+    bytes: usize,
+}
+
+impl TxQueue {
+    /// Queues a transaction verified at the given level, at the given time
+    pub fn push(&mut self, tx: Vec<u8>, level: u32, queued: Instant) {
+        self.bytes += frame_size(&tx);
+        self.txs.push_back((queued, level, tx));
+    }
+
+    /// Removes the given number of transactions at the head of the queue
+    pub fn drain(&mut self, count: usize) -> Vec<Vec<u8>> {
+        let txs: Vec<Vec<u8>> = self.txs.drain(..count).map(|(_, _, tx)| tx).collect();
+        self.bytes -= txs.iter().map(|tx| frame_size(tx)).sum::<usize>();
+        txs
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Size of the framed transactions of the queue
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Time at which the oldest transaction was queued
+    pub fn oldest(&self) -> Option<Instant> {
+        self.txs.front().map(|(queued, _, _)| *queued)
+    }
+
+    /// Level at which the oldest transaction was verified, the level of its batch
+    ///
+    /// The kernel applies the transactions of a batch at its level,
+    /// so that it judges them as they were judged before they were queued.
+    pub fn level(&self) -> Option<u32> {
+        self.txs.front().map(|(_, level, _)| *level)
+    }
+
+    /// Transactions at the head of the queue verified at the same level as the oldest one
+    pub fn head(&self) -> impl Iterator<Item = &Vec<u8>> {
+        let level = self.level();
+        self.txs
+            .iter()
+            .take_while(move |(_, tx_level, _)| Some(*tx_level) == level)
+            .map(|(_, _, tx)| tx)
+    }
+}
+
+/// Why a batch was flushed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushReason {
+    /// The queue held a full batch
+    Full,
+    /// The oldest transaction of the queue waited for `max_latency`
+    Latency,
+}
+
+/// Statistics over the batches flushed since the sequencer started
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchMetrics {
+    pub batches: u64,
+    pub txs: u64,
+    pub bytes: u64,
+    /// Batches flushed because they were full
+    pub full_batches: u64,
+    /// Batches flushed because their oldest transaction waited for `max_latency`
+    pub latency_batches: u64,
+    pub largest_batch_txs: u64,
+    pub largest_batch_bytes: u64,
+    pub last_batch_txs: u64,
+    pub last_batch_bytes: u64,
+}
+
+impl BatchMetrics {
+    /// Records a batch of the given transactions
+    pub fn record(&mut self, batch: &[Vec<u8>], reason: FlushReason) {
+        let txs = batch.len() as u64;
+        let bytes = batch.iter().map(|tx| frame_size(tx)).sum::<usize>() as u64;
+        self.batches += 1;
+        self.txs += txs;
+        self.bytes += bytes;
+        match reason {
+            FlushReason::Full => self.full_batches += 1,
+            FlushReason::Latency => self.latency_batches += 1,
+        }
+        self.largest_batch_txs = self.largest_batch_txs.max(txs);
+        self.largest_batch_bytes = self.largest_batch_bytes.max(bytes);
+        self.last_batch_txs = txs;
+        self.last_batch_bytes = bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_or, BatchMetrics, FlushPolicy, FlushReason, PolicyError, TxQueue};
+    use std::time::{Duration, Instant};
+
+    fn policy() -> FlushPolicy {
+        FlushPolicy {
+            max_latency: Duration::from_secs(1),
+            max_txs: 3,
+            max_bytes: 30,
+        }
+    }
+
+    fn queue(sizes: &[usize]) -> TxQueue {
+        let mut queue = TxQueue::default();
+        for size in sizes {
+            queue.push(vec![0; *size], 1, Instant::now());
+        }
+        queue
+    }
+
+    #[test]
+    fn test_batches_are_bounded() {
+        let policy = policy();
+        assert_eq!(policy.next_batch(&queue(&[])), 0);
+        assert_eq!(policy.next_batch(&queue(&[6, 6])), 2);
+        assert!(!policy.is_full(&queue(&[6, 6])));
+
+        // Bounded by the number of transactions
+        let txs = queue(&[1; 5]);
+        assert!(policy.is_full(&txs));
+        assert_eq!(policy.next_batch(&txs), 3);
+
+        // Bounded by the size: 10 + 10 + 10 bytes fit, not 10 + 10 + 11
+        let txs = queue(&[6, 6, 7]);
+        assert!(policy.is_full(&txs));
+        assert_eq!(policy.next_batch(&txs), 2);
+        assert_eq!(policy.next_batch(&queue(&[6, 6, 6])), 3);
+
+        // A transaction larger than a batch still goes through
+        let mut txs = queue(&[40, 1]);
+        assert_eq!(policy.next_batch(&txs), 1);
+        assert_eq!(txs.drain(1), vec![vec![0; 40]]);
+        assert_eq!(policy.next_batch(&txs), 1);
+    }
+
+    #[test]
+    fn test_batches_have_a_single_level() {
+        let policy = policy();
+        let mut txs = TxQueue::default();
+        for level in [1, 1, 2, 2, 2] {
+            txs.push(vec![0; 1], level, Instant::now());
+        }
+        assert!(policy.is_full(&txs));
+        assert_eq!((txs.level(), policy.next_batch(&txs)), (Some(1), 2));
+        txs.drain(2);
+        assert_eq!((txs.level(), policy.next_batch(&txs)), (Some(2), 3));
+        txs.drain(3);
+        assert_eq!(txs.level(), None);
+    }
+
+    #[test]
+    fn test_queue_keeps_its_size() {
+        let mut txs = queue(&[6, 6, 7]);
+        assert_eq!((txs.len(), txs.bytes()), (3, 31));
+        txs.drain(2);
+        assert_eq!((txs.len(), txs.bytes()), (1, 11));
+        txs.drain(1);
+        assert!(txs.is_empty());
+        assert_eq!(txs.bytes(), 0);
+    }
+
+    #[test]
+    fn test_oldest_tx_sets_the_deadline() {
+        let policy = policy();
+        let start = Instant::now();
+        let mut txs = TxQueue::default();
+        assert!(!policy.is_expired(&txs, start));
+        assert_eq!(policy.deadline(&txs), None);
+
+        txs.push(vec![0; 1], 1, start);
+        txs.push(vec![0; 1], 1, start + Duration::from_millis(500));
+        assert_eq!(policy.deadline(&txs), Some(start + Duration::from_secs(1)));
+        assert!(!policy.is_expired(&txs, start + Duration::from_millis(999)));
+        assert!(policy.is_expired(&txs, start + Duration::from_secs(1)));
+
+        // The deadline follows the oldest transaction left
+        txs.drain(1);
+        assert!(!policy.is_expired(&txs, start + Duration::from_secs(1)));
+        assert_eq!(
+            policy.deadline(&txs),
+            Some(start + Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        std::env::set_var("BATCHER_TEST_ZERO", "0");
+        std::env::set_var("BATCHER_TEST_LARGE", "11");
+        std::env::set_var("BATCHER_TEST_INVALID", "-1");
+        assert!(matches!(
+            env_or("BATCHER_TEST_ZERO", 1, 10),
+            Err(PolicyError::Zero(_))
+        ));
+        assert!(matches!(
+            env_or("BATCHER_TEST_LARGE", 1, 10),
+            Err(PolicyError::TooLarge(_, 10))
+        ));
+        assert!(matches!(
+            env_or("BATCHER_TEST_INVALID", 1, 10),
+            Err(PolicyError::Invalid(_, _))
+        ));
+        assert_eq!(env_or("BATCHER_TEST_UNSET", 5, 10).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = BatchMetrics::default();
+        metrics.record(&[vec![0; 6], vec![0; 6]], FlushReason::Full);
+        metrics.record(&[vec![0; 1]], FlushReason::Latency);
+        assert_eq!(
+            metrics,
+            BatchMetrics {
+                batches: 2,
+                txs: 3,
+                bytes: 25,
+                full_batches: 1,
+                latency_batches: 1,
+                largest_batch_txs: 2,
+                largest_batch_bytes: 20,
+                last_batch_txs: 1,
+                last_batch_bytes: 5,
+            }
+        );
+    }
+}
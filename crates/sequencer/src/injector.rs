@@ -0,0 +1,781 @@
+use crate::queue::write_atomically;
+use lib::hash::{Blake2b, Blake2b20};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tezos_crypto_rs::hash::{BlockHash, ContractTz1Hash, HashTrait};
+
+/// Tag of the smart_rollup_add_messages operation
+const ADD_MESSAGES_TAG: u8 = 201;
+
+/// Tag of the reveal operation
+const REVEAL_TAG: u8 = 107;
+
+/// Tag of the ed25519 public keys
+const ED25519_KEY_TAG: u8 = 0;
+
+/// Gas limit of the reveal of an ed25519 key
+const REVEAL_GAS_LIMIT: u64 = 1_000;
+
+/// Upper bound of the gas of adding one message to the inbox, on top of the gas limit
+const GAS_PER_MESSAGE: u64 = 1_000;
+
+/// Maximal size of an operation accepted by the node, signature included
+const MAX_OPERATION_SIZE: usize = 32 * 1024;
+
+/// Watermark of the generic operations, prepended to the forged operation before signing
+const GENERIC_OPERATION_WATERMARK: u8 = 0x03;
+
+/// Validation pass of the manager operations, in the operation hashes of a block
+const MANAGER_PASS: u8 = 3;
+
+/// Size of an ed25519 signature, appended to the forged operation
+const SIGNATURE_SIZE: usize = 64;
+
+/// Upper bound of the size of a forged reveal
+const REVEAL_SIZE: usize = 100;
+
+/// Minimal fees of the baker: 100 mutez, plus 1 mutez per byte and 0.1 mutez per gas unit
+const MINIMAL_FEE: u64 = 100;
+const MUTEZ_PER_BYTE: u64 = 1;
+const GAS_PER_MUTEZ: u64 = 10;
+
+/// Margin added to the minimal fee, the size of the fee itself is not accounted
+const FEE_MARGIN: u64 = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InjectorError {
+    #[error("RPC request failed: {0}")]
+    Rpc(String),
+    #[error("Invalid RPC response: {0}")]
+    InvalidResponse(String),
+    #[error("Line {0} of the message log is not a hex encoded message")]
+    InvalidMessage(usize),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, InjectorError>;
+
+pub struct InjectorConfig {
+    /// RPC endpoint of the Tezos node, e.g. http://localhost:8732
+    pub endpoint: String,
+    /// Log of the external messages, one hex encoded message per line
+    pub message_log: PathBuf,
+    /// File holding the line of the log to inject next, starting at 1
+    pub index_path: PathBuf,
+    /// Delay between two polls of the node
+    pub poll_interval: Duration,
+    /// Number of blocks to wait for the inclusion of an operation before injecting it again
+    pub inclusion_blocks: i32,
+    /// Gas limit of an operation, without its messages
+    pub gas_limit: u64,
+}
+
+/// Injects the external messages of the log on L1, in order
+///
+/// All the pending messages fitting in an operation are injected together,
+/// the operation is injected again until it is included in a block,
+/// then the index of the next message is stored.
+/// Each injection of the same messages uses the same counter, so that at most one is included
+/// even if an operation deemed lost is still pending, see `inject_until_included`.
+/// If a message ends up included twice anyway, the kernel ignores the second batch,
+/// whose header does not follow the last batch.
+///
+/// The operations are signed by the injector key, not by the sequencer key signing the batches.
+/// The sequencer key is registered in the kernel by the governance and only signs batch headers,
+/// while the injector key holds the tez paying the fees:
+/// it can be refilled or replaced without touching the kernel, and a leak costs at most its tez.
+/// The account has to be used by the injector only, as its counter tracks the inclusions.
+/// The key of the account is revealed with the first operation if it is not yet.
+pub struct Injector {
+    config: InjectorConfig,
+    client: awc::Client,
+    secret_key: ed25519_compact::SecretKey,
+    /// Public key hash of the injector, as encoded in the operations
+    source: [u8; 21],
+}
+
+#[derive(Deserialize)]
+struct BlockHeader {
+    level: i32,
+}
+
+/// Encodes a natural number as a zarith: 7 bits per byte, least significant first
+fn zarith(mut n: u64, bytes: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Fields common to all the manager operations: source, fee, counter, gas and storage limits
+fn forge_manager(
+    tag: u8,
+    source: &[u8; 21],
+    fee: u64,
+    counter: u64,
+    gas_limit: u64,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.push(tag);
+    bytes.extend_from_slice(source);
+    zarith(fee, bytes);
+    zarith(counter, bytes);
+    zarith(gas_limit, bytes);
+    zarith(0, bytes);
+}
+
+/// Binary encoding of a reveal of an ed25519 key, without branch
+pub fn forge_reveal(source: &[u8; 21], fee: u64, counter: u64, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut bytes = vec![];
+    forge_manager(
+        REVEAL_TAG,
+        source,
+        fee,
+        counter,
+        REVEAL_GAS_LIMIT,
+        &mut bytes,
+    );
+    bytes.push(ED25519_KEY_TAG);
+    bytes.extend_from_slice(public_key);
+    bytes
+}
+
+/// Binary encoding of a smart_rollup_add_messages operation, without branch
+pub fn forge_add_messages(
+    source: &[u8; 21],
+    fee: u64,
+    counter: u64,
+    gas_limit: u64,
+    messages: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut bytes = vec![];
+    forge_manager(
+        ADD_MESSAGES_TAG,
+        source,
+        fee,
+        counter,
+        gas_limit,
+        &mut bytes,
+    );
+    let size: usize = messages.iter().map(|message| 4 + message.len()).sum();
+    bytes.extend_from_slice(&(size as u32).to_be_bytes());
+    for message in messages {
+        bytes.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(message);
+    }
+    bytes
+}
+
+/// Signs a forged operation with the generic operation watermark, and appends the signature
+pub fn sign_operation(secret_key: &ed25519_compact::SecretKey, forged: &[u8]) -> Vec<u8> {
+    let mut watermarked = vec![GENERIC_OPERATION_WATERMARK];
+    watermarked.extend_from_slice(forged);
+    let signature = secret_key.sign(Blake2b::from(&watermarked), None);
+    let mut signed = forged.to_vec();
+    signed.extend_from_slice(&signature[..]);
+    signed
+}
+
+/// Minimal fee of an operation of the given size, signature included
+fn fee(size: usize, gas_limit: u64) -> u64 {
+    let size = (size + SIGNATURE_SIZE) as u64;
+    MINIMAL_FEE
+        + size * MUTEZ_PER_BYTE
+        + (gas_limit + GAS_PER_MUTEZ - 1) / GAS_PER_MUTEZ
+        + FEE_MARGIN
+}
+
+/// Gas limit of an operation adding the given number of messages
+fn add_messages_gas_limit(base: u64, messages: usize) -> u64 {
+    base + GAS_PER_MESSAGE * messages as u64
+}
+
+/// Messages of the log starting at the given line, as many as fit in an operation
+///
+/// An external message is at most 4 KiB, so at least one always fits.
+/// Only the complete lines are read, the last one may still be written by the sequencer.
+/// The messages stop before a line which is not hex encoded, an error is returned
+/// if it is the first one.
+fn next_messages(log: &str, index: usize, gas_limit: u64) -> Result<Vec<Vec<u8>>> {
+    let complete = log.rfind('\n').map_or(0, |newline| newline + 1);
+    let mut messages = vec![];
+    for (line_index, line) in log[..complete].lines().enumerate().skip(index - 1) {
+        if line.is_empty() {
+            break;
+        }
+        match hex::decode(line) {
+            Ok(message) => messages.push(message),
+            Err(_) if messages.is_empty() => {
+                return Err(InjectorError::InvalidMessage(line_index + 1))
+            }
+            Err(_) => break,
+        }
+        let gas_limit = add_messages_gas_limit(gas_limit, messages.len());
+        let size = forge_add_messages(&[0; 21], u64::MAX, u64::MAX, gas_limit, &messages).len();
+        // The branch, a reveal and the signature are not part of the forged messages
+        if size + 32 + REVEAL_SIZE + SIGNATURE_SIZE > MAX_OPERATION_SIZE && messages.len() > 1 {
+            messages.pop();
+            break;
+        }
+    }
+    Ok(messages)
+}
+
+impl Injector {
+    pub fn new(config: InjectorConfig, secret_key: ed25519_compact::SecretKey) -> Self {
+        let mut source = [0; 21];
+        source[1..].copy_from_slice(Blake2b20::from(&secret_key.public_key()[..]).as_ref());
+        Injector {
+            config,
+            client: awc::Client::default(),
+            secret_key,
+            source,
+        }
+    }
+
+    /// Base58 of the public key hash of the injector
+    fn public_key_hash(&self) -> String {
+        ContractTz1Hash::try_from(&self.source[1..])
+            .unwrap()
+            .to_base58_check()
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{}", self.config.endpoint, path);
+        let mut response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|err| InjectorError::Rpc(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(InjectorError::Rpc(format!(
+                "GET {}: {}",
+                path,
+                response.status()
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|err| InjectorError::InvalidResponse(err.to_string()))
+    }
+
+    async fn head_hash(&self) -> Result<[u8; 32]> {
+        let hash: String = self.get("/chains/main/blocks/head/hash").await?;
+        let hash = BlockHash::from_base58_check(&hash)
+            .map_err(|err| InjectorError::InvalidResponse(err.to_string()))?;
+        hash.0
+            .as_slice()
+            .try_into()
+            .map_err(|_| InjectorError::InvalidResponse(hash.to_base58_check()))
+    }
+
+    async fn head_level(&self) -> Result<i32> {
+        let header: BlockHeader = self.get("/chains/main/blocks/head/header").await?;
+        Ok(header.level)
+    }
+
+    /// Whether the key of the injector is revealed
+    async fn is_revealed(&self) -> Result<bool> {
+        let path = format!(
+            "/chains/main/blocks/head/context/contracts/{}/manager_key",
+            self.public_key_hash()
+        );
+        let manager_key: Option<String> = self.get(&path).await?;
+        Ok(manager_key.is_some())
+    }
+
+    async fn counter(&self) -> Result<u64> {
+        let path = format!(
+            "/chains/main/blocks/head/context/contracts/{}/counter",
+            self.public_key_hash()
+        );
+        let counter: String = self.get(&path).await?;
+        counter
+            .parse()
+            .map_err(|_| InjectorError::InvalidResponse(counter))
+    }
+
+    /// Hashes of the manager operations included in the block of the given level
+    async fn manager_operations(&self, level: i32) -> Result<Vec<String>> {
+        let path = format!(
+            "/chains/main/blocks/{}/operation_hashes/{}",
+            level, MANAGER_PASS
+        );
+        self.get(&path).await
+    }
+
+    /// Forges, signs and injects an operation adding the messages to the rollup inbox
+    ///
+    /// The key of the injector is revealed in the same operation if needed.
+    /// The operation starts at the given counter, set to the next counter of the account
+    /// for the first injection of the messages.
+    /// Returns the hash of the operation, or None if the counter has been consumed:
+    /// an earlier injection of the messages has been included.
+    async fn inject(
+        &self,
+        messages: &[Vec<u8>],
+        counter: &mut Option<u64>,
+    ) -> Result<Option<String>> {
+        let branch = self.head_hash().await?;
+        let head_counter = self.counter().await?;
+        let first_counter = *counter.get_or_insert(head_counter + 1);
+        if head_counter >= first_counter {
+            return Ok(None);
+        }
+        let mut counter = first_counter;
+        let mut forged = branch.to_vec();
+        if !self.is_revealed().await? {
+            let public_key = *self.secret_key.public_key();
+            let size = forge_reveal(&self.source, 0, counter, &public_key).len();
+            let fee = fee(size, REVEAL_GAS_LIMIT);
+            forged.extend(forge_reveal(&self.source, fee, counter, &public_key));
+            counter += 1;
+        }
+        let gas_limit = add_messages_gas_limit(self.config.gas_limit, messages.len());
+        let size = forge_add_messages(&self.source, 0, counter, gas_limit, messages).len();
+        let fee = fee(size, gas_limit);
+        forged.extend(forge_add_messages(
+            &self.source,
+            fee,
+            counter,
+            gas_limit,
+            messages,
+        ));
+        let signed = sign_operation(&self.secret_key, &forged);
+
+        let url = format!("{}/injection/operation", self.config.endpoint);
+        let mut response = self
+            .client
+            .post(&url)
+            .send_json(&hex::encode(signed))
+            .await
+            .map_err(|err| InjectorError::Rpc(err.to_string()))?;
+        if !response.status().is_success() {
+            let body = response.body().await.unwrap_or_default();
+            return Err(InjectorError::Rpc(
+                String::from_utf8_lossy(&body).to_string(),
+            ));
+        }
+        response
+            .json()
+            .await
+            .map(Some)
+            .map_err(|err| InjectorError::InvalidResponse(err.to_string()))
+    }
+
+    /// Waits for the inclusion of an operation, for at most `inclusion_blocks` blocks
+    async fn wait_for_inclusion(&self, operation: &str) -> Result<bool> {
+        let start = self.head_level().await?;
+        let mut next = start;
+        loop {
+            actix_web::rt::time::sleep(self.config.poll_interval).await;
+            let level = self.head_level().await?;
+            while next <= level {
+                if self
+                    .manager_operations(next)
+                    .await?
+                    .iter()
+                    .any(|hash| hash == operation)
+                {
+                    return Ok(true);
+                }
+                next += 1;
+            }
+            if level >= start + self.config.inclusion_blocks {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Injects the messages until they are included in a block
+    ///
+    /// An operation not included after `inclusion_blocks` may still be in the mempool:
+    /// it is injected again with the same counter, so that only one of them can be included.
+    /// Once the counter is consumed, one of them has been included.
+    async fn inject_until_included(&self, messages: &[Vec<u8>]) {
+        let mut counter = None;
+        loop {
+            let included = match self.inject(messages, &mut counter).await {
+                Ok(Some(operation)) => {
+                    println!("Injected operation {}", operation);
+                    self.wait_for_inclusion(&operation).await
+                }
+                Ok(None) => Ok(true),
+                Err(err) => Err(err),
+            };
+            match included {
+                Ok(true) => return,
+                Ok(false) => println!("Operation not included, injecting it again"),
+                Err(err) => {
+                    eprintln!("Injection failed: {}", err);
+                    actix_web::rt::time::sleep(self.config.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    fn read_index(&self) -> usize {
+        fs::read_to_string(&self.config.index_path)
+            .ok()
+            .and_then(|index| index.trim().parse().ok())
+            .unwrap_or(1)
+    }
+
+    fn store_index(&self, index: usize) -> Result<()> {
+        write_atomically(&self.config.index_path, index.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// Injects the next messages of the log, if any
+    ///
+    /// Returns false when all the messages of the log have been injected
+    pub async fn inject_next(&self) -> Result<bool> {
+        let index = self.read_index();
+        let log = read_log(&self.config.message_log)?;
+        let messages = next_messages(&log, index, self.config.gas_limit)?;
+        if messages.is_empty() {
+            return Ok(false);
+        }
+        println!(
+            "Injecting messages {} to {}",
+            index,
+            index + messages.len() - 1
+        );
+        self.inject_until_included(&messages).await;
+        self.store_index(index + messages.len())?;
+        Ok(true)
+    }
+
+    /// Injects the messages of the log as they are written
+    pub async fn run(self) {
+        loop {
+            match self.inject_next().await {
+                Ok(true) => (),
+                Ok(false) => actix_web::rt::time::sleep(self.config.poll_interval).await,
+                Err(err) => {
+                    eprintln!("Injector error: {}", err);
+                    actix_web::rt::time::sleep(self.config.poll_interval).await;
+                }
+            }
+        }
+    }
+}
+
+fn read_log(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(log) => Ok(log),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_messages_gas_limit, fee, forge_add_messages, forge_reveal, next_messages, zarith,
+        Injector, InjectorConfig, InjectorError, MAX_OPERATION_SIZE, REVEAL_GAS_LIMIT,
+    };
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use lib::hash::Blake2b;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tezos_crypto_rs::hash::{BlockHash, HashTrait, OperationHash};
+
+    /// A Tezos node producing a block each time its head is requested
+    ///
+    /// The first injected operation is dropped, the next ones are included in the next block.
+    /// With `late_inclusion`, the first operation is included instead, in a block which
+    /// the injector does not see, only the counter of the account tells it.
+    #[derive(Default)]
+    struct MockNode {
+        level: i32,
+        revealed: bool,
+        late_inclusion: bool,
+        injected: Vec<Vec<u8>>,
+        pending: Vec<String>,
+        blocks: Vec<(i32, Vec<String>)>,
+    }
+
+    type Node = web::Data<Arc<Mutex<MockNode>>>;
+
+    async fn head_hash() -> HttpResponse {
+        let hash = BlockHash::try_from_bytes(&[1; 32]).unwrap();
+        HttpResponse::Ok().json(hash.to_base58_check())
+    }
+
+    async fn head_header(node: Node) -> HttpResponse {
+        let mut node = node.lock().unwrap();
+        node.level += 1;
+        let block = (node.level, std::mem::take(&mut node.pending));
+        node.blocks.push(block);
+        HttpResponse::Ok().json(serde_json::json!({ "level": node.level }))
+    }
+
+    async fn counter(node: Node) -> HttpResponse {
+        let node = node.lock().unwrap();
+        match node.late_inclusion && !node.injected.is_empty() {
+            true => HttpResponse::Ok().json("42"),
+            false => HttpResponse::Ok().json("41"),
+        }
+    }
+
+    async fn manager_key(node: Node) -> HttpResponse {
+        let node = node.lock().unwrap();
+        match node.revealed {
+            true => {
+                HttpResponse::Ok().json("edpkuDMUm7Y53wp4gxeLBXuiAhXZrLn8XB1R83ksvvesH8Lp8bmCfK")
+            }
+            false => HttpResponse::Ok().json(serde_json::Value::Null),
+        }
+    }
+
+    async fn operation_hashes(node: Node, path: web::Path<(i32, u8)>) -> HttpResponse {
+        let node = node.lock().unwrap();
+        let (level, _) = path.into_inner();
+        let hashes = node
+            .blocks
+            .iter()
+            .find(|(block, _)| *block == level)
+            .map(|(_, hashes)| hashes.clone())
+            .unwrap_or_default();
+        HttpResponse::Ok().json(hashes)
+    }
+
+    async fn inject(node: Node, operation: web::Json<String>) -> HttpResponse {
+        let mut node = node.lock().unwrap();
+        let operation = hex::decode(operation.into_inner()).unwrap();
+        let hash = OperationHash::try_from_bytes(Blake2b::from(&operation).as_ref()).unwrap();
+        let hash = hash.to_base58_check();
+        if !node.injected.is_empty() && !node.late_inclusion {
+            node.pending.push(hash.clone());
+        }
+        node.injected.push(operation);
+        HttpResponse::Ok().json(hash)
+    }
+
+    /// Starts a mock node, returns its endpoint
+    fn start_node(node: Arc<Mutex<MockNode>>) -> String {
+        let data = web::Data::new(node);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/chains/main/blocks/head/hash", web::get().to(head_hash))
+                .route(
+                    "/chains/main/blocks/head/header",
+                    web::get().to(head_header),
+                )
+                .route(
+                    "/chains/main/blocks/head/context/contracts/{pkh}/counter",
+                    web::get().to(counter),
+                )
+                .route(
+                    "/chains/main/blocks/head/context/contracts/{pkh}/manager_key",
+                    web::get().to(manager_key),
+                )
+                .route(
+                    "/chains/main/blocks/{level}/operation_hashes/{pass}",
+                    web::get().to(operation_hashes),
+                )
+                .route("/injection/operation", web::post().to(inject))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    fn injector(
+        endpoint: String,
+        dir: &std::path::Path,
+        log: &str,
+    ) -> (Injector, ed25519_compact::KeyPair) {
+        std::fs::create_dir_all(dir).unwrap();
+        let message_log = dir.join("external_message_log");
+        std::fs::write(&message_log, log).unwrap();
+        let config = InjectorConfig {
+            endpoint,
+            message_log,
+            index_path: dir.join("next_index"),
+            poll_interval: Duration::from_millis(1),
+            inclusion_blocks: 2,
+            gas_limit: 10_000,
+        };
+        let seed = ed25519_compact::Seed::new([3; 32]);
+        let keys = ed25519_compact::KeyPair::from_seed(seed);
+        (Injector::new(config, keys.sk.clone()), keys)
+    }
+
+    /// Verifies the signature of an operation, returns the forged operation
+    fn verify_operation<'a>(keys: &ed25519_compact::KeyPair, operation: &'a [u8]) -> &'a [u8] {
+        let (forged, signature) = operation.split_at(operation.len() - 64);
+        let mut watermarked = vec![0x03];
+        watermarked.extend_from_slice(forged);
+        let signature = ed25519_compact::Signature::from_slice(signature).unwrap();
+        keys.pk
+            .verify(Blake2b::from(&watermarked), &signature)
+            .unwrap();
+        forged
+    }
+
+    fn encode_zarith(n: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        zarith(n, &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_zarith() {
+        assert_eq!(encode_zarith(0), vec![0]);
+        assert_eq!(encode_zarith(127), vec![0x7f]);
+        assert_eq!(encode_zarith(128), vec![0x80, 0x01]);
+        assert_eq!(encode_zarith(10_000), vec![0x90, 0x4e]);
+    }
+
+    #[actix_web::test]
+    async fn test_messages_are_injected_until_included() {
+        let node = Arc::new(Mutex::new(MockNode {
+            revealed: true,
+            ..MockNode::default()
+        }));
+        let endpoint = start_node(node.clone());
+        let dir = std::env::temp_dir().join(format!("injector-{}", std::process::id()));
+        let (injector, keys) = injector(endpoint, &dir, "7401\n7402\n");
+
+        assert!(injector.inject_next().await.unwrap());
+        assert!(!injector.inject_next().await.unwrap());
+        let index = std::fs::read_to_string(dir.join("next_index")).unwrap();
+        assert_eq!(index, "3");
+
+        let node = node.lock().unwrap();
+        // Both messages are in the same operation, dropped once and injected again
+        assert_eq!(node.injected.len(), 2);
+        for operation in node.injected.iter() {
+            let forged = verify_operation(&keys, operation);
+            let counter = encode_zarith(42);
+            assert_eq!(forged[..32], [1; 32]);
+            assert_eq!(forged[32], 201);
+            assert!(forged
+                .windows(counter.len())
+                .any(|window| window == counter));
+            assert_eq!(
+                forged[forged.len() - 16..],
+                [0, 0, 0, 12, 0, 0, 0, 2, 0x74, 0x01, 0, 0, 0, 2, 0x74, 0x02]
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_pending_operation_is_not_duplicated() {
+        let node = Arc::new(Mutex::new(MockNode {
+            revealed: true,
+            late_inclusion: true,
+            ..MockNode::default()
+        }));
+        let endpoint = start_node(node.clone());
+        let dir = std::env::temp_dir().join(format!("injector-late-{}", std::process::id()));
+        let (injector, _) = injector(endpoint, &dir, "7401\n");
+
+        assert!(injector.inject_next().await.unwrap());
+        let index = std::fs::read_to_string(dir.join("next_index")).unwrap();
+        assert_eq!(index, "2");
+
+        // The counter of the first operation is consumed, it is not injected again
+        assert_eq!(node.lock().unwrap().injected.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_key_is_revealed_with_the_first_operation() {
+        let node = Arc::new(Mutex::new(MockNode::default()));
+        let endpoint = start_node(node.clone());
+        let dir = std::env::temp_dir().join(format!("injector-reveal-{}", std::process::id()));
+        let (injector, keys) = injector(endpoint, &dir, "7401\n");
+
+        assert!(injector.inject_next().await.unwrap());
+
+        let node = node.lock().unwrap();
+        let forged = verify_operation(&keys, &node.injected[0]);
+        let public_key: [u8; 32] = *keys.pk;
+        let source = injector.source;
+        let size = forge_reveal(&source, 0, 42, &public_key).len();
+        let reveal = forge_reveal(&source, fee(size, REVEAL_GAS_LIMIT), 42, &public_key);
+        let gas_limit = add_messages_gas_limit(10_000, 1);
+        let messages = [vec![0x74, 0x01]];
+        let size = forge_add_messages(&source, 0, 43, gas_limit, &messages).len();
+        let add_messages =
+            forge_add_messages(&source, fee(size, gas_limit), 43, gas_limit, &messages);
+        assert_eq!(forged, [&[1; 32][..], &reveal, &add_messages].concat());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_messages_are_packed_up_to_the_operation_size() {
+        let line = "74".to_string() + &"ab".repeat(4000);
+        let log = vec![line; 20].join("\n") + "\n";
+        let first = next_messages(&log, 1, 10_000).unwrap();
+        assert!(first.len() > 1 && first.len() < 20);
+        let size: usize = first.iter().map(|message| 4 + message.len()).sum();
+        assert!(size < MAX_OPERATION_SIZE);
+
+        let rest = next_messages(&log, 1 + first.len(), 10_000).unwrap();
+        assert!(!rest.is_empty());
+        assert!(next_messages(&log, 21, 10_000).unwrap().is_empty());
+        assert_eq!(
+            next_messages("7401\n7402\n", 2, 10_000).unwrap(),
+            vec![vec![0x74, 0x02]]
+        );
+    }
+
+    #[test]
+    fn test_only_complete_hex_lines_are_injected() {
+        // The last line is still being written
+        let messages = next_messages("7401\n7402\n74", 1, 10_000).unwrap();
+        assert_eq!(messages, vec![vec![0x74, 0x01], vec![0x74, 0x02]]);
+
+        // The messages stop before an invalid line, which is then rejected
+        let log = "7401\n{\"header\": 1}\n7402\n";
+        assert_eq!(
+            next_messages(log, 1, 10_000).unwrap(),
+            vec![vec![0x74, 0x01]]
+        );
+        assert!(matches!(
+            next_messages(log, 2, 10_000),
+            Err(InjectorError::InvalidMessage(2))
+        ));
+    }
+
+    #[test]
+    fn test_forge_add_messages() {
+        let forged = forge_add_messages(&[0; 21], 1, 2, 3, &[vec![0xab], vec![]]);
+        let mut expected = vec![201];
+        expected.extend_from_slice(&[0; 21]);
+        expected.extend_from_slice(&[1, 2, 3, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 9, 0, 0, 0, 1, 0xab, 0, 0, 0, 0]);
+        assert_eq!(forged, expected);
+    }
+
+    #[test]
+    fn test_forge_reveal() {
+        let forged = forge_reveal(&[0; 21], 1, 2, &[7; 32]);
+        let mut expected = vec![107];
+        expected.extend_from_slice(&[0; 21]);
+        expected.extend_from_slice(&[1, 2, 0xe8, 0x07, 0, 0]);
+        expected.extend_from_slice(&[7; 32]);
+        assert_eq!(forged, expected);
+    }
+}
@@ -1,35 +1,72 @@
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, StreamHandler};
+mod batcher;
+mod injector;
+mod queue;
+mod rollup;
+
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, SpawnHandle, StreamHandler};
 use actix_files::Files;
 use actix_web::{
     web::{self, Bytes},
     App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_actors::ws;
-use lib::{dac::encoding::PreimageHash, message::UserMessage, place::PlaceState};
+use batcher::{BatchMetrics, FlushPolicy, FlushReason, TxQueue};
+use injector::{Injector, InjectorConfig};
+use lib::{
+    dac::encoding::PreimageHash,
+    message::{raw_rollup_address, BatchHeader, LoggedMessage, UserMessage},
+    place::PlaceState,
+};
+use queue::Checkpoint;
+use rollup::RollupNode;
+use serde::Serialize;
 use std::{
     fs::{self, File, OpenOptions},
     io::prelude::*,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 struct AppState {
     place: PlaceState,
     connections: Vec<Addr<WsActor>>,
-    tx_queue: Vec<Vec<u8>>,
+    tx_queue: TxQueue,
     tx_log: File,
     external_message_log: File,
+    /// Header of the last batch written to the external message log
+    last_batch: Option<BatchHeader>,
+    /// Number of transactions of the transaction log already in a batch
+    flushed_txs: u64,
+    checkpoint_path: PathBuf,
+    metrics: BatchMetrics,
 }
 
-struct TextMessage(bytestring::ByteString);
+/// Result of a message sent on the websocket, replied to its sender only
+///
+/// The accepted pixels are also broadcast to all the connections.
+#[derive(Serialize)]
+enum Reply {
+    Accepted { hash: String },
+    Rejected { hash: Option<String>, error: String },
+}
 
-impl Message for TextMessage {
+impl Reply {
+    fn to_json(&self) -> String {
+        serde_json_wasm::to_string(self).unwrap()
+    }
+}
+
+/// Accepted message broadcast to the connections, in its binary encoding
+struct PlacedPixel(Bytes);
+
+impl Message for PlacedPixel {
     type Result = ();
 }
 
 struct WsActor {
     app_state: Arc<Mutex<AppState>>,
+    printer: Addr<PrinterActor>,
 }
 
 impl Actor for WsActor {
@@ -47,21 +84,51 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsActor {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Text(text)) => {
                 println!("Received Text message");
-                let bin: Bytes = bytestring::ByteString::into_bytes(text.clone());
+                let bin: Bytes = bytestring::ByteString::into_bytes(text);
                 match std::str::from_utf8(bin.as_ref()) {
                     Err(_) => (),
-                    Ok(message) => match serde_json_wasm::from_str(message) {
-                        Err(err) => println!("Erro parsing message: {}", err),
+                    Ok(message) => match serde_json_wasm::from_str::<UserMessage>(message) {
+                        Err(err) => {
+                            println!("Erro parsing message: {}", err);
+                            let reply = Reply::Rejected {
+                                hash: None,
+                                error: err.to_string(),
+                            };
+                            ctx.text(reply.to_json());
+                        }
                         Ok(message) => {
                             println!("Parsed message successfully");
+                            let hash = message.hash().to_string();
                             let mut app_state = self.app_state.lock().unwrap();
-                            let (_result, message) = app_state.place.set_pixel(message);
-                            let json = serde_json_wasm::to_string(&message).unwrap();
-                            writeln!(app_state.tx_log, "{}", json).unwrap();
-                            app_state.tx_queue.push(json.into_bytes());
+                            if let Err(err) = app_state.place.verify_message(&message) {
+                                println!("Pixel rejected: {}", err.to_string());
+                                let reply = Reply::Rejected {
+                                    hash: Some(hash),
+                                    error: err.to_string(),
+                                };
+                                ctx.text(reply.to_json());
+                                return;
+                            }
+                            // The transaction is accepted only once it survives a crash
+                            if let Err(err) = queue::append_tx(&mut app_state.tx_log, &message) {
+                                eprintln!("Cannot write the transaction log: {}", err);
+                                let reply = Reply::Rejected {
+                                    hash: Some(hash),
+                                    error: String::from("The transaction cannot be logged"),
+                                };
+                                ctx.text(reply.to_json());
+                                return;
+                            }
+                            // Verified above, the transition cannot fail
+                            app_state.place.apply_message(&message).unwrap();
+                            let bytes = Bytes::from(message.to_bytes());
+                            let (tx, level) = (bytes.to_vec(), app_state.place.level());
+                            app_state.tx_queue.push(tx, level, Instant::now());
+                            ctx.text(Reply::Accepted { hash }.to_json());
                             for connection in &app_state.connections {
-                                let _ = connection.do_send(TextMessage(text.clone()));
+                                let _ = connection.do_send(PlacedPixel(bytes.clone()));
                             }
+                            self.printer.do_send(QueuedTx);
                         }
                     },
                 }
@@ -71,22 +138,24 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsActor {
     }
 }
 
-impl Handler<TextMessage> for WsActor {
+impl Handler<PlacedPixel> for WsActor {
     type Result = ();
 
-    fn handle(&mut self, msg: TextMessage, ctx: &mut Self::Context) -> Self::Result {
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: PlacedPixel, ctx: &mut Self::Context) -> Self::Result {
+        ctx.binary(msg.0);
     }
 }
 
 async fn new_connection(
     app_state: web::Data<Arc<Mutex<AppState>>>,
+    printer: web::Data<Addr<PrinterActor>>,
     req: HttpRequest,
     stream: web::Payload,
 ) -> Result<HttpResponse, Error> {
     ws::start(
         WsActor {
             app_state: app_state.get_ref().clone(),
+            printer: printer.get_ref().clone(),
         },
         &req,
         stream,
@@ -105,61 +174,187 @@ async fn get_image(place: web::Data<Arc<Mutex<AppState>>>) -> impl Responder {
         .body(bytes);
 }
 
+/// Nonce of the last message accepted from an account, the next message has to use the following one
+async fn get_nonce(
+    place: web::Data<Arc<Mutex<AppState>>>,
+    public_key_hash: web::Path<String>,
+) -> impl Responder {
+    let app_state = place.lock().unwrap();
+    let nonce = app_state.place.nonce(&public_key_hash);
+
+    HttpResponse::Ok()
+        .append_header(("Access-Control-Allow-Origin", "*"))
+        .append_header(("Cache-Control", "no-cache, no-store"))
+        .content_type("application/json")
+        .body(nonce.0.to_string())
+}
+
+/// Statistics over the sizes of the batches flushed so far
+async fn get_metrics(place: web::Data<Arc<Mutex<AppState>>>) -> impl Responder {
+    let app_state = place.lock().unwrap();
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-cache, no-store"))
+        .json(&app_state.metrics)
+}
+
+/// Flushes the queue of transactions into batches, written to the external message log
 struct PrinterActor {
     app_state: Arc<Mutex<AppState>>,
+    policy: FlushPolicy,
+    secret_key: ed25519_compact::SecretKey,
+    rollup_address: [u8; 20],
+    preimages_dir: PathBuf,
+    /// Flush armed for the deadline of the oldest transaction of the queue
+    timer: Option<SpawnHandle>,
+}
+
+/// Sent by the connections when a transaction is queued, to flush the queue if it is full
+struct QueuedTx;
+
+impl Message for QueuedTx {
+    type Result = ();
+}
+
+impl PrinterActor {
+    /// Writes a batch of the transactions at the head of the queue
+    fn flush_batch(&self, app_state: &mut AppState, reason: FlushReason) {
+        // The batch is built for the level its transactions were verified at in the preview
+        let level = match app_state.tx_queue.level() {
+            Some(level) => level,
+            None => return,
+        };
+        let batch_len = self.policy.next_batch(&app_state.tx_queue);
+        let batch = app_state.tx_queue.drain(batch_len);
+        println!("flushing {} txs from queue", batch_len);
+        let save_preimages = |hash: PreimageHash, preimage: Vec<u8>| {
+            let name = hex::encode(hash.as_ref());
+            let path = self.preimages_dir.join(name);
+
+            if let Err(e) = fs::write(&path, preimage) {
+                eprintln!("Failed to write preimage to {:?} due to {}.", path, e);
+            }
+        };
+
+        let root_hash =
+            lib::dac::encoding::prepare_preimages(lib::framing::pack(&batch), save_preimages)
+                .unwrap();
+
+        let merkle_root = *root_hash.as_ref();
+
+        let header = match &app_state.last_batch {
+            Some(last_batch) => last_batch.next(merkle_root, level),
+            None => BatchHeader::first(merkle_root, level),
+        };
+        let message = lib::message::Message::new(
+            self.secret_key.clone(),
+            &self.rollup_address,
+            header.clone(),
+        );
+        let external_message = hex::encode(message.to_bytes());
+        let checkpoint = Checkpoint {
+            batch_index: header.index,
+            txs_before: app_state.flushed_txs,
+            txs_after: app_state.flushed_txs + batch_len as u64,
+        };
+        queue::write_checkpoint(&app_state.checkpoint_path, &checkpoint).unwrap();
+        writeln!(app_state.external_message_log, "{}", external_message).unwrap();
+        app_state.external_message_log.sync_data().unwrap();
+        app_state.flushed_txs = checkpoint.txs_after;
+        app_state.metrics.record(&batch, reason);
+        app_state.last_batch = Some(header);
+    }
+
+    /// Flushes the full batches of the queue, and the batches whose oldest transaction
+    /// reached the maximum latency
+    ///
+    /// A flush is then armed for the deadline of the oldest transaction left in the queue.
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        let mut app_state = self.app_state.lock().unwrap();
+        let now = Instant::now();
+        loop {
+            let reason = if self.policy.is_full(&app_state.tx_queue) {
+                FlushReason::Full
+            } else if self.policy.is_expired(&app_state.tx_queue, now) {
+                FlushReason::Latency
+            } else {
+                break;
+            };
+            self.flush_batch(&mut app_state, reason);
+        }
+        let deadline = self.policy.deadline(&app_state.tx_queue);
+        drop(app_state);
+        if let (None, Some(deadline)) = (&self.timer, deadline) {
+            let delay = deadline.saturating_duration_since(now);
+            self.timer = Some(ctx.run_later(delay, |actor, ctx| {
+                actor.timer = None;
+                actor.flush(ctx);
+            }));
+        }
+    }
 }
 
 impl Actor for PrinterActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let sequencer_secret_key = std::env::var("SEQUENCER_SECRET_KEY").unwrap();
-        let _rollup_address = std::env::var("ROLLUP_ADDRESS").unwrap();
-        let rollup_preimages_dir = std::env::var("ROLLUP_PREIMAGES_DIR").unwrap();
-        let () = std::fs::create_dir_all(rollup_preimages_dir.clone()).unwrap();
+        // The transactions recovered at startup are flushed by their deadline
+        self.flush(ctx);
+    }
+}
 
-        let sk = tezos_crypto_rs::hash::SecretKeyEd25519::from_base58_check(
-            sequencer_secret_key.as_str(),
-        )
-        .unwrap();
+impl Handler<QueuedTx> for PrinterActor {
+    type Result = ();
 
-        ctx.run_interval(Duration::from_secs(10), move |actor, _| {
-            let mut app_state = actor.app_state.lock().unwrap();
-            let queue_len = app_state.tx_queue.len();
-            if queue_len > 0 {
-                println!("flushing {} txs from queue", queue_len);
-                let preimages_dir = Path::new(rollup_preimages_dir.as_str()); // FIXME:
-                let save_preimages = |hash: PreimageHash, preimage: Vec<u8>| {
-                    let name = hex::encode(hash.as_ref());
-                    let path = preimages_dir.join(name);
-
-                    if let Err(e) = fs::write(&path, preimage) {
-                        eprintln!("Failed to write preimage to {:?} due to {}.", path, e);
-                    }
-                };
-
-                let root_hash = lib::dac::encoding::prepare_preimages(
-                    app_state.tx_queue.clone(),
-                    save_preimages,
-                )
-                .unwrap();
+    fn handle(&mut self, _msg: QueuedTx, ctx: &mut Self::Context) -> Self::Result {
+        self.flush(ctx);
+    }
+}
 
-                let mut unprefixed_merkel_root: [u8; 32] = [0; 32];
-                unprefixed_merkel_root.copy_from_slice(&root_hash.as_ref()[1..]);
+/// Reads an ed25519 secret key from an environment variable, as a b58 edsk
+fn read_secret_key(name: &str) -> ed25519_compact::SecretKey {
+    let secret_key = std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let sk = tezos_crypto_rs::hash::SecretKeyEd25519::from_base58_check(secret_key.trim())
+        .unwrap_or_else(|_| panic!("Invalid value for {}", name));
+    ed25519_compact::SecretKey::from_slice(sk.as_ref().as_slice()).unwrap()
+}
 
-                let sk = sk.as_ref().as_slice();
-                let sk = ed25519_compact::SecretKey::from_slice(sk).unwrap();
-                let message = lib::message::Message::new(sk, unprefixed_merkel_root);
-                let str = serde_json::to_string(&message).unwrap();
-                writeln!(app_state.external_message_log, "{}", str).unwrap();
-                app_state.tx_queue.clear();
-            } else {
-                println!("Queue empty, skipping flush")
-            }
-        });
+/// Delay between two reads of the configuration of the rollup
+const ROLLUP_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reads the head of L1 and the configuration of the rollup from the rollup node,
+/// and applies them to the preview
+async fn sync_rollup(node: &RollupNode, app_state: &Mutex<AppState>) -> rollup::Result<()> {
+    let head_level = node.l1_head_level().await?;
+    let config = node.read_config().await?;
+    let mut app_state = app_state.lock().unwrap();
+    app_state.place.set_l1_head(head_level);
+    app_state.place.set_rollup_config(config);
+    Ok(())
+}
+
+/// Follows the head of L1 and the configuration set by the governance
+async fn follow_rollup(node: RollupNode, app_state: Arc<Mutex<AppState>>) {
+    loop {
+        actix_web::rt::time::sleep(ROLLUP_POLL_INTERVAL).await;
+        if let Err(err) = sync_rollup(&node, &app_state).await {
+            eprintln!("Cannot read the state of the rollup: {}", err);
+        }
     }
 }
 
+/// Reads the header of the last batch written to the external message log
+///
+/// The log contains the hex encoding of each external message,
+/// older JSON lines are decoded as legacy messages.
+/// Lines that cannot be decoded, or that are not chained, are ignored
+fn read_last_batch(path: &str) -> Option<BatchHeader> {
+    let log = fs::read_to_string(path).ok()?;
+    log.lines()
+        .rev()
+        .find_map(|line| LoggedMessage::parse(line).ok()?.header())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     println!("starging server");
@@ -168,6 +363,23 @@ async fn main() -> std::io::Result<()> {
     let tx_log_path = std::env::var("ROLLUP_TX_LOG").unwrap();
     let frontend_path = std::env::var("TZPLACE_FRONTEND").unwrap();
 
+    let checkpoint_path = std::env::var("ROLLUP_CHECKPOINT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(format!("{}.checkpoint", tx_log_path)));
+
+    // A batch cut by a crash was never injected, the next one is appended on its own line
+    queue::truncate_partial_line(Path::new(&external_message_log_path))?;
+    let last_batch = read_last_batch(&external_message_log_path);
+    let place = PlaceState::new(PathBuf::from(image_path));
+
+    // Recover the transactions accepted but not batched before the restart
+    queue::truncate_partial_line(Path::new(&tx_log_path))?;
+    let mut txs = queue::read_tx_log(Path::new(&tx_log_path));
+    let checkpoint = queue::read_checkpoint(&checkpoint_path);
+    let (flushed_txs, pending) = queue::pending_txs(&txs, checkpoint, last_batch.as_ref());
+    let recovered = txs.split_off(flushed_txs as usize);
+    println!("{} transactions recovered from the log", pending.len());
+
     let tx_log = OpenOptions::new()
         .create(true)
         .write(true)
@@ -179,29 +391,123 @@ async fn main() -> std::io::Result<()> {
         .create(true)
         .write(true)
         .append(true)
-        .open(external_message_log_path)
+        .open(&external_message_log_path)
         .unwrap();
     // Note: web::Data created _outside_ HttpServer::new closure
     let app_state = AppState {
-        place: PlaceState::new(PathBuf::from(image_path)),
+        place,
         connections: vec![],
-        tx_queue: vec![],
+        tx_queue: TxQueue::default(),
         tx_log,
         external_message_log,
+        last_batch,
+        flushed_txs,
+        checkpoint_path,
+        metrics: BatchMetrics::default(),
     };
     let place = web::Data::new(Arc::new(Mutex::new(app_state)));
 
+    let sk = read_secret_key("SEQUENCER_SECRET_KEY");
+    let rollup_address = std::env::var("ROLLUP_ADDRESS").map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "ROLLUP_ADDRESS is not set",
+        )
+    })?;
+    let rollup_address = raw_rollup_address(&rollup_address).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "ROLLUP_ADDRESS {} is not a smart rollup address (sr1...)",
+                rollup_address
+            ),
+        )
+    })?;
+    let rollup_preimages_dir = PathBuf::from(std::env::var("ROLLUP_PREIMAGES_DIR").unwrap());
+    let () = std::fs::create_dir_all(&rollup_preimages_dir).unwrap();
+    let policy = FlushPolicy::from_env()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()))?;
+    println!("Flush policy: {:?}", policy);
+
     let printer_actor = PrinterActor {
         app_state: place.get_ref().clone(),
+        policy,
+        secret_key: sk,
+        rollup_address,
+        preimages_dir: rollup_preimages_dir,
+        timer: None,
+    };
+    let printer = web::Data::new(printer_actor.start());
+
+    // The preview has to follow the rollup before accepting any transaction
+    let node = RollupNode::new(std::env::var("ROLLUP_NODE_ENDPOINT").unwrap());
+    let state = match sync_rollup(&node, place.get_ref()).await {
+        Ok(()) => node.read_state().await,
+        Err(err) => Err(err),
     };
-    let _ = printer_actor.start(); // Ignoring the returned Addr<PrinterActor>
+    let state = state.map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Cannot read the state of the rollup: {}", err),
+        )
+    })?;
+    actix_web::rt::spawn(follow_rollup(node, place.get_ref().clone()));
+
+    // The preview starts from the state of the rollup, the transactions the kernel has not
+    // applied yet are replayed on top
+    {
+        let mut app_state = place.lock().unwrap();
+        app_state.place.restore(state);
+        // The transactions already applied are rejected for their nonce. The ones of the batches
+        // still in flight are replayed at the level of the last batch, the only one logged.
+        let batch_level = match &app_state.last_batch {
+            Some(header) => header.level,
+            None => 0,
+        };
+        for message in &txs {
+            let _ = app_state.place.replay_message(message, batch_level);
+        }
+        // The recovered transactions are queued at the level of the preview
+        let level = app_state.place.level();
+        for (message, tx) in recovered.iter().zip(pending) {
+            if let Err(err) = app_state.place.replay_message(message, level) {
+                eprintln!(
+                    "Recovered transaction no longer applies to the preview: {}",
+                    err.to_string()
+                );
+            }
+            app_state.tx_queue.push(tx, level, Instant::now());
+        }
+    }
+    printer.do_send(QueuedTx);
+
+    // Inject the batches on L1 as they are written to the external message log
+    if let Ok(endpoint) = std::env::var("TEZOS_NODE_ENDPOINT") {
+        let index_path = std::env::var("ROLLUP_MESSAGE_INDEX")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(format!("{}.index", external_message_log_path)));
+        let config = InjectorConfig {
+            endpoint,
+            message_log: PathBuf::from(&external_message_log_path),
+            index_path,
+            poll_interval: Duration::from_secs(5),
+            inclusion_blocks: 5,
+            gas_limit: 10_000,
+        };
+        // The operations are paid by the injector account, the sequencer key only signs batches
+        let injector_key = read_secret_key("INJECTOR_SECRET_KEY");
+        actix_web::rt::spawn(Injector::new(config, injector_key).run());
+    }
 
     HttpServer::new(move || {
         // move counter into the closure
         App::new()
             .app_data(place.clone()) // <- register the created data
+            .app_data(printer.clone())
             .route("/ws", web::get().to(new_connection))
             .route("/place.png", web::get().to(get_image))
+            .route("/nonce/{public_key_hash}", web::get().to(get_nonce))
+            .route("/metrics", web::get().to(get_metrics))
             .service(Files::new("/", frontend_path.clone()).index_file("index.html"))
         // Serve static files from the `static` folder
     })
@@ -0,0 +1,263 @@
+use lib::message::{BatchHeader, UserMessage};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// Progress of the batches over the transaction log, written before each batch
+///
+/// The transaction log holds every accepted transaction, the batches take them in order.
+/// The checkpoint records the transactions taken by the batch about to be written,
+/// `txs_before` if the batch is missing from the external message log after a crash,
+/// `txs_after` once it is there.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Index of the batch about to be written
+    pub batch_index: u64,
+    /// Number of transactions of the log in the batches before this one
+    pub txs_before: u64,
+    /// Number of transactions of the log in the batches up to this one
+    pub txs_after: u64,
+}
+
+impl Checkpoint {
+    /// Number of transactions of the log already in a batch of the external message log
+    pub fn flushed_txs(&self, last_batch: Option<&BatchHeader>) -> u64 {
+        match last_batch {
+            Some(header) if header.index >= self.batch_index => self.txs_after,
+            _ => self.txs_before,
+        }
+    }
+}
+
+/// Reads the checkpoint, if any
+pub fn read_checkpoint(path: &Path) -> Option<Checkpoint> {
+    let bytes = fs::read(path).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Writes the checkpoint atomically: a crash leaves either the previous or the new checkpoint
+pub fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    write_atomically(path, &serde_json::to_vec(checkpoint)?)
+}
+
+/// Replaces the content of a file, durably: a crash leaves either the previous or the new content
+///
+/// The rename is durable once the directory holding the file is synced.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Removes the last line of the log if it is incomplete, e.g. cut by a crash
+///
+/// Has to be done before the log is opened in append mode:
+/// the next transaction would be appended to the partial line, and both would be lost.
+/// A transaction is only accepted once its line is synced, so a partial line was never accepted.
+pub fn truncate_partial_line(path: &Path) -> io::Result<()> {
+    let log = match fs::read(path) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if log.is_empty() || log.ends_with(b"\n") {
+        return Ok(());
+    }
+    let complete = log
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(complete as u64)?;
+    file.sync_all()
+}
+
+/// Line of the log holding a transaction: the hex of its binary encoding
+pub fn tx_log_line(message: &UserMessage) -> String {
+    hex::encode(message.to_bytes())
+}
+
+/// Appends a transaction to the log and syncs it, the transaction is accepted once it is done
+///
+/// On failure, the log is cut back to its previous length,
+/// so that the next transaction is not appended to a partial line.
+pub fn append_tx(tx_log: &mut File, message: &UserMessage) -> io::Result<()> {
+    let len = tx_log.metadata()?.len();
+    let result = writeln!(tx_log, "{}", tx_log_line(message)).and_then(|()| tx_log.sync_data());
+    if result.is_err() {
+        let _ = tx_log.set_len(len);
+    }
+    result
+}
+
+/// Reads the transactions of the log, one message per line
+///
+/// The lines written before the binary encoding hold the JSON of the message.
+/// Lines that cannot be decoded are ignored
+pub fn read_tx_log(path: &Path) -> Vec<UserMessage> {
+    let log = match fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(_) => return vec![],
+    };
+    log.lines().filter_map(parse_tx_log_line).collect()
+}
+
+fn parse_tx_log_line(line: &str) -> Option<UserMessage> {
+    match hex::decode(line) {
+        Ok(bytes) => UserMessage::from_bytes(&bytes).ok(),
+        Err(_) => serde_json_wasm::from_str::<UserMessage>(line).ok(),
+    }
+}
+
+/// Transactions of the log which are not in any batch yet, and the number of the others
+///
+/// The checkpoint is written before the first batch, so without checkpoint nothing is flushed.
+pub fn pending_txs(
+    txs: &[UserMessage],
+    checkpoint: Option<Checkpoint>,
+    last_batch: Option<&BatchHeader>,
+) -> (u64, Vec<Vec<u8>>) {
+    let flushed = match checkpoint {
+        Some(checkpoint) => checkpoint.flushed_txs(last_batch),
+        None => 0,
+    };
+    let pending = txs
+        .iter()
+        .skip(flushed as usize)
+        .map(|message| message.to_bytes())
+        .collect();
+    (flushed, pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        append_tx, pending_txs, read_checkpoint, read_tx_log, truncate_partial_line, tx_log_line,
+        write_checkpoint, Checkpoint,
+    };
+    use lib::message::{BatchHeader, Content, Inner, PlacePixel, UserMessage};
+    use lib::nonce::Nonce;
+
+    fn user_message(nonce: u64) -> UserMessage {
+        let seed = ed25519_compact::Seed::new([7; 32]);
+        let sk = ed25519_compact::KeyPair::from_seed(seed).sk;
+        let content = Content::PlacePixel(PlacePixel {
+            x: 1,
+            y: 2,
+            color: [255, 0, 0],
+        });
+        UserMessage::new(sk, Inner::new(Nonce(nonce), content))
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.json", std::process::id()));
+        assert_eq!(read_checkpoint(&path), None);
+        let checkpoint = Checkpoint {
+            batch_index: 3,
+            txs_before: 10,
+            txs_after: 12,
+        };
+        write_checkpoint(&path, &checkpoint).unwrap();
+        assert_eq!(read_checkpoint(&path), Some(checkpoint));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pending_txs_are_recovered() {
+        let txs: Vec<UserMessage> = (1..=5).map(user_message).collect();
+        let first = BatchHeader::first([1; 33], 1);
+        let second = first.next([2; 33], 1);
+        let checkpoint = Checkpoint {
+            batch_index: 1,
+            txs_before: 2,
+            txs_after: 4,
+        };
+
+        // The second batch was written before the crash
+        let (flushed, pending) = pending_txs(&txs, Some(checkpoint), Some(&second));
+        assert_eq!(flushed, 4);
+        assert_eq!(pending, vec![txs[4].to_bytes()]);
+
+        // The crash happened before writing the second batch
+        let (flushed, pending) = pending_txs(&txs, Some(checkpoint), Some(&first));
+        assert_eq!(flushed, 2);
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0], txs[2].to_bytes());
+
+        // Nothing was flushed before the first checkpoint
+        let (flushed, pending) = pending_txs(&txs, None, None);
+        assert_eq!(flushed, 0);
+        assert_eq!(pending.len(), 5);
+    }
+
+    #[test]
+    fn test_partial_line_is_truncated() {
+        let path = std::env::temp_dir().join(format!("tx_log-{}", std::process::id()));
+        truncate_partial_line(&path).unwrap();
+
+        let first = tx_log_line(&user_message(1));
+        let second = tx_log_line(&user_message(2));
+        let log = format!("{}\n{}", first, &second[..10]);
+        std::fs::write(&path, log).unwrap();
+        truncate_partial_line(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", first)
+        );
+
+        // The next transaction is appended on its own line
+        let mut tx_log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut tx_log, format!("{}\n", second).as_bytes()).unwrap();
+        assert_eq!(read_tx_log(&path).len(), 2);
+
+        truncate_partial_line(&path).unwrap();
+        assert_eq!(read_tx_log(&path).len(), 2);
+
+        std::fs::write(&path, &first[..10]).unwrap();
+        truncate_partial_line(&path).unwrap();
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_txs_are_appended() {
+        let path = std::env::temp_dir().join(format!("tx_log-append-{}", std::process::id()));
+        let mut tx_log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        append_tx(&mut tx_log, &user_message(1)).unwrap();
+        append_tx(&mut tx_log, &user_message(2)).unwrap();
+        let txs = read_tx_log(&path);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[1].to_bytes(), user_message(2).to_bytes());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_json_lines_are_read() {
+        let path = std::env::temp_dir().join(format!("tx_log-json-{}", std::process::id()));
+        let json = serde_json_wasm::to_string(&user_message(1)).unwrap();
+        let log = format!("{}\n{}\n", json, tx_log_line(&user_message(2)));
+        std::fs::write(&path, log).unwrap();
+        let txs = read_tx_log(&path);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].to_bytes(), user_message(1).to_bytes());
+        assert_eq!(txs[1].to_bytes(), user_message(2).to_bytes());
+        std::fs::remove_file(path).unwrap();
+    }
+}
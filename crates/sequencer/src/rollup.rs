@@ -0,0 +1,395 @@
+use lib::canvas::{CanvasConfig, CHUNK_SIZE};
+use lib::cooldown::{Cooldown, CooldownConfig};
+use lib::event::EventWindow;
+use lib::nonce::Nonce;
+use lib::place::{RollupConfig, RollupState};
+use std::collections::HashSet;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RollupError {
+    #[error("RPC request failed: {0}")]
+    Rpc(String),
+    #[error("Invalid value of {0} in the durable storage")]
+    InvalidValue(String),
+}
+
+pub type Result<T> = std::result::Result<T, RollupError>;
+
+/// Reads the configuration of the rollup from the durable storage of a rollup node
+///
+/// The sequencer has to accept the same messages as the kernel,
+/// so it follows the configuration set by the governance.
+pub struct RollupNode {
+    /// RPC endpoint of the rollup node, e.g. http://localhost:8932
+    endpoint: String,
+    client: awc::Client,
+}
+
+impl RollupNode {
+    pub fn new(endpoint: String) -> Self {
+        RollupNode {
+            endpoint,
+            client: awc::Client::default(),
+        }
+    }
+
+    /// Response of a query on the durable storage, at the head of the rollup
+    async fn durable<T: serde::de::DeserializeOwned>(&self, query: &str, key: &str) -> Result<T> {
+        let url = format!(
+            "{}/global/block/head/durable/wasm_2_0_0/{}?key={}",
+            self.endpoint, query, key
+        );
+        let mut response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|err| RollupError::Rpc(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(RollupError::Rpc(format!(
+                "GET {} {}: {}",
+                query,
+                key,
+                response.status()
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|err| RollupError::Rpc(err.to_string()))
+    }
+
+    /// Value stored at a path of the durable storage
+    async fn read_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value: Option<String> = self.durable("value", key).await?;
+        value
+            .map(|value| hex::decode(value).map_err(|_| RollupError::InvalidValue(key.to_string())))
+            .transpose()
+    }
+
+    async fn read_u32(&self, key: &str) -> Result<Option<u32>> {
+        match self.read_value(key).await? {
+            Some(value) => value
+                .try_into()
+                .map(|value| Some(u32::from_be_bytes(value)))
+                .map_err(|_| RollupError::InvalidValue(key.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_u64(&self, key: &str) -> Result<Option<u64>> {
+        match self.read_value(key).await? {
+            Some(value) => value
+                .try_into()
+                .map(|value| Some(u64::from_be_bytes(value)))
+                .map_err(|_| RollupError::InvalidValue(key.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_i64(&self, key: &str) -> Result<Option<i64>> {
+        Ok(self.read_u64(key).await?.map(|value| value as i64))
+    }
+
+    async fn read_bool(&self, key: &str) -> Result<bool> {
+        match self.read_value(key).await?.as_deref() {
+            Some([value]) => Ok(*value == 0x01),
+            Some(_) => Err(RollupError::InvalidValue(key.to_string())),
+            None => Ok(false),
+        }
+    }
+
+    /// Level of the last L1 block processed by the rollup node
+    pub async fn l1_head_level(&self) -> Result<u32> {
+        let url = format!("{}/global/tezos_level", self.endpoint);
+        let mut response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|err| RollupError::Rpc(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(RollupError::Rpc(format!(
+                "GET /global/tezos_level: {}",
+                response.status()
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|err| RollupError::Rpc(err.to_string()))
+    }
+
+    /// Cooldown configuration under /config/cooldown, the default one when it is not set
+    pub async fn read_cooldown_config(&self) -> Result<CooldownConfig> {
+        let default = CooldownConfig::default();
+        let max_pixels = self.read_u32("/config/cooldown/max_pixels").await?;
+        let levels = self.read_u32("/config/cooldown/levels").await?;
+        Ok(CooldownConfig {
+            max_pixels: max_pixels.unwrap_or(default.max_pixels),
+            levels: levels.unwrap_or(default.levels),
+        })
+    }
+
+    /// Accounts of the allowlist under /allowlist, or None if the allowlist is disabled
+    pub async fn read_allowlist(&self) -> Result<Option<HashSet<String>>> {
+        if !self.read_bool("/config/allowlist/enabled").await? {
+            return Ok(None);
+        }
+        let accounts: Vec<String> = self.durable("subkeys", "/allowlist").await?;
+        Ok(Some(accounts.into_iter().collect()))
+    }
+
+    /// Canvas configuration under /config/canvas, the default one when it is not set
+    pub async fn read_canvas_config(&self) -> Result<CanvasConfig> {
+        let default = CanvasConfig::default();
+        let width = self.read_u32("/config/canvas/width").await?;
+        let height = self.read_u32("/config/canvas/height").await?;
+        let palette = match self.read_value("/config/canvas/palette").await? {
+            Some(bytes) => Some(
+                CanvasConfig::palette_from_bytes(&bytes)
+                    .map_err(|_| RollupError::InvalidValue("/config/canvas/palette".to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(CanvasConfig {
+            width: width.unwrap_or(default.width),
+            height: height.unwrap_or(default.height),
+            palette,
+        })
+    }
+
+    /// Nonce and cooldown of each account under /accounts
+    async fn read_accounts(&self, state: &mut RollupState) -> Result<()> {
+        let accounts: Vec<String> = self.durable("subkeys", "/accounts").await?;
+        for account in accounts {
+            let nonce = self
+                .read_u64(&format!("/accounts/{}/nonce", account))
+                .await?;
+            let key = format!("/accounts/{}/cooldown", account);
+            let cooldown = match self.read_value(&key).await? {
+                Some(bytes) => {
+                    Cooldown::from_bytes(&bytes).map_err(|_| RollupError::InvalidValue(key))?
+                }
+                None => Cooldown::default(),
+            };
+            state
+                .nonces
+                .insert(account.clone(), Nonce(nonce.unwrap_or_default()));
+            state.cooldowns.insert(account, cooldown);
+        }
+        Ok(())
+    }
+
+    /// Chunks of the canvas under /canvas/{y}/{chunk}
+    ///
+    /// The pixels still in the layout used before the chunks are not read,
+    /// the kernel moves them to their chunk in the first runs after its upgrade.
+    async fn read_canvas(&self, state: &mut RollupState) -> Result<()> {
+        let rows: Vec<String> = self.durable("subkeys", "/canvas").await?;
+        for row in rows {
+            let chunks: Vec<String> = self.durable("subkeys", &format!("/canvas/{}", row)).await?;
+            for chunk in chunks {
+                let key = format!("/canvas/{}/{}", row, chunk);
+                let bytes = self.read_value(&key).await?;
+                match (row.parse::<u32>(), chunk.parse::<u32>(), bytes) {
+                    (Ok(y), Ok(chunk), Some(bytes)) if bytes.len() == CHUNK_SIZE => {
+                        state.chunks.insert((y, chunk), bytes);
+                    }
+                    _ => return Err(RollupError::InvalidValue(key)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// State of the accounts and of the canvas, the preview of the sequencer starts from it
+    pub async fn read_state(&self) -> Result<RollupState> {
+        let mut state = RollupState::default();
+        self.read_accounts(&mut state).await?;
+        self.read_canvas(&mut state).await?;
+        Ok(state)
+    }
+
+    /// Configuration of the rollup the transitions of the kernel depend on
+    pub async fn read_config(&self) -> Result<RollupConfig> {
+        Ok(RollupConfig {
+            allowlist: self.read_allowlist().await?,
+            frozen: self.read_bool("/snapshot/frozen").await?,
+            event_window: EventWindow {
+                start: self.read_i64("/config/event/start").await?,
+                end: self.read_i64("/config/event/end").await?,
+            },
+            level_timestamp: self.read_i64("/level/timestamp").await?,
+            canvas: self.read_canvas_config().await?,
+            cooldown: self.read_cooldown_config().await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RollupNode;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use lib::canvas::{blank_chunk, CanvasConfig};
+    use lib::cooldown::{Cooldown, CooldownConfig};
+    use lib::event::EventWindow;
+    use lib::nonce::Nonce;
+    use lib::place::{RollupConfig, RollupState};
+    use std::collections::{BTreeSet, HashMap};
+
+    type Storage = web::Data<HashMap<String, Vec<u8>>>;
+
+    #[derive(serde::Deserialize)]
+    struct Key {
+        key: String,
+    }
+
+    async fn value(storage: Storage, key: web::Query<Key>) -> HttpResponse {
+        let value = storage.get(&key.key).map(hex::encode);
+        HttpResponse::Ok().json(value)
+    }
+
+    async fn subkeys(storage: Storage, key: web::Query<Key>) -> HttpResponse {
+        let prefix = format!("{}/", key.key);
+        let subkeys: BTreeSet<&str> = storage
+            .keys()
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .filter_map(|path| path.split('/').next())
+            .collect();
+        HttpResponse::Ok().json(subkeys)
+    }
+
+    async fn tezos_level() -> HttpResponse {
+        HttpResponse::Ok().json(42)
+    }
+
+    /// Starts a rollup node serving the given durable storage, returns its endpoint
+    fn mock_rollup_node(storage: HashMap<String, Vec<u8>>) -> String {
+        let storage = web::Data::new(storage);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(storage.clone())
+                .route(
+                    "/global/block/head/durable/wasm_2_0_0/value",
+                    web::get().to(value),
+                )
+                .route(
+                    "/global/block/head/durable/wasm_2_0_0/subkeys",
+                    web::get().to(subkeys),
+                )
+                .route("/global/tezos_level", web::get().to(tezos_level))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    #[actix_web::test]
+    async fn test_cooldown_config_is_read_from_the_rollup() {
+        let node = RollupNode::new(mock_rollup_node(HashMap::new()));
+        assert_eq!(
+            node.read_cooldown_config().await.unwrap(),
+            CooldownConfig::default()
+        );
+
+        let mut storage = HashMap::new();
+        storage.insert("/config/cooldown/max_pixels".to_string(), vec![0, 0, 0, 1]);
+        storage.insert("/config/cooldown/levels".to_string(), vec![0, 0, 0, 10]);
+        let node = RollupNode::new(mock_rollup_node(storage));
+        assert_eq!(
+            node.read_cooldown_config().await.unwrap(),
+            CooldownConfig {
+                max_pixels: 1,
+                levels: 10
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_l1_head_is_read_from_the_rollup() {
+        let node = RollupNode::new(mock_rollup_node(HashMap::new()));
+        assert_eq!(node.l1_head_level().await.unwrap(), 42);
+    }
+
+    #[actix_web::test]
+    async fn test_config_is_read_from_the_rollup() {
+        let node = RollupNode::new(mock_rollup_node(HashMap::new()));
+        assert_eq!(node.read_config().await.unwrap(), RollupConfig::default());
+
+        let mut storage = HashMap::new();
+        let account = "tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv";
+        storage.insert("/config/allowlist/enabled".to_string(), vec![1]);
+        storage.insert(format!("/allowlist/{}", account), vec![1]);
+        storage.insert("/snapshot/frozen".to_string(), vec![1]);
+        storage.insert(
+            "/config/event/end".to_string(),
+            1_700_000_000u64.to_be_bytes().to_vec(),
+        );
+        storage.insert(
+            "/level/timestamp".to_string(),
+            1_600_000_000u64.to_be_bytes().to_vec(),
+        );
+        storage.insert(
+            "/config/canvas/palette".to_string(),
+            vec![0, 0, 0, 255, 255, 255],
+        );
+        storage.insert("/config/cooldown/levels".to_string(), vec![0, 0, 0, 10]);
+        let node = RollupNode::new(mock_rollup_node(storage));
+        assert_eq!(
+            node.read_config().await.unwrap(),
+            RollupConfig {
+                allowlist: Some([account.to_string()].into_iter().collect()),
+                frozen: true,
+                event_window: EventWindow {
+                    start: None,
+                    end: Some(1_700_000_000),
+                },
+                level_timestamp: Some(1_600_000_000),
+                canvas: CanvasConfig {
+                    palette: Some(vec![[0, 0, 0], [255, 255, 255]]),
+                    ..CanvasConfig::default()
+                },
+                cooldown: CooldownConfig {
+                    levels: 10,
+                    ..CooldownConfig::default()
+                },
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_state_is_read_from_the_rollup() {
+        let node = RollupNode::new(mock_rollup_node(HashMap::new()));
+        assert_eq!(node.read_state().await.unwrap(), RollupState::default());
+
+        let mut storage = HashMap::new();
+        let account = "tz1QFD9WqLWZmmAuqnnTPPUjfauitYEWdshv";
+        let cooldown = Cooldown {
+            window_start: 10,
+            pixels: 2,
+            total: 7,
+        };
+        storage.insert(
+            format!("/accounts/{}/nonce", account),
+            7u64.to_be_bytes().to_vec(),
+        );
+        storage.insert(
+            format!("/accounts/{}/cooldown", account),
+            cooldown.to_bytes(),
+        );
+        storage.insert("/canvas/3/1".to_string(), blank_chunk());
+        let node = RollupNode::new(mock_rollup_node(storage.clone()));
+        let state = node.read_state().await.unwrap();
+        assert_eq!(state.nonces.get(account), Some(&Nonce(7)));
+        assert_eq!(state.cooldowns.get(account), Some(&cooldown));
+        assert_eq!(state.chunks.get(&(3, 1)), Some(&blank_chunk()));
+
+        storage.insert("/canvas/3/2".to_string(), vec![0; 3]);
+        let node = RollupNode::new(mock_rollup_node(storage));
+        assert!(node.read_state().await.is_err());
+    }
+}
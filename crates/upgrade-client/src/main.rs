@@ -4,13 +4,20 @@
 
 use clap::{Parser, Subcommand};
 use hex::ToHex;
+use lib::public_key::PublicKey;
 use std::ffi::OsString;
 use std::fs;
 use std::path::Path;
 use tezos_smart_rollup::dac::prepare_preimages;
 use tezos_smart_rollup::dac::PreimageHash;
+use tezos_smart_rollup_installer_config::yaml::{Instr, RevealArgs, YamlConfig};
 use thiserror::Error;
 
+/// Paths of the configuration of the kernel, written by the installer before its first run
+const SEQUENCERS_PATH: &str = "/config/sequencers";
+const EVENT_START_PATH: &str = "/config/event/start";
+const EVENT_END_PATH: &str = "/config/event/end";
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unable to read content file: {0}.")]
@@ -19,6 +26,12 @@ pub enum Error {
     PreimagesDir(std::io::Error),
     #[error("Failed to produce preimages from content: {0}.")]
     Preimage(String),
+    #[error("Invalid sequencer key, an ed25519 public key is expected: {0}.")]
+    SequencerKey(String),
+    #[error("Unable to write setup file: {0}.")]
+    SetupFile(std::io::Error),
+    #[error("Unable to encode setup file: {0}.")]
+    SetupEncoding(serde_yaml::Error),
 }
 
 pub fn content_to_preimages(content: &Path, preimage_dir: &Path) -> Result<PreimageHash, Error> {
@@ -27,7 +40,10 @@ pub fn content_to_preimages(content: &Path, preimage_dir: &Path) -> Result<Preim
     }
 
     let content = fs::read(content).map_err(Error::ContentFile)?;
+    bytes_to_preimages(&content, preimage_dir)
+}
 
+fn bytes_to_preimages(content: &[u8], preimage_dir: &Path) -> Result<PreimageHash, Error> {
     let save_preimages = |hash: PreimageHash, preimage: Vec<u8>| {
         let name = hex::encode(hash.as_ref());
         let path = preimage_dir.join(name);
@@ -37,7 +53,50 @@ pub fn content_to_preimages(content: &Path, preimage_dir: &Path) -> Result<Preim
         }
     };
 
-    prepare_preimages(&content, save_preimages).map_err(|e| Error::Preimage(e.to_string()))
+    prepare_preimages(content, save_preimages).map_err(|e| Error::Preimage(e.to_string()))
+}
+
+/// Instruction of the installer revealing the given value at a path of the durable storage
+fn reveal_value(value: &[u8], to: &str, preimage_dir: &Path) -> Result<Instr, Error> {
+    let root_hash = bytes_to_preimages(value, preimage_dir)?;
+    Ok(Instr::Reveal(RevealArgs {
+        reveal: hex::encode(root_hash.as_ref()),
+        to: to.to_string(),
+    }))
+}
+
+/// Setup of the installer, writing the initial configuration of the kernel
+///
+/// The sequencer keys are concatenated raw ed25519 keys, the bounds of the event are
+/// big endian timestamps, as read by the kernel.
+/// A value which is not given is not written: the kernel falls back to its default.
+pub fn setup_config(
+    sequencers: &[String],
+    event_start: Option<i64>,
+    event_end: Option<i64>,
+    preimage_dir: &Path,
+) -> Result<YamlConfig, Error> {
+    if !preimage_dir.is_dir() {
+        fs::create_dir_all(preimage_dir).map_err(Error::PreimagesDir)?;
+    }
+    let mut instructions = vec![];
+    if !sequencers.is_empty() {
+        let mut keys = vec![];
+        for sequencer in sequencers {
+            match PublicKey::from_b58(sequencer) {
+                Ok(key @ PublicKey::Ed25519(_)) => keys.extend_from_slice(&key.to_bytes()),
+                _ => return Err(Error::SequencerKey(sequencer.clone())),
+            }
+        }
+        instructions.push(reveal_value(&keys, SEQUENCERS_PATH, preimage_dir)?);
+    }
+    let bounds = [(event_start, EVENT_START_PATH), (event_end, EVENT_END_PATH)];
+    for (bound, path) in bounds {
+        if let Some(timestamp) = bound {
+            instructions.push(reveal_value(&timestamp.to_be_bytes(), path, preimage_dir)?);
+        }
+    }
+    Ok(YamlConfig { instructions })
 }
 
 #[derive(Parser)]
@@ -53,6 +112,26 @@ pub enum Commands {
         #[arg(short, long, value_name = "KERNEL")]
         kernel: OsString,
 
+        #[arg(short = 'P', long, value_name = "PREIMAGES_OUTPUT_DIR")]
+        preimages_dir: OsString,
+    },
+    /// Writes the setup file of the installer, to be passed with --setup-file
+    GetSetupConfig {
+        /// Public key of a sequencer, ed25519 only; the default sequencer if none is given
+        #[arg(long = "sequencer", value_name = "SEQUENCER_PUBLIC_KEY")]
+        sequencers: Vec<String>,
+
+        /// Timestamp from which pixels can be placed
+        #[arg(long, value_name = "TIMESTAMP")]
+        event_start: Option<i64>,
+
+        /// Timestamp at which the canvas is frozen
+        #[arg(long, value_name = "TIMESTAMP")]
+        event_end: Option<i64>,
+
+        #[arg(short, long, value_name = "SETUP_OUTPUT_FILE")]
+        output: OsString,
+
         #[arg(short = 'P', long, value_name = "PREIMAGES_OUTPUT_DIR")]
         preimages_dir: OsString,
     },
@@ -77,6 +156,18 @@ fn main() -> Result<(), ClientError> {
             let x: String = root_hash.as_ref().encode_hex_upper();
             println!("Root hash: {}", x)
         }
+        Commands::GetSetupConfig {
+            sequencers,
+            event_start,
+            event_end,
+            output,
+            preimages_dir,
+        } => {
+            let preimages_dir = Path::new(&preimages_dir);
+            let config = setup_config(&sequencers, event_start, event_end, preimages_dir)?;
+            let yaml = serde_yaml::to_string(&config).map_err(Error::SetupEncoding)?;
+            fs::write(Path::new(&output), yaml).map_err(Error::SetupFile)?;
+        }
     }
 
     Ok(())
@@ -80,10 +80,6 @@
           rust-packages.packages
           // frontend.packages
           // {
-            process-message = import ./nix/process-message.nix {
-              inherit pkgs;
-              tezos-packages = tezos.packages.${system};
-            };
             octez-client = tezos.packages.${system}.trunk-octez-client;
           };
         devShells.default = pkgs.mkShell {
@@ -93,7 +89,6 @@
           '';
           buildInputs = with pkgs;
             [
-              self.packages.${system}.process-message
               # TODO: I'm going to want to fix this with dream2nix somehow
               pkg-config
               openssl
@@ -1,9 +1,14 @@
 import { blake2bHex, blake2b } from "blakejs";
 import { TezosToolkit } from "@taquito/taquito";
+import { Buffer } from "buffer";
 
 globalThis.blake2bHex = blake2bHex
 globalThis.blake2b = blake2b;
 
+// Text signed by the wallets, followed by the hex of the hash of the inner,
+// see `micheline_signed_message` in the kernel
+const SIGNED_MESSAGE_PREFIX = "Tezos Signed Message: tezos-place ";
+
 interface Signer {
   sign: (bytes: string) => Promise<{
     bytes: string;
@@ -23,6 +28,9 @@ export class Place {
   #glWindow;
   #tezos;
   #signer;
+  #host;
+  // Nonce of the last message sent by the account, fetched from the sequencer on the first pixel
+  #nonce: number | null;
 
   constructor(glWindow, tezos: TezosToolkit, signer: Signer) {
     this.#loaded = false;
@@ -32,6 +40,8 @@ export class Place {
     this.#glWindow = glWindow;
     this.#tezos = tezos;
     this.#signer = signer;
+    this.#host = null;
+    this.#nonce = null;
   }
 
   initConnection() {
@@ -50,6 +60,7 @@ export class Place {
       wsProt = "ws:";
     }
 
+    this.#host = window.location.protocol + "//" + host;
     this.#connect(wsProt + "//" + host + "/ws");
     this.#loadingp.innerHTML = "downloading map";
 
@@ -90,11 +101,23 @@ export class Place {
 
   #connect(path) {
     this.#socket = new WebSocket(path);
+    // The accepted messages are broadcast in their binary encoding
+    this.#socket.binaryType = "arraybuffer";
     console.log("connected");
 
     const socketMessage = async (event) => {
+      if (event.data instanceof ArrayBuffer) {
+        this.#handleSocketSetPixel(this.#decodePlacePixel(new Uint8Array(event.data)));
+        return;
+      }
       let data = JSON.parse(event.data);
-      this.#handleSocketSetPixel(data.inner.content.PlacePixel);
+      if (data.Accepted) {
+        console.log("pixel accepted:", data.Accepted.hash);
+      } else if (data.Rejected) {
+        console.warn("pixel rejected:", data.Rejected.error);
+        // The nonce of a rejected message is not consumed
+        this.#nonce = null;
+      }
     };
 
     const socketClose = (event) => {
@@ -119,8 +142,10 @@ export class Place {
       const x_floor = Math.floor(x);
       const y_floor = Math.floor(y);
       const color_values = Object.values(color);
+      const publicKey = await this.#signer.publicKey();
+      const nonce = await this.#nextNonce();
       const inner = {
-        nonce: 777,
+        nonce,
         content: {
           PlacePixel: {
             x: x_floor,
@@ -129,28 +154,45 @@ export class Place {
           },
         },
       };
-      const hash = blake2bHex(JSON.stringify(inner), undefined, 32);
-      const publicKey = await this.#signer.publicKey();
-      const { prefixSig } = await this.#signer.sign(hash);
+      const hash = blake2bHex(
+        this.#encodeInner(inner.nonce, x_floor, y_floor, color_values),
+        undefined,
+        32
+      );
+      const { prefixSig } = await this.#signer.sign(
+        this.#michelinePayload(SIGNED_MESSAGE_PREFIX + hash)
+      );
+      const curve = this.#curve(publicKey);
       const message = {
+        scheme: "Micheline",
         pkey: {
-          Ed25519: publicKey,
+          [curve]: publicKey,
         },
         signature: {
-          Ed25519: prefixSig,
+          [curve]: prefixSig,
         },
         inner,
       };
       console.log("=========== message:",message);
+      // The pixel is drawn when the sequencer broadcasts it, once it is accepted
       this.#socket.send(JSON.stringify(message));
-      this.#glWindow.setPixelColor(x, y, color);
-      this.#glWindow.draw();
+      this.#nonce = nonce;
     } else {
       alert("Disconnected. Probably the server is upgrading. Try refreshing in a few seconds.");
       console.error("Disconnected.");
     }
   }
 
+  // Nonce of the next message, the one following the last nonce accepted by the sequencer
+  async #nextNonce(): Promise<number> {
+    if (this.#nonce == null) {
+      const publicKeyHash = await this.#signer.publicKeyHash();
+      const resp = await fetch(this.#host + "/nonce/" + publicKeyHash);
+      this.#nonce = resp.ok ? await resp.json() : 0;
+    }
+    return this.#nonce + 1;
+  }
+
   #handleSocketSetPixel({ x, y, color }) {
     // if (this.#loaded) {
       this.#glWindow.setPixelColor(x, y, color);
@@ -174,6 +216,53 @@ export class Place {
     await promise;
   }
 
+  // Variant of the key and the signature of a message, from the prefix of the key
+  #curve(publicKey: string): string {
+    if (publicKey.startsWith("sppk")) {
+      return "Secp256k1";
+    } else if (publicKey.startsWith("p2pk")) {
+      return "P256";
+    }
+    return "Ed25519";
+  }
+
+  // Binary encoding of the inner of a PlacePixel message, as hashed by the kernel:
+  // nonce (u64), content tag (0 for PlacePixel), x (u32), y (u32), color (3 bytes)
+  #encodeInner(nonce, x, y, color): Uint8Array {
+    let b = new ArrayBuffer(8 + 1 + 4 + 4 + 3);
+    let view = new DataView(b);
+    view.setBigUint64(0, BigInt(nonce), false);
+    view.setUint8(8, 0);
+    view.setUint32(9, x, false);
+    view.setUint32(13, y, false);
+    color.forEach((c, i) => view.setUint8(17 + i, c));
+    return new Uint8Array(b);
+  }
+
+  // Pixel of a message in its binary encoding: its inner, as encoded by #encodeInner, is last
+  #decodePlacePixel(message: Uint8Array) {
+    const inner = message.subarray(message.length - 20);
+    const view = new DataView(inner.buffer, inner.byteOffset, inner.byteLength);
+    return {
+      x: view.getUint32(9, false),
+      y: view.getUint32(13, false),
+      color: Array.from(inner.subarray(17, 20)),
+    };
+  }
+
+  // Hex of a Micheline packed string, as signed by the wallets:
+  // 0x05 (packed), 0x01 (string), length (u32) and the bytes of the text
+  #michelinePayload(text: string): string {
+    const bytes = new TextEncoder().encode(text);
+    let b = new ArrayBuffer(2 + 4 + bytes.length);
+    let view = new DataView(b);
+    view.setUint8(0, 0x05);
+    view.setUint8(1, 0x01);
+    view.setUint32(2, bytes.length, false);
+    new Uint8Array(b).set(bytes, 6);
+    return Buffer.from(b).toString("hex");
+  }
+
   #putUint32(b, offset, n) {
     let view = new DataView(b);
     view.setUint32(offset, n, false);
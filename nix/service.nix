@@ -11,6 +11,7 @@ with lib; let
   cfg = config.services.tezos-place;
   listToString = lib.strings.concatStringsSep ",";
   myPkgs = packages.${config.nixpkgs.system};
+  rollupAddress = "sr1VHLzFsBdyL8jiEGHRdkBj3o9k7NujQhsx";
 in {
   options.services.tezos-place = {
     enable = mkEnableOption "tezos-place system";
@@ -23,21 +24,7 @@ in {
       ];
     };
     systemd = {
-      timers = {
-        tezos-place-process-messages = {
-          description = "Tezos Place - process messages timer";
-          wantedBy = [ "timers.target" ];
-          timerConfig.OnCalendar = "*-*-* *:*:00";
-        };
-      };
       services = {
-        tezos-place-process-messages = {
-          description = "Tezos Place - process messages";
-          serviceConfig.Type = "simple";
-          serviceConfig.ExecStart = "${myPkgs.process-message}/bin/process-message";
-          serviceConfig.User = "d4hines";
-          wantedBy = ["default.target"];
-        };
         tezos-place-sequencer = {
           description = "Tezos Place Sequencer";
           after = ["network.target"];
@@ -45,12 +32,20 @@ in {
           path = [];
           environment = {
             SEQUENCER_SECRET_KEY = "${builtins.readFile ../secret/sequencer_key}";
-            ROLLUP_ADDRESS = "http://localhost:8932";
+            # Account paying the injection of the batches on L1, distinct from the sequencer key
+            INJECTOR_SECRET_KEY = "${builtins.readFile ../secret/injector_key}";
+            ROLLUP_ADDRESS = rollupAddress;
             ROLLUP_PREIMAGES_DIR = "/var/lib/rollup/.tezos-smart-rollup-node/wasm_2_0_0";
             ROLLUP_EXTERNAL_MESSAGE_LOG = "/var/lib/tezos-place/external_message_log";
             ROLLUP_TX_LOG = "/var/lib/tezos-place/tx_log";
+            ROLLUP_CHECKPOINT = "/var/lib/tezos-place/checkpoint";
             ROLLUP_IMAGE = "/var/lib/tezos-place/image.png";
             ROLLUP_MESSAGE_INDEX = "/var/lib/tezos-place/next_index";
+            TEZOS_NODE_ENDPOINT = "https://mainnet.api.tez.ie";
+            ROLLUP_NODE_ENDPOINT = "http://localhost:8932";
+            SEQUENCER_MAX_LATENCY_MS = "10000";
+            SEQUENCER_MAX_BATCH_TXS = "10000";
+            SEQUENCER_MAX_BATCH_BYTES = "1048576";
             TZPLACE_FRONTEND = "${myPkgs.frontend}/lib/node_modules/frontend/dist";
           };
           serviceConfig = {
@@ -99,7 +94,7 @@ in {
                   exec ${tezos.packages.${config.nixpkgs.system}.trunk-octez-smart-rollup-node-PtMumbai}/bin/octez-smart-rollup-node-PtMumbai \
                     -E https://mainnet.api.tez.ie \
                     run \
-                    --rollup sr1VHLzFsBdyL8jiEGHRdkBj3o9k7NujQhsx \
+                    --rollup ${rollupAddress} \
                     --mode observer \
                     --log-kernel-debug
                 '';
@@ -20,10 +20,27 @@ case $1 in
 originate-rollup)
     build
 
+    # Initial configuration of the kernel, the defaults of the kernel are used when unset
+    setup_args=()
+    if [ -n "${SEQUENCER_PUBLIC_KEY:-}" ]; then
+        setup_args+=(--sequencer "$SEQUENCER_PUBLIC_KEY")
+    fi
+    if [ -n "${EVENT_START:-}" ]; then
+        setup_args+=(--event-start "$EVENT_START")
+    fi
+    if [ -n "${EVENT_END:-}" ]; then
+        setup_args+=(--event-end "$EVENT_END")
+    fi
+    cargo run --bin upgrade-client -- get-setup-config \
+        "${setup_args[@]}" \
+        --output ./setup.yaml \
+        -P ./kernel_preimages
+
     cargo run --bin smart-rollup-installer -- get-reveal-installer \
         --upgrade-to ./target/wasm32-unknown-unknown/release/kernel.wasm \
         --output ./installer.hex \
-        --preimages-dir ./kernel_preimages
+        --preimages-dir ./kernel_preimages \
+        --setup-file ./setup.yaml
 
     octez-client \
         -f ./secret/password \